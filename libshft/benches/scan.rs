@@ -0,0 +1,52 @@
+// Run with `cargo bench`. This uses its own timing loop rather than the
+// unstable `test` crate so it also builds on stable.
+extern crate libshft;
+
+use libshft::grammar::{DelimOptions, Grammar, GrammarDef};
+use libshft::parse::slurp;
+use std::time::{Duration, Instant};
+
+const INPUT_SIZE: usize = 5 * 1024 * 1024;
+const ITERATIONS: u32 = 10;
+
+fn grammar() -> Grammar {
+    Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"[".to_vec(), b"]".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"{".to_vec(), b"}".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"/*".to_vec(), b"*/".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b",".to_vec()),
+        GrammarDef::Breaker(b";".to_vec()),
+    ], vec![b" ".to_vec(), b"\r\n".to_vec()])
+}
+
+// Mostly long runs of plain content, which is where scanning every offset
+// against every def hurts.
+fn input() -> Vec<u8> {
+    let record = b"<<(aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa,bbbbbbbbbbbbbbbbbbbbbbbbbbbbbb) [cccccccccccccccccccccccccccccc]>>\r\n";
+    record.iter().cloned().cycle().take(INPUT_SIZE).collect()
+}
+
+fn bench<F: FnMut() -> usize>(name: &str, mut f: F) {
+    // One untimed run to warm up caches and the allocator.
+    let mut checksum = f();
+    let mut total = Duration::from_secs(0);
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        checksum += f();
+        total += start.elapsed();
+    }
+    println!("{}: {:?} per iteration ({} nodes)", name, total / ITERATIONS, checksum / (ITERATIONS as usize + 1));
+}
+
+fn main() {
+    let buf = input();
+
+    let grammar = grammar();
+    bench("scan_prefilter", || slurp(&grammar, &buf).nodes.len());
+
+    let mut grammar = grammar;
+    grammar.prefilter = None;
+    bench("scan_linear", || slurp(&grammar, &buf).nodes.len());
+}
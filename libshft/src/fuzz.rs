@@ -2,60 +2,362 @@ extern crate rand;
 
 use std::borrow::Cow;
 use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::str;
+use std::str::FromStr;
 use self::rand::Rng;
-use grammar::Delim;
+use error::*;
+use grammar::{Delim, LengthField};
 use parse::{Node, NodeRef, ParsedFile, RangeRef};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FuzzFile<'buf: 'parse, 'parse> {
     root: Cow<'parse, [NodeRef]>,
     nodes: Cow<'parse, [Node<'buf>]>,
     ranges: Cow<'parse, [Vec<NodeRef>]>,
+    // Everything the mutations so far changed, see `validate_touched`.
+    touched: Vec<Touched>,
 }
 
-#[derive(Clone)]
+// A node or range a mutation changed or added. `Range(None)` is the root.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Touched {
+    Node(NodeRef),
+    Range(Option<RangeRef>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Mutation {
+    BitFlip,
+    CollapseDelim,
+    DeleteToken,
+    DictToken,
     DuplicateRange,
     DuplicateRootNode,
     EmptyDelim,
+    InjectExtraClose,
+    InsertRandom,
+    MutateNumber,
     NestDelim,
+    PermuteBytes,
     RandDelim,
+    RandomizeToken,
     RemoveDelim,
+    RepeatDelim,
+    ResizePayload,
+    ReverseRange,
     ShuffleRanges,
+    Splice,
+    SubstringSplice,
     SwapDelim,
+    SwapEndianness,
     SwapRanges,
+    SwapTokens,
+    TransposeDelimBytes,
+    Truncate,
 }
 
 pub fn default_mutations() -> Vec<Mutation> {
-    vec![
-        Mutation::DuplicateRange,
-        Mutation::DuplicateRootNode,
-        Mutation::EmptyDelim,
-        Mutation::NestDelim,
-        Mutation::RandDelim,
-        Mutation::RemoveDelim,
-        Mutation::ShuffleRanges,
-        Mutation::SwapDelim,
-        Mutation::SwapRanges,
-    ]
+    Mutation::all()
+}
+
+// Every default mutation with the same weight, equivalent to leaving
+// `FuzzConfig.weights` empty.
+pub fn default_weighted_mutations() -> Vec<(Mutation, u32)> {
+    default_mutations().into_iter().map(|mutation| (mutation, 1)).collect()
+}
+
+impl Mutation {
+    // Every variant, in the order `Display` lists them.
+    pub fn all() -> Vec<Mutation> {
+        vec![
+            Mutation::BitFlip,
+            Mutation::CollapseDelim,
+            Mutation::DeleteToken,
+            Mutation::DictToken,
+            Mutation::DuplicateRange,
+            Mutation::DuplicateRootNode,
+            Mutation::EmptyDelim,
+            Mutation::InjectExtraClose,
+            Mutation::InsertRandom,
+            Mutation::MutateNumber,
+            Mutation::NestDelim,
+            Mutation::PermuteBytes,
+            Mutation::RandDelim,
+            Mutation::RandomizeToken,
+            Mutation::RemoveDelim,
+            Mutation::RepeatDelim,
+            Mutation::ResizePayload,
+            Mutation::ReverseRange,
+            Mutation::ShuffleRanges,
+            Mutation::Splice,
+            Mutation::SubstringSplice,
+            Mutation::SwapDelim,
+            Mutation::SwapEndianness,
+            Mutation::SwapRanges,
+            Mutation::SwapTokens,
+            Mutation::TransposeDelimBytes,
+            Mutation::Truncate,
+        ]
+    }
+
+    // Mutations that rewrite the bytes of tokens rather than the tree.
+    pub fn is_content(self: &Self) -> bool {
+        match *self {
+            Mutation::BitFlip | Mutation::DictToken | Mutation::MutateNumber | Mutation::PermuteBytes | Mutation::RandomizeToken | Mutation::SubstringSplice | Mutation::SwapEndianness | Mutation::SwapTokens => true,
+            _ => false,
+        }
+    }
+
+    // Content mutations that keep every token the same length, so the
+    // structure stays at the same byte offsets.
+    pub fn is_in_place(self: &Self) -> bool {
+        match *self {
+            Mutation::BitFlip | Mutation::PermuteBytes | Mutation::RandomizeToken | Mutation::SwapEndianness => true,
+            _ => false,
+        }
+    }
+
+    // Mutations that add nodes or ranges, or copy the contents of ranges.
+    pub fn grows_tree(self: &Self) -> bool {
+        match *self {
+            Mutation::DuplicateRange | Mutation::DuplicateRootNode | Mutation::InsertRandom | Mutation::NestDelim | Mutation::RepeatDelim | Mutation::Splice => true,
+            _ => false,
+        }
+    }
+
+    // Mutations that can wrap existing nodes in a new one, or graft in a
+    // deeper tree.
+    pub fn adds_depth(self: &Self) -> bool {
+        match *self {
+            Mutation::DuplicateRootNode | Mutation::NestDelim | Mutation::RepeatDelim | Mutation::Splice => true,
+            _ => false,
+        }
+    }
+}
+
+// Kebab-case names, as accepted by `from_str`.
+impl fmt::Display for Mutation {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Mutation::BitFlip => "bit-flip",
+            Mutation::CollapseDelim => "collapse-delim",
+            Mutation::DeleteToken => "delete-token",
+            Mutation::DictToken => "dict-token",
+            Mutation::DuplicateRange => "duplicate-range",
+            Mutation::DuplicateRootNode => "duplicate-root-node",
+            Mutation::EmptyDelim => "empty-delim",
+            Mutation::InjectExtraClose => "inject-extra-close",
+            Mutation::InsertRandom => "insert-random",
+            Mutation::MutateNumber => "mutate-number",
+            Mutation::NestDelim => "nest-delim",
+            Mutation::PermuteBytes => "permute-bytes",
+            Mutation::RandDelim => "rand-delim",
+            Mutation::RandomizeToken => "randomize-token",
+            Mutation::RemoveDelim => "remove-delim",
+            Mutation::RepeatDelim => "repeat-delim",
+            Mutation::ResizePayload => "resize-payload",
+            Mutation::ReverseRange => "reverse-range",
+            Mutation::ShuffleRanges => "shuffle-ranges",
+            Mutation::Splice => "splice",
+            Mutation::SubstringSplice => "substring-splice",
+            Mutation::SwapDelim => "swap-delim",
+            Mutation::SwapEndianness => "swap-endianness",
+            Mutation::SwapRanges => "swap-ranges",
+            Mutation::SwapTokens => "swap-tokens",
+            Mutation::TransposeDelimBytes => "transpose-delim-bytes",
+            Mutation::Truncate => "truncate",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Mutation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Mutation> {
+        // Names match ignoring case, `-` and `_`.
+        let normalize = |name: &str| -> String {
+            name.chars()
+                .filter(|c| *c != '-' && *c != '_')
+                .flat_map(|c| c.to_lowercase())
+                .collect()
+        };
+        let name = normalize(s);
+        // Other names a mutation is known by.
+        if name == "jumbletoken" {
+            return Ok(Mutation::PermuteBytes)
+        }
+        match Mutation::all().into_iter().find(|mutation| normalize(&mutation.to_string()) == name) {
+            Some(mutation) => Ok(mutation),
+            None => {
+                let names: Vec<String> = Mutation::all().iter().map(|mutation| mutation.to_string()).collect();
+                bail!("Unknown mutation: {}, expected one of {}", s, names.join(", "))
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Preset {
+    Text,
+    Binary,
+    Structured,
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Preset> {
+        match &s.to_lowercase()[..] {
+            "text" => Ok(Preset::Text),
+            "binary" => Ok(Preset::Binary),
+            "structured" => Ok(Preset::Structured),
+            _ => bail!("Unknown preset: {}", s),
+        }
+    }
 }
 
 pub struct FuzzConfig<'buf> {
     pub max_mutations: usize,
+    // Mutations that have to actually apply for an output to count. After
+    // `max_mutations` attempts, up to `MUTATION_ATTEMPTS_FACTOR` times as
+    // many are made to reach this, then the output is given up on.
+    pub min_mutations: usize,
     pub max_duplications: usize,
     pub valid_actions: Vec<Mutation>,
     pub all_delims: Vec<Delim<'buf>>,
+    pub intensity: f64,
+    pub content_only: bool,
+    // Pick ranges to duplicate with weight inversely proportional to their
+    // length, to keep output growth under control.
+    pub prefer_small_ranges: bool,
+    // Mutations that nest nodes are undone if they take the tree deeper
+    // than this.
+    pub max_depth: Option<usize>,
+    // Most extra levels `RepeatDelim` wraps around a delim in one go.
+    pub max_nest_depth: usize,
+    // One weight per entry of `valid_actions`, empty to pick uniformly.
+    pub weights: Vec<u32>,
+    // Replacement values for `DictToken`.
+    pub dictionary: Vec<Vec<u8>>,
+    // Most bits `BitFlip` flips in one token.
+    pub max_bit_flips: usize,
+    // Longest token `InsertRandom` creates.
+    pub max_insert_len: usize,
+    // Mutations that grow the tree are skipped once it has this many nodes
+    // and ranges between them, and undone if they take it past this.
+    pub max_total_nodes: Option<usize>,
 }
 
-fn rand_indices<R: Rng, T>(mut rng: &mut Rng, x: &[T]) -> Option<(usize, usize)> {
+impl<'buf> FuzzConfig<'buf> {
+    pub fn new(all_delims: Vec<Delim<'buf>>) -> Self {
+        FuzzConfig {
+            max_mutations: 5,
+            min_mutations: 1,
+            max_duplications: 5,
+            valid_actions: default_mutations(),
+            all_delims: all_delims,
+            intensity: 1.0,
+            content_only: false,
+            prefer_small_ranges: false,
+            max_depth: None,
+            max_nest_depth: 16,
+            weights: Vec::new(),
+            dictionary: Vec::new(),
+            max_bit_flips: 8,
+            max_insert_len: 16,
+            max_total_nodes: None,
+        }
+    }
+
+    pub fn set_weighted_mutations(self: &mut Self, weighted: Vec<(Mutation, u32)>) {
+        let (valid_actions, weights) = weighted.into_iter().unzip();
+        self.valid_actions = valid_actions;
+        self.weights = weights;
+    }
+
+    pub fn validate(self: &Self) -> Result<()> {
+        if !self.weights.is_empty() && self.weights.len() != self.valid_actions.len() {
+            bail!("{} mutation weights given for {} mutations", self.weights.len(), self.valid_actions.len());
+        }
+        if self.max_mutations == 0 {
+            bail!("max_mutations must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub fn preset(preset: Preset, all_delims: Vec<Delim<'buf>>) -> Self {
+        let mut config = FuzzConfig::new(all_delims);
+        match preset {
+            Preset::Text => {
+                config.valid_actions.retain(|mutation| *mutation != Mutation::SwapEndianness);
+            },
+            Preset::Binary => {
+                config.max_mutations = 3;
+                config.max_duplications = 2;
+                config.intensity = 0.5;
+                config.valid_actions.retain(|mutation| {
+                    mutation.is_content() || *mutation == Mutation::DuplicateRange || *mutation == Mutation::SwapRanges
+                });
+            },
+            Preset::Structured => {
+                config.max_mutations = 8;
+                config.valid_actions.retain(|mutation| !mutation.is_content());
+            },
+        }
+        config
+    }
+
+    // Scales the upper bound of a parameterized mutation by `intensity`,
+    // without dropping below 1.
+    pub fn scaled(self: &Self, max: usize) -> usize {
+        let intensity = self.intensity.max(0.0).min(1.0);
+        cmp::max(1, (max as f64 * intensity).round() as usize)
+    }
+}
+
+// Two distinct indices into `x`. The second is drawn from the remaining
+// `len - 1` positions, skipping over the first.
+fn rand_indices<R: Rng, T>(rng: &mut R, x: &[T]) -> Option<(usize, usize)> {
     if x.len() > 1 {
-        let indices = rand::sample(&mut rng, 0..x.len(), 2);
-        Some((indices[0], indices[1]))
+        let index0 = rng.gen_range(0, x.len());
+        let index1 = rng.gen_range(0, x.len() - 1);
+        Some((index0, if index1 >= index0 { index1 + 1 } else { index1 }))
     } else {
         None
     }
 }
 
+fn rand_index_weighted<R: Rng>(rng: &mut R, weights: &[f64]) -> Option<usize> {
+    let total: f64 = weights.iter().sum();
+    if weights.is_empty() || total <= 0.0 {
+        return None
+    }
+
+    let mut target = rng.gen::<f64>() * total;
+    for (index, weight) in weights.iter().enumerate() {
+        if target < *weight {
+            return Some(index)
+        }
+        target -= *weight
+    }
+    Some(weights.len() - 1)
+}
+
+fn delim_indices(nodes: &[Node]) -> Vec<NodeRef> {
+    nodes.iter().enumerate().filter_map(|item| {
+        match item {
+            (index, &Node::Delim(..)) => Some(index),
+            _ => None,
+        }
+    }).collect()
+}
+
 fn rand_delim<'buf, R: Rng>(mut rng: &mut R, nodes: &[Node<'buf>]) -> Option<(NodeRef, Delim<'buf>, RangeRef)> {
     let delims: Vec<_> = nodes.iter().enumerate().filter_map(|item| {
         match item {
@@ -68,6 +370,48 @@ fn rand_delim<'buf, R: Rng>(mut rng: &mut R, nodes: &[Node<'buf>]) -> Option<(No
     rng.choose(&delims[..]).cloned()
 }
 
+fn rand_token<'buf, R: Rng>(rng: &mut R, nodes: &[Node<'buf>]) -> Option<NodeRef> {
+    rand_token_where(rng, nodes, |_| true)
+}
+
+fn rand_token_where<'buf, R: Rng, F: Fn(&[u8]) -> bool>(rng: &mut R, nodes: &[Node<'buf>], predicate: F) -> Option<NodeRef> {
+    let tokens: Vec<_> = nodes.iter().enumerate().filter_map(|item| {
+        match item {
+            (index, &Node::Token(ref token)) if predicate(token) => Some(index),
+            _ => None,
+        }
+    }).collect();
+    rng.choose(&tokens[..]).cloned()
+}
+
+// A token that is entirely an ASCII decimal integer, with optional sign.
+fn parse_number(token: &[u8]) -> Option<i64> {
+    str::from_utf8(token).ok().and_then(|text| i64::from_str(text).ok())
+}
+
+// Values around common integer limits and around `value` itself, for
+// counts and offsets that are off by one or overflow.
+fn boundary_numbers(value: i64) -> Vec<Vec<u8>> {
+    let mut numbers: Vec<Vec<u8>> = vec![
+        0,
+        1,
+        -1,
+        value.wrapping_add(1),
+        value.wrapping_sub(1),
+        value.wrapping_neg(),
+        i32::max_value() as i64,
+        i32::max_value() as i64 + 1,
+        u32::max_value() as i64,
+        u32::max_value() as i64 + 1,
+        i64::max_value(),
+        i64::min_value(),
+    ].into_iter().map(|number| number.to_string().into_bytes()).collect();
+    numbers.push(u64::max_value().to_string().into_bytes());
+    // Too big for any fixed-width integer.
+    numbers.push(vec![b'9'; 40]);
+    numbers
+}
+
 struct SerializeState {
     have_serialized_range: Vec<bool>,
 }
@@ -93,8 +437,51 @@ impl SerializeState {
     }
 }
 
+enum SerializeWork<'a> {
+    Node(NodeRef),
+    Bytes(&'a [u8]),
+    Reset(RangeRef),
+    EndLengthPrefixed(LengthField),
+}
+
+// Output of the length-prefixed sections being serialized, innermost
+// last. Their length is only known once their range is done. `len` is
+// kept as they grow, so checking the pending total doesn't walk them.
+struct Payloads {
+    stack: Vec<Vec<u8>>,
+    len: usize,
+}
+
+impl Payloads {
+    fn emit<S: SerializeInto>(self: &mut Self, out: &mut S, bytes: &[u8]) {
+        match self.stack.last_mut() {
+            Some(payload) => {
+                payload.extend(bytes);
+                self.len += bytes.len()
+            },
+            None => out.push(bytes),
+        }
+    }
+
+    fn open(self: &mut Self) {
+        self.stack.push(Vec::new())
+    }
+
+    fn close(self: &mut Self) -> Vec<u8> {
+        let payload = self.stack.pop().expect("length-prefixed payload");
+        self.len -= payload.len();
+        payload
+    }
+}
+
 pub trait SerializeInto {
     fn push(&mut self, &[u8]);
+
+    // Called with the number of bytes buffered for length-prefixed sections
+    // that have yet to be pushed. Returning true stops serialization.
+    fn is_full(&mut self, _pending: usize) -> bool {
+        false
+    }
 }
 
 impl SerializeInto for Vec<u8> {
@@ -103,9 +490,22 @@ impl SerializeInto for Vec<u8> {
     }
 }
 
+struct LenSerializer {
+    len: usize,
+}
+
+impl SerializeInto for LenSerializer {
+    fn push(self: &mut Self, token: &[u8]) {
+        self.len += token.len()
+    }
+}
+
+// Writes as much as fits in `slice`, and records whether anything had to
+// be dropped.
 pub struct SliceSerializer<'buf> {
     slice: &'buf mut [u8],
     cur_offset: usize,
+    truncated: bool,
 }
 
 impl<'buf> SliceSerializer<'buf> {
@@ -113,18 +513,26 @@ impl<'buf> SliceSerializer<'buf> {
         SliceSerializer {
             slice: slice,
             cur_offset: 0,
+            truncated: false,
         }
     }
 
     pub fn bytes_written(self: &Self) -> usize {
         self.cur_offset
     }
+
+    pub fn was_truncated(self: &Self) -> bool {
+        self.truncated
+    }
 }
 
 impl<'buf> SerializeInto for SliceSerializer<'buf> {
     fn push(self: &mut Self, token: &[u8]) {
         let remaining = self.slice.len() - self.cur_offset;
         let num_bytes_to_write = cmp::min(remaining, token.len());
+        if num_bytes_to_write < token.len() {
+            self.truncated = true
+        }
         if num_bytes_to_write > 0 {
             self.slice[self.cur_offset..self.cur_offset+num_bytes_to_write].copy_from_slice(&token[..num_bytes_to_write]);
             self.cur_offset += num_bytes_to_write
@@ -132,39 +540,136 @@ impl<'buf> SerializeInto for SliceSerializer<'buf> {
     }
 }
 
+// Passes at most `remaining` bytes through to `out`, and records whether
+// anything had to be dropped.
+struct CappedSerializer<'a, S: SerializeInto + 'a> {
+    out: &'a mut S,
+    remaining: usize,
+    truncated: bool,
+}
+
+impl<'a, S: SerializeInto> SerializeInto for CappedSerializer<'a, S> {
+    fn push(self: &mut Self, token: &[u8]) {
+        let num_bytes_to_write = cmp::min(self.remaining, token.len());
+        if num_bytes_to_write < token.len() {
+            self.truncated = true
+        }
+        if num_bytes_to_write > 0 {
+            self.out.push(&token[..num_bytes_to_write]);
+            self.remaining -= num_bytes_to_write
+        }
+    }
+
+    fn is_full(self: &mut Self, pending: usize) -> bool {
+        if pending > self.remaining {
+            self.truncated = true
+        }
+        self.truncated
+    }
+}
+
+// Appends `record` to a stream readable by `read_packed`.
+pub fn write_packed<W: Write>(writer: &mut W, record: &[u8]) -> io::Result<()> {
+    if record.len() > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "record too large to pack"))
+    }
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(record)
+}
+
+// Reads the records of a packed stream, as written by `write_packed`. A
+// packed stream is a sequence of records, each a 4-byte little-endian
+// length followed by that many bytes, with no header or trailer, so
+// streams can be concatenated. Iteration stops at the end of the stream,
+// or at the first read error or truncated record.
+pub fn read_packed<R: Read>(mut reader: R) -> impl Iterator<Item=Vec<u8>> {
+    iter::from_fn(move || {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).ok()?;
+        let len = u32::from_le_bytes(len) as usize;
+        let mut record = Vec::new();
+        match (&mut reader).take(len as u64).read_to_end(&mut record) {
+            Ok(n) if n == len => Some(record),
+            _ => None,
+        }
+    })
+}
+
 impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
     pub fn new(parsed: &'parse ParsedFile<'buf>) -> Self {
         FuzzFile {
             root: Cow::from(parsed.root.as_slice()),
             nodes: Cow::from(parsed.nodes.as_slice()),
             ranges: Cow::from(parsed.ranges.as_slice()),
+            touched: Vec::new(),
         }
     }
 
-    fn serialize_noderef<S: SerializeInto>(self: &Self, noderef: NodeRef, mut state: &mut SerializeState, mut out: &mut S) {
-        match self.nodes[noderef] {
-            Node::Delim(ref delim, rangeref) => {
-                out.push(delim.start_pattern);
-                if state.should_serialize(rangeref) {
-                    for noderef in &self.ranges[rangeref] {
-                        self.serialize_noderef(*noderef, &mut state, out)
-                    }
-                    state.reset(rangeref);
-                }
-                out.push(delim.end_pattern);
-            },
-            Node::Range(rangeref) => {
-                if state.should_serialize(rangeref) {
-                    for noderef in &self.ranges[rangeref] {
-                        self.serialize_noderef(*noderef, &mut state, out)
+    fn touch(self: &mut Self, touched: Touched) {
+        self.touched.push(touched)
+    }
+
+    pub fn touched(self: &Self) -> &[Touched] {
+        &self.touched[..]
+    }
+
+    // Queues the nodes of a range, unless it is already being serialized
+    // further up the tree.
+    fn push_range_work<'a>(self: &'a Self, rangeref: RangeRef, state: &mut SerializeState, work: &mut Vec<SerializeWork<'a>>) {
+        if state.should_serialize(rangeref) {
+            work.push(SerializeWork::Reset(rangeref));
+            for noderef in self.ranges[rangeref].iter().rev() {
+                work.push(SerializeWork::Node(*noderef))
+            }
+        }
+    }
+
+    // Walks the tree with an explicit stack rather than recursion, so that
+    // arbitrarily deep trees can't overflow the call stack.
+    fn serialize_noderef<S: SerializeInto>(self: &Self, noderef: NodeRef, state: &mut SerializeState, out: &mut S) {
+        let mut work = vec![SerializeWork::Node(noderef)];
+        let mut payloads = Payloads { stack: Vec::new(), len: 0 };
+        while let Some(item) = work.pop() {
+            if out.is_full(payloads.len) {
+                break
+            }
+            match item {
+                SerializeWork::Node(noderef) => {
+                    match self.nodes[noderef] {
+                        Node::Delim(ref delim, rangeref) => {
+                            payloads.emit(out, &delim.start_pattern);
+                            work.push(SerializeWork::Bytes(&delim.end_pattern));
+                            self.push_range_work(rangeref, state, &mut work);
+                        },
+                        Node::Range(rangeref) => {
+                            self.push_range_work(rangeref, state, &mut work);
+                        },
+                        Node::LengthPrefixed(field, rangeref) => {
+                            payloads.open();
+                            work.push(SerializeWork::EndLengthPrefixed(field));
+                            self.push_range_work(rangeref, state, &mut work);
+                        },
+                        Node::Token(ref token) => payloads.emit(out, token),
                     }
-                    state.reset(rangeref);
-                }
-            },
-            Node::Token(token) => out.push(token),
+                },
+                SerializeWork::Bytes(bytes) => payloads.emit(out, bytes),
+                SerializeWork::Reset(rangeref) => state.reset(rangeref),
+                SerializeWork::EndLengthPrefixed(field) => {
+                    // The length field is always re-encoded, so it stays
+                    // consistent with whatever the payload was mutated into.
+                    let payload = payloads.close();
+                    payloads.emit(out, &field.encode(payload.len()));
+                    payloads.emit(out, &payload);
+                },
+            }
         }
     }
 
+    pub fn serialize_subtree<S: SerializeInto>(self: &Self, noderef: NodeRef, out: &mut S) {
+        let mut state = SerializeState::new(&self.ranges[..]);
+        self.serialize_noderef(noderef, &mut state, out);
+    }
+
     pub fn serialize<S: SerializeInto>(self: &Self, out: &mut S) {
         let mut state = SerializeState::new(&self.ranges[..]);
 
@@ -173,40 +678,283 @@ impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
         }
     }
 
+    // Like `serialize`, but stops once `max` bytes have been produced, so a
+    // tree that has grown huge is never fully walked. Returns true if the
+    // output was truncated.
+    pub fn serialize_capped<S: SerializeInto>(self: &Self, out: &mut S, max: usize) -> bool {
+        let mut capped = CappedSerializer { out: out, remaining: max, truncated: false };
+        let mut state = SerializeState::new(&self.ranges[..]);
+
+        for noderef in self.root.iter() {
+            if capped.truncated {
+                break
+            }
+            self.serialize_noderef(*noderef, &mut state, &mut capped);
+        }
+        capped.truncated
+    }
+
+    // Checks that every node and range index in the tree is in bounds.
+    pub fn validate(self: &Self) -> Result<()> {
+        for noderef in self.root.iter().chain(self.ranges.iter().flat_map(|range| range.iter())) {
+            if *noderef >= self.nodes.len() {
+                bail!("NodeRef {} out of bounds ({} nodes)", noderef, self.nodes.len());
+            }
+        }
+        for (noderef, node) in self.nodes.iter().enumerate() {
+            match *node {
+                Node::Delim(_, rangeref) | Node::LengthPrefixed(_, rangeref) | Node::Range(rangeref) => {
+                    if rangeref >= self.ranges.len() {
+                        bail!("Node {} refers to RangeRef {} out of bounds ({} ranges)", noderef, rangeref, self.ranges.len());
+                    }
+                },
+                Node::Token(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Like `validate`, but only checks the nodes and ranges that mutations
+    // touched, and the nodes directly in those ranges. Everything else is
+    // as parsed, so is assumed to be valid.
+    pub fn validate_touched(self: &Self) -> Result<()> {
+        for touched in &self.touched {
+            match *touched {
+                Touched::Node(noderef) => self.validate_node(noderef)?,
+                Touched::Range(None) => {
+                    for noderef in self.root.iter() {
+                        self.validate_node(*noderef)?
+                    }
+                },
+                Touched::Range(Some(rangeref)) => {
+                    if rangeref >= self.ranges.len() {
+                        bail!("RangeRef {} out of bounds ({} ranges)", rangeref, self.ranges.len());
+                    }
+                    for noderef in &self.ranges[rangeref] {
+                        self.validate_node(*noderef)?
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_node(self: &Self, noderef: NodeRef) -> Result<()> {
+        match self.nodes.get(noderef) {
+            Some(&Node::Delim(_, rangeref)) | Some(&Node::LengthPrefixed(_, rangeref)) | Some(&Node::Range(rangeref)) => {
+                if rangeref >= self.ranges.len() {
+                    bail!("Node {} refers to RangeRef {} out of bounds ({} ranges)", noderef, rangeref, self.ranges.len());
+                }
+            },
+            Some(&Node::Token(_)) => (),
+            None => bail!("NodeRef {} out of bounds ({} nodes)", noderef, self.nodes.len()),
+        }
+        Ok(())
+    }
+
+    // The maximum nesting of the tree, a flat file of tokens has depth 0.
+    // Like serialization, this walks the tree without recursion.
+    pub fn depth(self: &Self) -> usize {
+        let mut state = SerializeState::new(&self.ranges[..]);
+        let mut work: Vec<(Option<NodeRef>, usize)> = self.root.iter().rev().map(|noderef| (Some(*noderef), 0)).collect();
+        let mut max_depth = 0;
+        while let Some(item) = work.pop() {
+            match item {
+                (Some(noderef), depth) => {
+                    match self.nodes[noderef] {
+                        Node::Delim(_, rangeref) | Node::LengthPrefixed(_, rangeref) | Node::Range(rangeref) => {
+                            max_depth = cmp::max(max_depth, depth + 1);
+                            if state.should_serialize(rangeref) {
+                                // A marker to reset the range once its nodes are done.
+                                work.push((None, rangeref));
+                                for noderef in self.ranges[rangeref].iter().rev() {
+                                    work.push((Some(*noderef), depth + 1))
+                                }
+                            }
+                        },
+                        Node::Token(_) => (),
+                    }
+                },
+                (None, rangeref) => state.reset(rangeref),
+            }
+        }
+        max_depth
+    }
+
+    // The size of the node and range arenas together.
+    pub fn total_nodes(self: &Self) -> usize {
+        self.nodes.len() + self.ranges.len()
+    }
+
+    pub fn serialized_len(self: &Self) -> usize {
+        let mut counter = LenSerializer { len: 0 };
+        self.serialize(&mut counter);
+        counter.len
+    }
+
     pub fn swap_ranges<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
-        match rand_indices::<R, _>(rng, &self.ranges[..]) {
+        match rand_indices(rng, &self.ranges[..]) {
             Some((index0, index1)) => {
-                let mut ranges = self.ranges.to_mut();
+                let ranges = self.ranges.to_mut();
                 ranges.swap(index0, index1);
+                self.touch(Touched::Range(Some(index0)));
+                self.touch(Touched::Range(Some(index1)));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn swap_tokens<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        let tokens: Vec<NodeRef> = self.nodes.iter().enumerate().filter_map(|item| {
+            match item {
+                (index, &Node::Token(_)) => Some(index),
+                _ => None,
+            }
+        }).collect();
+        match rand_indices(rng, &tokens[..]) {
+            Some((index0, index1)) => {
+                let nodes = self.nodes.to_mut();
+                nodes.swap(tokens[index0], tokens[index1]);
+                self.touch(Touched::Node(tokens[index0]));
+                self.touch(Touched::Node(tokens[index1]));
                 true
             },
             None => false,
         }
     }
 
+    // Picks a range like `Rng::choose`, but by index so it can be touched.
+    fn rand_rangeref<R: Rng>(self: &Self, rng: &mut R) -> Option<RangeRef> {
+        if self.ranges.is_empty() {
+            None
+        } else {
+            Some(rng.gen_range(0, self.ranges.len()))
+        }
+    }
+
     pub fn shuffle_range<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
-        match rng.choose_mut(self.ranges.to_mut()) {
-            Some(range) => {
-                rng.shuffle(range);
+        match self.rand_rangeref(rng) {
+            Some(rangeref) => {
+                rng.shuffle(&mut self.ranges.to_mut()[rangeref]);
+                self.touch(Touched::Range(Some(rangeref)));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn reverse_range<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        match self.rand_rangeref(rng) {
+            Some(rangeref) if self.ranges[rangeref].len() > 1 => {
+                self.ranges.to_mut()[rangeref].reverse();
+                self.touch(Touched::Range(Some(rangeref)));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Removes one token from the root or from a range.
+    pub fn delete_token<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        let is_token = |noderef: &NodeRef| match self.nodes[*noderef] {
+            Node::Token(_) => true,
+            _ => false,
+        };
+        let mut positions = Vec::new();
+        for (index, child) in self.root.iter().enumerate() {
+            if is_token(child) {
+                positions.push((None, index))
+            }
+        }
+        for (rangeref, range) in self.ranges.iter().enumerate() {
+            for (index, child) in range.iter().enumerate() {
+                if is_token(child) {
+                    positions.push((Some(rangeref), index))
+                }
+            }
+        }
+
+        match rng.choose(&positions[..]) {
+            Some(&(Some(rangeref), index)) => {
+                self.ranges.to_mut()[rangeref].remove(index);
+                self.touch(Touched::Range(Some(rangeref)));
+                true
+            },
+            Some(&(None, index)) => {
+                self.root.to_mut().remove(index);
+                self.touch(Touched::Range(None));
                 true
             },
             None => false,
         }
     }
 
-    pub fn duplicate_range<R: Rng>(self: &mut Self, rng: &mut R, max_duplications: usize) -> bool {
+    // Adds a token of 1 to `max_len` random bytes at any position in the
+    // root or in a range.
+    pub fn insert_random_token<R: Rng>(self: &mut Self, rng: &mut R, max_len: usize) -> bool {
+        if max_len < 1 {
+            return false
+        }
+
+        let mut token = vec![0; rng.gen_range(1, max_len + 1)];
+        rng.fill_bytes(&mut token[..]);
+        let noderef = self.nodes.len();
+        self.nodes.to_mut().push(Node::Token(Cow::Owned(token)));
+
+        let rangeref = rng.gen_range(0, self.ranges.len() + 1);
+        let parent = if rangeref == self.ranges.len() { None } else { Some(rangeref) };
+        let range = match parent {
+            Some(rangeref) => &mut self.ranges.to_mut()[rangeref],
+            None => self.root.to_mut(),
+        };
+        let index = rng.gen_range(0, range.len() + 1);
+        range.insert(index, noderef);
+        self.touch(Touched::Node(noderef));
+        self.touch(Touched::Range(parent));
+        true
+    }
+
+    // Drops the tail of a random range, keeping at most `len - 1` nodes.
+    pub fn truncate_range<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        if self.ranges.is_empty() {
+            return false
+        }
+        let rangeref = rng.gen_range(0, self.ranges.len());
+        let len = self.ranges[rangeref].len();
+        if len == 0 {
+            return false
+        }
+        let cut = rng.gen_range(0, len);
+        self.ranges.to_mut()[rangeref].truncate(cut);
+        self.touch(Touched::Range(Some(rangeref)));
+        true
+    }
+
+    pub fn duplicate_range<R: Rng>(self: &mut Self, rng: &mut R, max_duplications: usize, prefer_small: bool) -> bool {
         if max_duplications < 1 {
             return false
         }
 
-        match rng.choose_mut(self.ranges.to_mut()) {
-            Some(range) => {
-                let num_duplications = rng.gen_range(1, max_duplications);
+        let index = if prefer_small {
+            let weights: Vec<f64> = self.ranges.iter().map(|range| 1.0 / (range.len() + 1) as f64).collect();
+            rand_index_weighted(rng, &weights[..])
+        } else if self.ranges.is_empty() {
+            None
+        } else {
+            Some(rng.gen_range(0, self.ranges.len()))
+        };
+
+        match index {
+            Some(index) => {
+                let range = &mut self.ranges.to_mut()[index];
+                let num_duplications = rng.gen_range(1, max_duplications + 1);
                 let mut extension = Vec::new();
                 for _ in 0..num_duplications {
                     extension.extend(&range[..])
                 }
                 range.extend(&extension[..]);
+                self.touch(Touched::Range(Some(index)));
                 true
             },
             None => false,
@@ -214,9 +962,9 @@ impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
     }
 
     pub fn duplicate_root_node<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
-        match rand_indices::<R, _>(rng, &self.ranges[..]) {
+        match rand_indices(rng, &self.ranges[..]) {
             Some((src_index, dst_index)) => {
-                let mut nodes = self.nodes.to_mut();
+                let nodes = self.nodes.to_mut();
 
                 let dup_node = nodes[dst_index].clone();
                 let noderef = nodes.len();
@@ -228,70 +976,599 @@ impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
                 ranges.push(range);
 
                 nodes[dst_index] = Node::Range(rangeref);
+                self.touch(Touched::Node(noderef));
+                self.touch(Touched::Range(Some(rangeref)));
+                self.touch(Touched::Node(dst_index));
                 true
             },
             None => false,
         }
     }
 
+    fn remove_delim_at(self: &mut Self, index: NodeRef, rangeref: RangeRef) {
+        let nodes = self.nodes.to_mut();
+        nodes[index] = Node::Range(rangeref);
+        self.touch(Touched::Node(index));
+    }
+
     pub fn remove_delim<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
         match rand_delim(&mut rng, &self.nodes[..]) {
             Some((index, _, rangeref)) => {
-                let mut nodes = self.nodes.to_mut();
-                nodes[index] = Node::Range(rangeref);
+                self.remove_delim_at(index, rangeref);
                 true
             },
             None => false,
         }
     }
 
+    // Replaces a delim in the root or in a range with the nodes of its own
+    // range, so both its patterns and the boundary between them are gone.
+    pub fn collapse_delim<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        let inner_range = |noderef: &NodeRef| match self.nodes[*noderef] {
+            Node::Delim(_, rangeref) => Some(rangeref),
+            _ => None,
+        };
+        let mut positions = Vec::new();
+        for (index, child) in self.root.iter().enumerate() {
+            if let Some(inner) = inner_range(child) {
+                positions.push((None, index, inner))
+            }
+        }
+        for (rangeref, range) in self.ranges.iter().enumerate() {
+            for (index, child) in range.iter().enumerate() {
+                match inner_range(child) {
+                    Some(inner) if inner != rangeref => positions.push((Some(rangeref), index, inner)),
+                    _ => (),
+                }
+            }
+        }
+
+        let (parent, index, inner) = match rng.choose(&positions[..]) {
+            Some(position) => *position,
+            None => return false,
+        };
+        let children = self.ranges[inner].clone();
+        let range = match parent {
+            Some(rangeref) => &mut self.ranges.to_mut()[rangeref],
+            None => self.root.to_mut(),
+        };
+        range.splice(index..index + 1, children);
+        self.touch(Touched::Range(parent));
+        true
+    }
+
+    fn swap_delim_at(self: &mut Self, index: NodeRef, delim: Delim<'buf>, rangeref: RangeRef) {
+        let nodes = self.nodes.to_mut();
+        let delim = Delim::new(delim.end_pattern, delim.start_pattern);
+        nodes[index] = Node::Delim(delim, rangeref);
+        self.touch(Touched::Node(index));
+    }
+
     pub fn swap_delim<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
         match rand_delim(&mut rng, &self.nodes[..]) {
             Some((index, delim, rangeref)) => {
-                let mut nodes = self.nodes.to_mut();
-                let delim = Delim::new(delim.end_pattern, delim.start_pattern);
-                nodes[index] = Node::Delim(delim, rangeref);
+                self.swap_delim_at(index, delim, rangeref);
                 true
             },
             None => false,
         }
     }
 
+    // Swaps two adjacent, distinct bytes of a delim's start or end pattern,
+    // so the marker becomes a near-miss of the real one.
+    pub fn transpose_delim_bytes<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        let is_transposable = |pattern: &[u8]| pattern.windows(2).any(|pair| pair[0] != pair[1]);
+        let mut candidates = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if let Node::Delim(ref delim, _) = *node {
+                if is_transposable(&delim.start_pattern) {
+                    candidates.push((index, true))
+                }
+                if is_transposable(&delim.end_pattern) {
+                    candidates.push((index, false))
+                }
+            }
+        }
+
+        let (index, is_start) = match rng.choose(&candidates[..]) {
+            Some(candidate) => *candidate,
+            None => return false,
+        };
+        let nodes = self.nodes.to_mut();
+        if let Node::Delim(ref mut delim, _) = nodes[index] {
+            let pattern = if is_start {
+                delim.start_pattern.to_mut()
+            } else {
+                delim.end_pattern.to_mut()
+            };
+            let positions: Vec<usize> = (0..pattern.len() - 1).filter(|i| pattern[*i] != pattern[*i + 1]).collect();
+            let position = *rng.choose(&positions[..]).expect("transposable pattern");
+            pattern.swap(position, position + 1);
+        }
+        self.touch(Touched::Node(index));
+        true
+    }
+
+    fn nest_delim_at(self: &mut Self, index: NodeRef, delim: Delim<'buf>, rangeref: RangeRef) {
+        self.repeat_delim_at(index, delim, rangeref, 1)
+    }
+
+    // Wraps the delim at `index` in `depth` more copies of itself, each in
+    // its own single-node range.
+    fn repeat_delim_at(self: &mut Self, index: NodeRef, delim: Delim<'buf>, rangeref: RangeRef, depth: usize) {
+        let nodes = self.nodes.to_mut();
+        let ranges = self.ranges.to_mut();
+
+        let mut inner_rangeref = rangeref;
+        for _ in 0..depth {
+            let nested_noderef = nodes.len();
+            nodes.push(Node::Delim(delim.clone(), inner_rangeref));
+
+            inner_rangeref = ranges.len();
+            ranges.push(vec![nested_noderef]);
+            self.touched.push(Touched::Node(nested_noderef));
+            self.touched.push(Touched::Range(Some(inner_rangeref)));
+        }
+
+        nodes[index] = Node::Delim(delim, inner_rangeref);
+        self.touched.push(Touched::Node(index));
+    }
+
     pub fn nest_delim<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
         match rand_delim(&mut rng, &self.nodes[..]) {
-            Some((index, ref delim, rangeref)) => {
-                let mut nodes = self.nodes.to_mut();
-                let mut ranges = self.ranges.to_mut();
-
-                let nested_noderef = nodes.len();
-                nodes.push(Node::Delim(delim.clone(), rangeref));
+            Some((index, delim, rangeref)) => {
+                self.nest_delim_at(index, delim, rangeref);
+                true
+            },
+            None => false,
+        }
+    }
 
-                let nested_rangeref = ranges.len();
-                ranges.push(vec![nested_noderef]);
+    pub fn repeat_delim<R: Rng>(self: &mut Self, mut rng: &mut R, max_depth: usize) -> bool {
+        if max_depth < 1 {
+            return false
+        }
 
-                nodes[index] = Node::Delim(delim.clone(), nested_rangeref);
+        match rand_delim(&mut rng, &self.nodes[..]) {
+            Some((index, delim, rangeref)) => {
+                let depth = rng.gen_range(1, max_depth + 1);
+                self.repeat_delim_at(index, delim, rangeref, depth);
                 true
             },
             None => false,
         }
     }
 
+    fn empty_delim_at(self: &mut Self, index: NodeRef, delim: Delim<'buf>) {
+        let nodes = self.nodes.to_mut();
+        let ranges = self.ranges.to_mut();
+
+        let rangeref = ranges.len();
+        ranges.push(vec![]);
+
+        nodes[index] = Node::Delim(delim, rangeref);
+        self.touch(Touched::Range(Some(rangeref)));
+        self.touch(Touched::Node(index));
+    }
+
     pub fn empty_delim<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
         match rand_delim(&mut rng, &self.nodes[..]) {
             Some((index, delim, _)) => {
-                let mut nodes = self.nodes.to_mut();
-                let mut ranges = self.ranges.to_mut();
+                self.empty_delim_at(index, delim);
+                true
+            },
+            None => false,
+        }
+    }
 
-                let rangeref = ranges.len();
-                ranges.push(vec![]);
+    // Every place a node is referenced from, as (None, index) for the root
+    // or (Some(rangeref), index) for a range.
+    fn parent_positions(self: &Self, noderef: NodeRef) -> Vec<(Option<RangeRef>, usize)> {
+        let mut positions = Vec::new();
+        for (index, child) in self.root.iter().enumerate() {
+            if *child == noderef {
+                positions.push((None, index))
+            }
+        }
+        for (rangeref, range) in self.ranges.iter().enumerate() {
+            for (index, child) in range.iter().enumerate() {
+                if *child == noderef {
+                    positions.push((Some(rangeref), index))
+                }
+            }
+        }
+        positions
+    }
+
+    fn insert_noderef(self: &mut Self, parent: Option<RangeRef>, index: usize, noderef: NodeRef) {
+        match parent {
+            Some(rangeref) => self.ranges.to_mut()[rangeref].insert(index, noderef),
+            None => self.root.to_mut().insert(index, noderef),
+        }
+        self.touch(Touched::Range(parent));
+    }
+
+    // Copies the nodes of `range` in `donor`, and everything below them,
+    // into this file. Returns the copied range, remapped into this file's
+    // nodes and ranges.
+    fn graft(self: &mut Self, donor: &ParsedFile<'buf>, range: &[NodeRef]) -> Vec<NodeRef> {
+        let mut node_map = HashMap::new();
+        let mut range_map = HashMap::new();
+        let mut donor_nodes = Vec::new();
+        let mut donor_ranges = Vec::new();
+        let mut work: Vec<NodeRef> = range.to_vec();
+        while let Some(noderef) = work.pop() {
+            match node_map.entry(noderef) {
+                Entry::Occupied(_) => continue,
+                Entry::Vacant(entry) => {
+                    entry.insert(self.nodes.len() + donor_nodes.len());
+                },
+            }
+            donor_nodes.push(noderef);
+            match donor.nodes[noderef] {
+                Node::Delim(_, rangeref) | Node::LengthPrefixed(_, rangeref) | Node::Range(rangeref) => {
+                    if let Entry::Vacant(entry) = range_map.entry(rangeref) {
+                        entry.insert(self.ranges.len() + donor_ranges.len());
+                        donor_ranges.push(rangeref);
+                        work.extend(&donor.ranges[rangeref])
+                    }
+                },
+                Node::Token(_) => (),
+            }
+        }
+
+        let nodes = self.nodes.to_mut();
+        for noderef in donor_nodes {
+            self.touched.push(Touched::Node(nodes.len()));
+            nodes.push(match donor.nodes[noderef] {
+                Node::Delim(ref delim, rangeref) => Node::Delim(delim.clone(), range_map[&rangeref]),
+                Node::LengthPrefixed(field, rangeref) => Node::LengthPrefixed(field, range_map[&rangeref]),
+                Node::Range(rangeref) => Node::Range(range_map[&rangeref]),
+                Node::Token(ref token) => Node::Token(token.clone()),
+            })
+        }
+        let ranges = self.ranges.to_mut();
+        for rangeref in donor_ranges {
+            self.touched.push(Touched::Range(Some(ranges.len())));
+            ranges.push(donor.ranges[rangeref].iter().map(|noderef| node_map[noderef]).collect())
+        }
+        range.iter().map(|noderef| node_map[noderef]).collect()
+    }
+
+    // Crossover: replaces a random range, or the root, with a copy of a
+    // random range of `donor`, or inserts the copy into it.
+    pub fn splice<R: Rng>(self: &mut Self, rng: &mut R, donor: &ParsedFile<'buf>) -> bool {
+        let donor_range = match rng.gen_range(0, donor.ranges.len() + 1) {
+            0 => &donor.root[..],
+            index => &donor.ranges[index - 1][..],
+        };
+        if donor_range.is_empty() {
+            return false
+        }
+        let grafted = self.graft(donor, donor_range);
+
+        let parent = match rng.gen_range(0, self.ranges.len() + 1) {
+            0 => None,
+            index => Some(index - 1),
+        };
+        let target = match parent {
+            Some(rangeref) => &mut self.ranges.to_mut()[rangeref],
+            None => self.root.to_mut(),
+        };
+        if rng.gen() {
+            *target = grafted
+        } else {
+            let position = rng.gen_range(0, target.len() + 1);
+            target.splice(position..position, grafted);
+        }
+        self.touch(Touched::Range(parent));
+        true
+    }
+
+    pub fn inject_extra_close<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        match rand_delim(&mut rng, &self.nodes[..]) {
+            Some((index, delim, _)) => {
+                match rng.choose(&self.parent_positions(index)[..]).cloned() {
+                    Some((parent, position)) => {
+                        let nodes = self.nodes.to_mut();
+                        let noderef = nodes.len();
+                        nodes.push(Node::Token(delim.end_pattern));
+                        self.touch(Touched::Node(noderef));
+                        self.insert_noderef(parent, position + 1, noderef);
+                        true
+                    },
+                    None => false,
+                }
+            },
+            None => false,
+        }
+    }
+
+    pub fn randomize_token<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        match rand_token(&mut rng, &self.nodes[..]) {
+            Some(index) => {
+                let nodes = self.nodes.to_mut();
+                let mut token = match nodes[index] {
+                    Node::Token(ref token) => vec![0; token.len()],
+                    _ => unreachable!(),
+                };
+                rng.fill_bytes(&mut token[..]);
+                nodes[index] = Node::Token(Cow::Owned(token));
+                self.touch(Touched::Node(index));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Flips between 1 and `max_flips` random bits of a non-empty token. The
+    // same bit may be picked twice, flipping it back.
+    pub fn flip_bits<R: Rng>(self: &mut Self, mut rng: &mut R, max_flips: usize) -> bool {
+        if max_flips < 1 {
+            return false
+        }
+
+        match rand_token_where(&mut rng, &self.nodes[..], |token| !token.is_empty()) {
+            Some(index) => {
+                let nodes = self.nodes.to_mut();
+                let mut token = match nodes[index] {
+                    Node::Token(ref token) => token.to_vec(),
+                    _ => unreachable!(),
+                };
+                for _ in 0..rng.gen_range(1, max_flips + 1) {
+                    let bit = rng.gen_range(0, token.len() * 8);
+                    token[bit / 8] ^= 1 << (bit % 8);
+                }
+                nodes[index] = Node::Token(Cow::Owned(token));
+                self.touch(Touched::Node(index));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn replace_token_with_dict<R: Rng>(self: &mut Self, mut rng: &mut R, dict: &[Vec<u8>]) -> bool {
+        if dict.is_empty() {
+            return false
+        }
+
+        match rand_token(&mut rng, &self.nodes[..]) {
+            Some(index) => {
+                let entry = rng.choose(dict).expect("non-empty dictionary");
+                let nodes = self.nodes.to_mut();
+                nodes[index] = Node::Token(Cow::Owned(entry.clone()));
+                self.touch(Touched::Node(index));
+                true
+            },
+            None => false,
+        }
+    }
 
-                nodes[index] = Node::Delim(delim, rangeref);
+    // Rewrites a numeric token to a boundary value that differs from it.
+    pub fn mutate_number<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        match rand_token_where(&mut rng, &self.nodes[..], |token| parse_number(token).is_some()) {
+            Some(index) => {
+                let nodes = self.nodes.to_mut();
+                let numbers = match nodes[index] {
+                    Node::Token(ref token) => {
+                        let value = parse_number(token).expect("numeric token");
+                        boundary_numbers(value).into_iter().filter(|number| number[..] != token[..]).collect::<Vec<_>>()
+                    },
+                    _ => unreachable!(),
+                };
+                let number = rng.choose(&numbers[..]).expect("a different boundary value").clone();
+                nodes[index] = Node::Token(Cow::Owned(number));
+                self.touch(Touched::Node(index));
                 true
             },
             None => false,
         }
     }
 
+    // Shuffles the bytes of a token that has at least two distinct bytes,
+    // so that the order is guaranteed to change.
+    pub fn permute_bytes<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        let is_permutable = |token: &[u8]| token.iter().any(|b| *b != token[0]);
+        match rand_token_where(&mut rng, &self.nodes[..], is_permutable) {
+            Some(index) => {
+                let nodes = self.nodes.to_mut();
+                let original = match nodes[index] {
+                    Node::Token(ref token) => token.to_vec(),
+                    _ => unreachable!(),
+                };
+                let mut permuted = original.clone();
+                while permuted == original {
+                    rng.shuffle(&mut permuted[..]);
+                }
+                nodes[index] = Node::Token(Cow::Owned(permuted));
+                self.touch(Touched::Node(index));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Copies a random substring of one token into a random position of
+    // another, or of the same token.
+    pub fn substring_splice<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        let src_index = match rand_token_where(&mut rng, &self.nodes[..], |token| !token.is_empty()) {
+            Some(index) => index,
+            None => return false,
+        };
+        let dst_index = match rand_token(&mut rng, &self.nodes[..]) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let substring = match self.nodes[src_index] {
+            Node::Token(ref token) => {
+                let start = rng.gen_range(0, token.len());
+                let end = rng.gen_range(start + 1, token.len() + 1);
+                token[start..end].to_vec()
+            },
+            _ => unreachable!(),
+        };
+
+        let nodes = self.nodes.to_mut();
+        let mut spliced = match nodes[dst_index] {
+            Node::Token(ref token) => token.to_vec(),
+            _ => unreachable!(),
+        };
+        let position = rng.gen_range(0, spliced.len() + 1);
+        spliced.splice(position..position, substring);
+        nodes[dst_index] = Node::Token(Cow::Owned(spliced));
+        self.touch(Touched::Node(dst_index));
+        true
+    }
+
+    // Truncates or extends the payload of a length-prefixed section. The
+    // payload is replaced by a single token, in a new range so that other
+    // nodes sharing the old range are unaffected.
+    pub fn resize_payload<R: Rng>(self: &mut Self, rng: &mut R) -> bool {
+        let candidates: Vec<_> = self.nodes.iter().enumerate().filter_map(|node| match node {
+            (index, &Node::LengthPrefixed(field, _)) => Some((index, field)),
+            _ => None,
+        }).collect();
+        let (index, field) = match rng.choose(&candidates[..]) {
+            Some(candidate) => *candidate,
+            None => return false,
+        };
+
+        let mut payload = Vec::new();
+        self.serialize_subtree(index, &mut payload);
+        let mut payload = payload.split_off(field.width);
+        let max_len = cmp::min(field.max_len(), 2 * payload.len() + 1);
+        let new_len = rng.gen_range(0, max_len + 1);
+        if new_len == payload.len() {
+            return false
+        } else if new_len < payload.len() {
+            payload.truncate(new_len)
+        } else {
+            let mut extension = vec![0; new_len - payload.len()];
+            rng.fill_bytes(&mut extension[..]);
+            payload.extend(extension)
+        }
+
+        let nodes = self.nodes.to_mut();
+        let noderef = nodes.len();
+        nodes.push(Node::Token(Cow::Owned(payload)));
+        let ranges = self.ranges.to_mut();
+        let rangeref = ranges.len();
+        ranges.push(vec![noderef]);
+        nodes[index] = Node::LengthPrefixed(field, rangeref);
+        self.touch(Touched::Node(noderef));
+        self.touch(Touched::Range(Some(rangeref)));
+        self.touch(Touched::Node(index));
+        true
+    }
+
+    pub fn swap_endianness<R: Rng>(self: &mut Self, mut rng: &mut R) -> bool {
+        let is_swappable = |token: &[u8]| {
+            (token.len() == 2 || token.len() == 4 || token.len() == 8) && !token.iter().eq(token.iter().rev())
+        };
+        match rand_token_where(&mut rng, &self.nodes[..], is_swappable) {
+            Some(index) => {
+                let nodes = self.nodes.to_mut();
+                let mut swapped = match nodes[index] {
+                    Node::Token(ref token) => token.to_vec(),
+                    _ => unreachable!(),
+                };
+                swapped.reverse();
+                nodes[index] = Node::Token(Cow::Owned(swapped));
+                self.touch(Touched::Node(index));
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn apply_at(self: &mut Self, mutation: &Mutation, position: Position) {
+        match position {
+            Position::Node(index) => {
+                if let Node::Delim(delim, rangeref) = self.nodes[index].clone() {
+                    match *mutation {
+                        Mutation::EmptyDelim => self.empty_delim_at(index, delim),
+                        Mutation::NestDelim => self.nest_delim_at(index, delim, rangeref),
+                        Mutation::RemoveDelim => self.remove_delim_at(index, rangeref),
+                        Mutation::SwapDelim => self.swap_delim_at(index, delim, rangeref),
+                        _ => unreachable!(),
+                    }
+                }
+            },
+            Position::Range(rangeref) => {
+                let range = &mut self.ranges.to_mut()[rangeref];
+                match *mutation {
+                    Mutation::ReverseRange => range.reverse(),
+                    Mutation::DuplicateRange => {
+                        let copy = range.clone();
+                        range.extend(copy)
+                    },
+                    _ => unreachable!(),
+                }
+                self.touch(Touched::Range(Some(rangeref)));
+            },
+        }
+    }
+
+    // None for mutations that need random choices beyond where to apply
+    // them, so can't be enumerated.
+    fn positions(self: &Self, mutation: &Mutation) -> Option<Vec<Position>> {
+        match *mutation {
+            Mutation::EmptyDelim | Mutation::NestDelim | Mutation::RemoveDelim | Mutation::SwapDelim => {
+                Some(delim_indices(&self.nodes[..]).into_iter().map(Position::Node).collect())
+            },
+            Mutation::ReverseRange | Mutation::DuplicateRange => {
+                Some((0..self.ranges.len()).map(Position::Range).collect())
+            },
+            _ => None,
+        }
+    }
+
+    pub fn apply_all_positions<'a>(self: &'a Self, mutation: &Mutation) -> Option<impl Iterator<Item=FuzzFile<'buf, 'parse>> + 'a> {
+        let mutation = mutation.clone();
+        let positions = self.positions(&mutation)?;
+        Some(positions.into_iter().map(move |position| {
+            let mut ff = self.clone();
+            ff.apply_at(&mutation, position);
+            ff
+        }))
+    }
+
+    // Removals that can only shrink the output. Children are listed last
+    // first, so applying one doesn't shift the index of the next.
+    fn reductions(self: &Self) -> Vec<Reduction> {
+        let mut reductions = vec![Reduction::ClearRange(None)];
+        reductions.extend((0..self.ranges.len()).map(|rangeref| Reduction::ClearRange(Some(rangeref))));
+        reductions.extend((0..self.root.len()).rev().map(|index| Reduction::RemoveChild(None, index)));
+        for (rangeref, range) in self.ranges.iter().enumerate() {
+            reductions.extend((0..range.len()).rev().map(|index| Reduction::RemoveChild(Some(rangeref), index)));
+        }
+        reductions.extend(delim_indices(&self.nodes[..]).into_iter().map(Reduction::CollapseDelim));
+        reductions
+    }
+
+    fn reduce(self: &mut Self, reduction: Reduction) {
+        match reduction {
+            Reduction::ClearRange(None) => self.root.to_mut().clear(),
+            Reduction::ClearRange(Some(rangeref)) => self.ranges.to_mut()[rangeref].clear(),
+            Reduction::RemoveChild(None, index) => {
+                if index < self.root.len() {
+                    self.root.to_mut().remove(index);
+                }
+            },
+            Reduction::RemoveChild(Some(rangeref), index) => {
+                if index < self.ranges[rangeref].len() {
+                    self.ranges.to_mut()[rangeref].remove(index);
+                }
+            },
+            Reduction::CollapseDelim(noderef) => {
+                if let Node::Delim(_, rangeref) = self.nodes[noderef] {
+                    self.remove_delim_at(noderef, rangeref)
+                }
+            },
+        }
+    }
+
     pub fn rand_delim<R: Rng>(self: &mut Self, mut rng: &mut R, delims: &[Delim<'buf>]) -> bool {
         if delims.is_empty() {
             return false
@@ -302,8 +1579,9 @@ impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
                 match rng.choose(&delims[..]) {
                     Some(&ref new_delim) => {
                         if new_delim != delim {
-                            let mut nodes = self.nodes.to_mut();
+                            let nodes = self.nodes.to_mut();
                             nodes[index] = Node::Delim(new_delim.clone(), rangeref);
+                            self.touch(Touched::Node(index));
                             true
                         } else {
                             false
@@ -319,27 +1597,484 @@ impl<'buf, 'parse> FuzzFile<'buf, 'parse> {
     }
 }
 
-pub fn fuzz_one<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, mut rng: &mut R, config: &'buf FuzzConfig) -> Option<FuzzFile<'buf, 'parse>> {
+// Where `apply_all_positions` applies a mutation.
+#[derive(Clone, Copy)]
+enum Position {
+    Node(NodeRef),
+    Range(RangeRef),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Reduction {
+    ClearRange(Option<RangeRef>),
+    RemoveChild(Option<RangeRef>, usize),
+    CollapseDelim(NodeRef),
+}
+
+// Shrinks `parsed` by removing ranges, collapsing delims and deleting nodes,
+// keeping each reduction only if `interesting` still holds for the
+// serialized output. Stops when no reduction can be kept.
+pub fn minimize<'buf, 'parse, F: FnMut(&[u8]) -> bool>(parsed: &'parse ParsedFile<'buf>, mut interesting: F) -> FuzzFile<'buf, 'parse> {
+    let mut best = FuzzFile::new(parsed);
+    let mut best_len = best.serialized_len();
+    loop {
+        let mut reduced = false;
+        for reduction in best.reductions() {
+            let mut candidate = best.clone();
+            candidate.reduce(reduction);
+            let candidate_len = candidate.serialized_len();
+            if candidate_len >= best_len {
+                continue
+            }
+            let mut serialized = Vec::with_capacity(candidate_len);
+            candidate.serialize(&mut serialized);
+            if interesting(&serialized) {
+                best = candidate;
+                best_len = candidate_len;
+                reduced = true;
+            }
+        }
+        if !reduced {
+            return best
+        }
+    }
+}
+
+pub fn fuzz_one<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, rng: &mut R, config: &FuzzConfig<'buf>) -> Option<FuzzFile<'buf, 'parse>> {
+    fuzz_one_traced(parsed, rng, config).map(|(ff, _)| ff)
+}
+
+// Like `fuzz_one`, but also returns the mutations that were applied.
+pub fn fuzz_one_traced<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, rng: &mut R, config: &FuzzConfig<'buf>) -> Option<(FuzzFile<'buf, 'parse>, Vec<Mutation>)> {
+    let mut buffers = FuzzBuffers::default();
+    fuzz_one_from(parsed, None, rng, config, &mut buffers).map(|ff| (ff, buffers.applied))
+}
+
+// Like `fuzz_one`, but `Mutation::Splice` can graft parts of `donor` into
+// the output.
+pub fn fuzz_one_with_donor<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, donor: &ParsedFile<'buf>, rng: &mut R, config: &FuzzConfig<'buf>) -> Option<FuzzFile<'buf, 'parse>> {
+    fuzz_one_from(parsed, Some(donor), rng, config, &mut FuzzBuffers::default())
+}
+
+// Scratch space for `fuzz_one_buffered`, kept by callers that fuzz in a
+// tight loop so it isn't allocated again for every output.
+#[derive(Default)]
+pub struct FuzzBuffers {
+    valid_actions: Vec<Mutation>,
+    weights: Vec<f64>,
+    // The mutations the last call applied, empty if it gave no output.
+    pub applied: Vec<Mutation>,
+}
+
+// Like `fuzz_one_traced`, but leaves the applied mutations in `buffers`.
+pub fn fuzz_one_buffered<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, rng: &mut R, config: &FuzzConfig<'buf>, buffers: &mut FuzzBuffers) -> Option<FuzzFile<'buf, 'parse>> {
+    fuzz_one_from(parsed, None, rng, config, buffers)
+}
+
+// How many times `max_mutations` attempts `fuzz_one` makes at most while
+// trying to apply `min_mutations`.
+const MUTATION_ATTEMPTS_FACTOR: usize = 4;
+
+// Without a donor `Mutation::Splice` is never picked.
+fn fuzz_one_from<'buf, 'parse, R: Rng>(parsed: &'parse ParsedFile<'buf>, donor: Option<&ParsedFile<'buf>>, mut rng: &mut R, config: &FuzzConfig<'buf>, buffers: &mut FuzzBuffers) -> Option<FuzzFile<'buf, 'parse>> {
+    let FuzzBuffers { ref mut valid_actions, ref mut weights, ref mut applied } = *buffers;
+    valid_actions.clear();
+    weights.clear();
+    applied.clear();
+
+    // Weights that don't line up with the mutations are ignored rather than
+    // panicking here, `FuzzConfig::validate` reports them.
+    let weighted = !config.weights.is_empty() && config.weights.len() == config.valid_actions.len();
+    // Likewise a config that allows no mutations gives no output.
+    if config.max_mutations == 0 {
+        return None
+    }
+    for (index, mutation) in config.valid_actions.iter().enumerate() {
+        if (!config.content_only || mutation.is_in_place()) && (donor.is_some() || *mutation != Mutation::Splice) {
+            valid_actions.push(mutation.clone());
+            weights.push(if weighted { config.weights[index] as f64 } else { 1.0 });
+        }
+    }
+
     let mut ff = FuzzFile::new(parsed);
-    let mut did_mutate = false;
-    for _ in 0..config.max_mutations {
-        did_mutate |= match rng.choose(&config.valid_actions[..]) {
-            Some(&Mutation::DuplicateRange) => ff.duplicate_range(&mut rng, config.max_duplications),
-            Some(&Mutation::DuplicateRootNode) => ff.duplicate_root_node(&mut rng),
-            Some(&Mutation::EmptyDelim) => ff.empty_delim(&mut rng),
-            Some(&Mutation::NestDelim) => ff.nest_delim(&mut rng),
-            Some(&Mutation::RandDelim) => ff.rand_delim(&mut rng, &config.all_delims[..]),
-            Some(&Mutation::RemoveDelim) => ff.remove_delim(&mut rng),
-            Some(&Mutation::ShuffleRanges) => ff.shuffle_range(&mut rng),
-            Some(&Mutation::SwapDelim) => ff.swap_delim(&mut rng),
-            Some(&Mutation::SwapRanges) => ff.swap_ranges(&mut rng),
-            None => false,
+    // Bounds the extra attempts, so a file that hardly any mutation applies
+    // to, like a single token, can't spin forever.
+    let max_attempts = cmp::max(config.max_mutations, config.min_mutations) * MUTATION_ATTEMPTS_FACTOR;
+    let min_mutations = cmp::max(config.min_mutations, 1);
+    for attempt in 0..max_attempts {
+        if attempt >= config.max_mutations && applied.len() >= min_mutations {
+            break
+        }
+        let mutation = if weighted {
+            match rand_index_weighted(rng, &weights[..]) {
+                Some(index) => &valid_actions[index],
+                None => continue,
+            }
+        } else {
+            match rng.choose(&valid_actions[..]) {
+                Some(mutation) => mutation,
+                None => continue,
+            }
+        };
+        if mutation.grows_tree() && config.max_total_nodes.map_or(false, |max_total_nodes| ff.total_nodes() >= max_total_nodes) {
+            continue
+        }
+        let bounds_depth = config.max_depth.is_some() && mutation.adds_depth();
+        let bounds_nodes = config.max_total_nodes.is_some() && mutation.grows_tree();
+        let snapshot = if bounds_depth || bounds_nodes { Some(ff.clone()) } else { None };
+        let did_mutate = match *mutation {
+            Mutation::BitFlip => ff.flip_bits(&mut rng, config.scaled(config.max_bit_flips)),
+            Mutation::CollapseDelim => ff.collapse_delim(&mut rng),
+            Mutation::DeleteToken => ff.delete_token(&mut rng),
+            Mutation::DictToken => ff.replace_token_with_dict(&mut rng, &config.dictionary),
+            Mutation::DuplicateRange => ff.duplicate_range(&mut rng, config.scaled(config.max_duplications), config.prefer_small_ranges),
+            Mutation::DuplicateRootNode => ff.duplicate_root_node(&mut rng),
+            Mutation::EmptyDelim => ff.empty_delim(&mut rng),
+            Mutation::InjectExtraClose => ff.inject_extra_close(&mut rng),
+            Mutation::InsertRandom => ff.insert_random_token(&mut rng, config.scaled(config.max_insert_len)),
+            Mutation::MutateNumber => ff.mutate_number(&mut rng),
+            Mutation::NestDelim => ff.nest_delim(&mut rng),
+            Mutation::PermuteBytes => ff.permute_bytes(&mut rng),
+            Mutation::RandDelim => ff.rand_delim(&mut rng, &config.all_delims[..]),
+            Mutation::RandomizeToken => ff.randomize_token(&mut rng),
+            Mutation::RemoveDelim => ff.remove_delim(&mut rng),
+            Mutation::RepeatDelim => ff.repeat_delim(&mut rng, config.scaled(config.max_nest_depth)),
+            Mutation::ResizePayload => ff.resize_payload(&mut rng),
+            Mutation::ReverseRange => ff.reverse_range(&mut rng),
+            Mutation::ShuffleRanges => ff.shuffle_range(&mut rng),
+            Mutation::Splice => ff.splice(&mut rng, donor.expect("splice needs a donor")),
+            Mutation::SubstringSplice => ff.substring_splice(&mut rng),
+            Mutation::SwapDelim => ff.swap_delim(&mut rng),
+            Mutation::SwapEndianness => ff.swap_endianness(&mut rng),
+            Mutation::SwapRanges => ff.swap_ranges(&mut rng),
+            Mutation::SwapTokens => ff.swap_tokens(&mut rng),
+            Mutation::TransposeDelimBytes => ff.transpose_delim_bytes(&mut rng),
+            Mutation::Truncate => ff.truncate_range(&mut rng),
+        };
+        if let Some(snapshot) = snapshot {
+            let too_deep = config.max_depth.map_or(false, |max_depth| bounds_depth && ff.depth() > max_depth);
+            let too_big = config.max_total_nodes.map_or(false, |max_total_nodes| bounds_nodes && ff.total_nodes() > max_total_nodes);
+            if did_mutate && (too_deep || too_big) {
+                ff = snapshot;
+                continue
+            }
+        }
+        if did_mutate {
+            applied.push(mutation.clone())
         }
     }
 
-    if did_mutate {
-        Some(ff)
+    if applied.len() < min_mutations {
+        applied.clear();
+        None
     } else {
+        Some(ff)
+    }
+}
+
+// Attempts per output before `Fuzzer` gives up on a file that none of the
+// mutations apply to.
+const MAX_ATTEMPTS: usize = 100;
+
+// Owns everything needed to fuzz one file, for callers that want a stream
+// of outputs rather than calling `fuzz_one` themselves.
+pub struct Fuzzer<'buf, R: Rng> {
+    parsed: ParsedFile<'buf>,
+    config: FuzzConfig<'buf>,
+    rng: R,
+}
+
+impl<'buf, R: Rng> Fuzzer<'buf, R> {
+    pub fn new(parsed: ParsedFile<'buf>, config: FuzzConfig<'buf>, rng: R) -> Self {
+        Fuzzer {
+            parsed: parsed,
+            config: config,
+            rng: rng,
+        }
+    }
+
+    pub fn parsed(self: &Self) -> &ParsedFile<'buf> {
+        &self.parsed
+    }
+}
+
+// Yields serialized outputs, ending only if no mutation applies to the file.
+impl<'buf, R: Rng> Iterator for Fuzzer<'buf, R> {
+    type Item = Vec<u8>;
+
+    fn next(self: &mut Self) -> Option<Vec<u8>> {
+        for _ in 0..MAX_ATTEMPTS {
+            if let Some(ff) = fuzz_one(&self.parsed, &mut self.rng, &self.config) {
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                return Some(serialized)
+            }
+        }
         None
     }
 }
+
+#[test]
+fn test_mutation_display() {
+    for mutation in Mutation::all() {
+        assert!(Mutation::from_str(&mutation.to_string()).unwrap() == mutation);
+        assert!(mutation.to_string().parse::<Mutation>().unwrap() == mutation);
+    }
+    assert!(Mutation::NestDelim.to_string() == "nest-delim");
+    for name in &["nest-delim", "NestDelim", "nest_delim", "NESTDELIM"] {
+        assert!(Mutation::from_str(name).unwrap() == Mutation::NestDelim);
+    }
+    assert!(Mutation::from_str("nest").is_err());
+    for name in &["jumble-token", "JumbleToken"] {
+        assert!(Mutation::from_str(name).unwrap() == Mutation::PermuteBytes);
+    }
+}
+
+#[test]
+fn test_validate() {
+    use grammar::{DelimOptions, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5");
+    let ff = FuzzFile::new(&parsed_file);
+    assert!(ff.validate().is_ok());
+
+    // A buggy mutation that drops a range but leaves delims pointing at it.
+    let mut buggy = ff.clone();
+    buggy.ranges.to_mut().pop();
+    assert!(buggy.validate().is_err());
+
+    let mut buggy = ff.clone();
+    let num_nodes = buggy.nodes.len();
+    buggy.root.to_mut().push(num_nodes);
+    assert!(buggy.validate().is_err());
+}
+
+#[test]
+fn test_validate_touched() {
+    use grammar::{DelimOptions, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    let mut parsed_file = slurp(&grammar, b"1<<2>>3(4 5)");
+    assert!(parsed_file.ranges.len() == 2);
+    // Range 0 is broken, but only range 1 is touched, so only it is checked.
+    let dangling = parsed_file.nodes.len();
+    parsed_file.ranges[0].push(dangling);
+    let ff = FuzzFile::new(&parsed_file);
+    assert!(ff.touched().is_empty() && ff.validate_touched().is_ok());
+
+    let variants: Vec<FuzzFile> = ff.apply_all_positions(&Mutation::ReverseRange).unwrap().collect();
+    assert!(variants[1].touched() == [Touched::Range(Some(1))]);
+    assert!(variants[1].validate().is_err());
+    assert!(variants[1].validate_touched().is_ok());
+    assert!(variants[0].touched() == [Touched::Range(Some(0))]);
+    assert!(variants[0].validate_touched().is_err());
+}
+
+#[test]
+fn test_mutations_report_touched() {
+    use grammar::{DelimOptions, Endian, Grammar, GrammarDef};
+    use parse::slurp;
+    use self::rand::{SeedableRng, isaac};
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<[".to_vec(), b"]>".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b" ".to_vec()),
+    ], vec![]);
+    let binary_grammar = Grammar::new(vec![
+        GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+    ], vec![]);
+    let parsed_files = vec![
+        slurp(&grammar, b"1 <[22 (333 4444)]> 0x10 (ab <[c]>)"),
+        slurp(&binary_grammar, b"\x03abc\x02de"),
+    ];
+    let donor = slurp(&grammar, b"5 <[6 (7)]>");
+    let mut config = FuzzConfig::new(grammar.delims());
+    config.max_mutations = 1;
+    config.dictionary = vec![b"dict".to_vec()];
+    let mut rng = isaac::Isaac64Rng::from_seed(&[1]);
+    // Anything that differs from the parsed file has to have been reported.
+    for parsed_file in &parsed_files {
+        for mutation in Mutation::all() {
+            config.valid_actions = vec![mutation.clone()];
+            for _ in 0..20 {
+                let ff = match fuzz_one_with_donor(parsed_file, &donor, &mut rng, &config) {
+                    Some(ff) => ff,
+                    None => continue,
+                };
+                assert!(!ff.touched().is_empty(), "{} touched nothing", mutation);
+                if ff.root[..] != parsed_file.root[..] {
+                    assert!(ff.touched().contains(&Touched::Range(None)), "{} changed the root", mutation);
+                }
+                for (rangeref, range) in ff.ranges.iter().enumerate() {
+                    if parsed_file.ranges.get(rangeref) != Some(range) {
+                        assert!(ff.touched().contains(&Touched::Range(Some(rangeref))), "{} changed range {}", mutation, rangeref);
+                    }
+                }
+                for (noderef, node) in ff.nodes.iter().enumerate() {
+                    let original = parsed_file.nodes.get(noderef).map(|node| format!("{:?}", node));
+                    if original != Some(format!("{:?}", node)) {
+                        assert!(ff.touched().contains(&Touched::Node(noderef)), "{} changed node {}", mutation, noderef);
+                    }
+                }
+                assert!(ff.validate_touched().is_ok());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_depth() {
+    use grammar::{DelimOptions, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    assert!(FuzzFile::new(&slurp(&grammar, b"")).depth() == 0);
+    assert!(FuzzFile::new(&slurp(&grammar, b"123")).depth() == 0);
+    assert!(FuzzFile::new(&slurp(&grammar, b"1<<2>>3")).depth() == 1);
+    assert!(FuzzFile::new(&slurp(&grammar, b"1<<2<<3>>4>>5<<6>>")).depth() == 2);
+}
+
+#[test]
+fn test_serialize_capped() {
+    use grammar::{DelimOptions, Endian, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    let parsed_file = slurp(&grammar, b"1<<2>>3");
+    let ff = FuzzFile::new(&parsed_file);
+    for &(max, truncated, expected) in &[(7, false, &b"1<<2>>3"[..]), (100, false, b"1<<2>>3"), (4, true, b"1<<2"), (0, true, b"")] {
+        let mut serialized = Vec::new();
+        assert!(ff.serialize_capped(&mut serialized, max) == truncated);
+        assert!(serialized == expected);
+    }
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+    ], vec![]);
+    let parsed_file = slurp(&grammar, b"\x03abc");
+    let ff = FuzzFile::new(&parsed_file);
+    let mut serialized = Vec::new();
+    assert!(!ff.serialize_capped(&mut serialized, 4));
+    assert!(serialized == b"\x03abc");
+    let mut serialized = Vec::new();
+    assert!(ff.serialize_capped(&mut serialized, 3));
+    assert!(serialized == b"\x03ab");
+
+    // Each range repeats the one below it twice, so the full output would
+    // be 2^60 bytes.
+    let mut nodes = vec![Node::Token(Cow::Borrowed(&b"x"[..]))];
+    let mut ranges = Vec::new();
+    for depth in 0..60 {
+        ranges.push(vec![depth, depth]);
+        nodes.push(Node::Range(depth));
+    }
+    let ff = FuzzFile {
+        root: Cow::from(vec![nodes.len() - 1]),
+        nodes: Cow::from(nodes),
+        ranges: Cow::from(ranges),
+        touched: Vec::new(),
+    };
+    let mut serialized = Vec::new();
+    assert!(ff.serialize_capped(&mut serialized, 1000));
+    assert!(serialized == vec![b'x'; 1000]);
+}
+
+#[test]
+fn test_rand_indices() {
+    use self::rand::{SeedableRng, isaac};
+
+    let mut rng = isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+    assert!(rand_indices(&mut rng, &[0u8; 0][..]).is_none());
+    assert!(rand_indices(&mut rng, &[0u8][..]).is_none());
+    for len in 2..6 {
+        let x = vec![0u8; len];
+        for _ in 0..100 {
+            let (index0, index1) = rand_indices(&mut rng, &x[..]).unwrap();
+            assert!(index0 != index1 && index0 < len && index1 < len);
+        }
+    }
+}
+
+#[test]
+fn test_slice_serializer_truncated() {
+    use grammar::{DelimOptions, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    let parsed_file = slurp(&grammar, b"1<<2>>3");
+    let ff = FuzzFile::new(&parsed_file);
+    for &(len, truncated, written) in &[(7, false, 7), (16, false, 7), (4, true, 4), (0, true, 0)] {
+        let mut out = vec![0; len];
+        let mut serializer = SliceSerializer::new(&mut out[..]);
+        ff.serialize(&mut serializer);
+        assert!(serializer.was_truncated() == truncated);
+        assert!(serializer.bytes_written() == written);
+    }
+}
+
+#[test]
+fn test_packed() {
+    let records: Vec<&[u8]> = vec![b"abc", b"", b"\x00\x01"];
+    let mut packed = Vec::new();
+    for record in &records {
+        write_packed(&mut packed, record).unwrap();
+    }
+    assert!(packed.len() == 3 * 4 + 5);
+    assert!(&packed[..7] == b"\x03\x00\x00\x00abc");
+    assert!(read_packed(&packed[..]).collect::<Vec<_>>() == records);
+
+    // A truncated trailing record is dropped.
+    assert!(read_packed(&packed[..packed.len() - 1]).collect::<Vec<_>>() == &records[..2]);
+    assert!(read_packed(&b""[..]).next().is_none());
+}
+
+#[test]
+fn test_fuzz_one_buffered() {
+    use self::rand::{SeedableRng, isaac};
+    use grammar::{DelimOptions, Grammar, GrammarDef};
+    use parse::slurp;
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![b" ".to_vec()]);
+    let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+    let config = FuzzConfig::new(grammar.delims());
+    let mut traced_rng = isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+    let mut buffered_rng = isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+    let mut buffers = FuzzBuffers::default();
+
+    let mut valid_actions = None;
+    for _ in 0..50 {
+        let traced = fuzz_one_traced(&parsed_file, &mut traced_rng, &config);
+        let buffered = fuzz_one_buffered(&parsed_file, &mut buffered_rng, &config, &mut buffers);
+        match (traced, buffered) {
+            (Some((traced, applied)), Some(buffered)) => {
+                let (mut expected, mut serialized) = (Vec::new(), Vec::new());
+                traced.serialize(&mut expected);
+                buffered.serialize(&mut serialized);
+                assert!(serialized == expected);
+                assert!(buffers.applied == applied);
+            },
+            (None, None) => assert!(buffers.applied.is_empty()),
+            _ => panic!("fuzz_one_buffered differs from fuzz_one_traced"),
+        }
+        // The candidate list is filled in place rather than reallocated.
+        let ptr = buffers.valid_actions.as_ptr();
+        assert!(*valid_actions.get_or_insert(ptr) == ptr);
+    }
+}
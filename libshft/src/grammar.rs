@@ -1,62 +1,378 @@
+extern crate aho_corasick;
+extern crate bincode;
 extern crate serde;
 extern crate serde_yaml;
 
+use self::aho_corasick::{AhoCorasick, MatchKind};
 use error::*;
-use std::fmt::Display;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{self, Display};
 use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::mem;
 use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Deserialize)]
+// A delim is either a bare `[start, end]` pair or a map with options.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DelimFormat {
+    Pair(String, String),
+    Full {
+        start: String,
+        end: String,
+        #[serde(default)]
+        non_nesting: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        escape: Option<String>,
+    },
+}
+
+// A tokenizer is either a bare pattern or a map with an escape.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TokenizerFormat {
+    Pattern(String),
+    Full {
+        pattern: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        escape: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct LengthPrefixedFormat {
+    width: usize,
+    endian: Endian,
+}
+
+#[derive(Serialize, Deserialize)]
 struct ConfigFormat {
-    delims: Vec<(String, String)>,
+    delims: Vec<DelimFormat>,
     breaks: Vec<String>,
     whitespace: Vec<String>,
+    #[serde(default)]
+    case_insensitive: bool,
+    #[serde(default)]
+    length_prefixed: Vec<LengthPrefixedFormat>,
+    #[serde(default)]
+    tokenizers: Vec<TokenizerFormat>,
+    // `[start, end]` pairs. Nothing inside a comment is parsed.
+    #[serde(default)]
+    comments: Vec<(String, String)>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+// Bump when the cached form of a grammar changes.
+const CACHE_VERSION: u32 = 4;
+
+// A grammar as stored in a cache file, keyed by a hash of its config. The
+// prefilter isn't stored, aho-corasick can't serialize an automaton, so
+// `Grammar::new` rebuilds it on load.
+#[derive(Serialize, Deserialize)]
+struct CachedGrammar {
+    version: u32,
+    config_hash: u64,
+    defs: Vec<GrammarDef>,
+    whitespace: Vec<Vec<u8>>,
+    case_insensitive: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DelimOptions {
+    // While open, further starts of this delim are literal content.
+    pub non_nesting: bool,
+    // While this delim is the innermost open one, its end pattern is
+    // literal content when preceded by an odd number of these.
+    pub escape: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+// The length field in front of a length-prefixed section.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LengthField {
+    pub width: usize,
+    pub endian: Endian,
+}
+
+impl LengthField {
+    pub fn max_len(self: &Self) -> usize {
+        // Fields as wide as `usize` can hold any length, and shifting by
+        // the full width would overflow.
+        if self.width >= mem::size_of::<usize>() {
+            usize::max_value()
+        } else {
+            (1 << (8 * self.width)) - 1
+        }
+    }
+
+    pub fn read(self: &Self, buf: &[u8]) -> Option<usize> {
+        if self.width == 0 || self.width > 8 || buf.len() < self.width {
+            return None
+        }
+        let field = &buf[..self.width];
+        let len = match self.endian {
+            Endian::Big => field.iter().fold(0u64, |len, b| (len << 8) | *b as u64),
+            Endian::Little => field.iter().rev().fold(0u64, |len, b| (len << 8) | *b as u64),
+        };
+        Some(len as usize)
+    }
+
+    // Lengths that don't fit in the field are truncated to its low bytes.
+    pub fn encode(self: &Self, len: usize) -> Vec<u8> {
+        let mut field: Vec<u8> = (0..self.width).map(|i| if i < 8 { (len as u64 >> (8 * i)) as u8 } else { 0 }).collect();
+        if self.endian == Endian::Big {
+            field.reverse()
+        }
+        field
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum GrammarDef {
     Breaker(Vec<u8>),
-    Delim(Vec<u8>, Vec<u8>),
+    // Everything from the start pattern up to and including the end
+    // pattern, or to the end of the input, is kept as a single token.
+    Comment(Vec<u8>, Vec<u8>),
+    Delim(Vec<u8>, Vec<u8>, DelimOptions),
+    // Only matches at the start of a token: a `width`-byte length followed
+    // by exactly that many bytes of payload.
+    LengthPrefixed { width: usize, endian: Endian },
+    // Splits the pattern out as a standalone token, wherever it occurs
+    // except right after an odd number of the escape, if there is one.
+    Tokenizer(Vec<u8>, Option<Vec<u8>>),
+}
+
+impl GrammarDef {
+    // The longest match wins at any offset. Between matches of the same
+    // length, a comment wins over a delimiter, then a tokenizer, then a
+    // length-prefixed section, then a breaker. Defs of the same rank keep
+    // their config order.
+    fn rank(self: &Self) -> usize {
+        match *self {
+            GrammarDef::Comment(..) => 0,
+            GrammarDef::Delim(..) => 1,
+            GrammarDef::Tokenizer(..) => 2,
+            GrammarDef::LengthPrefixed { .. } => 3,
+            GrammarDef::Breaker(..) => 4,
+        }
+    }
+
+    // The pattern that has to match for this def to fire at all.
+    fn leading_pattern(self: &Self) -> Option<&[u8]> {
+        match *self {
+            GrammarDef::Breaker(ref pattern) | GrammarDef::Tokenizer(ref pattern, _) => Some(pattern),
+            GrammarDef::Comment(ref start_pattern, _) | GrammarDef::Delim(ref start_pattern, _, _) => Some(start_pattern),
+            GrammarDef::LengthPrefixed { .. } => None,
+        }
+    }
+
+    fn patterns(self: &Self) -> Vec<&[u8]> {
+        match *self {
+            GrammarDef::Breaker(ref pattern) | GrammarDef::Tokenizer(ref pattern, _) => vec![pattern],
+            GrammarDef::Comment(ref start_pattern, ref end_pattern) | GrammarDef::Delim(ref start_pattern, ref end_pattern, _) => vec![start_pattern, end_pattern],
+            GrammarDef::LengthPrefixed { .. } => vec![],
+        }
+    }
+}
+
+pub fn starts_with(buf: &[u8], pattern: &[u8], ignore_case: bool) -> bool {
+    if ignore_case {
+        buf.len() >= pattern.len() && buf[..pattern.len()].eq_ignore_ascii_case(pattern)
+    } else {
+        buf.starts_with(pattern)
+    }
+}
+
+pub fn pattern_eq(a: &[u8], b: &[u8], ignore_case: bool) -> bool {
+    a.len() == b.len() && starts_with(a, b, ignore_case)
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Delim<'buf> {
-    pub start_pattern: &'buf [u8],
-    pub end_pattern: &'buf [u8],
+    pub start_pattern: Cow<'buf, [u8]>,
+    pub end_pattern: Cow<'buf, [u8]>,
+    pub ignore_case: bool,
 }
 
 impl<'buf> Delim<'buf> {
-    pub fn new(start_pattern: &'buf [u8], end_pattern: &'buf [u8]) -> Self {
+    pub fn new<S, E>(start_pattern: S, end_pattern: E) -> Self
+        where S: Into<Cow<'buf, [u8]>>, E: Into<Cow<'buf, [u8]>> {
+        Delim {
+            start_pattern: start_pattern.into(),
+            end_pattern: end_pattern.into(),
+            ignore_case: false,
+        }
+    }
+
+    pub fn matches_start(self: &Self, buf: &[u8]) -> bool {
+        starts_with(buf, &self.start_pattern, self.ignore_case)
+    }
+
+    pub fn matches_end(self: &Self, buf: &[u8]) -> bool {
+        starts_with(buf, &self.end_pattern, self.ignore_case)
+    }
+
+    pub fn into_owned(self: Self) -> Delim<'static> {
         Delim {
-            start_pattern: start_pattern,
-            end_pattern: end_pattern,
+            start_pattern: Cow::Owned(self.start_pattern.into_owned()),
+            end_pattern: Cow::Owned(self.end_pattern.into_owned()),
+            ignore_case: self.ignore_case,
         }
     }
 }
 
-#[derive(Debug)]
+// Claims a custom-length token at the start of the buffer, if any. Fuzz jobs
+// share the grammar across threads.
+pub type Lexer = Box<Fn(&[u8]) -> Option<usize> + Send + Sync>;
+
 pub struct Grammar {
     pub defs: Vec<GrammarDef>,
     pub whitespace: Vec<Vec<u8>>,
+    pub case_insensitive: bool,
+    pub lexer: Option<Lexer>,
+    // Finds the offsets where any pattern could start, so that `scan_next`
+    // can skip the rest. Built by `new`, `None` means every offset is tried.
+    pub prefilter: Option<AhoCorasick>,
+}
+
+impl FromStr for Grammar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Grammar> {
+        Grammar::from_reader(s.as_bytes())
+    }
+}
+
+// A lexer can't be compared, only whether there is one. The prefilter is
+// built from the patterns, so is left out.
+impl PartialEq for Grammar {
+    fn eq(self: &Self, other: &Self) -> bool {
+        self.defs == other.defs &&
+            self.whitespace == other.whitespace &&
+            self.case_insensitive == other.case_insensitive &&
+            self.lexer.is_some() == other.lexer.is_some()
+    }
+}
+
+impl fmt::Debug for Grammar {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Grammar")
+            .field("defs", &self.defs)
+            .field("whitespace", &self.whitespace)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("lexer", &self.lexer.is_some())
+            .field("prefilter", &self.prefilter.is_some())
+            .finish()
+    }
+}
+
+// The automaton matches case-insensitively whatever the grammar says, as
+// it only has to find a superset of the offsets where a pattern matches.
+fn build_prefilter(defs: &[GrammarDef], whitespace: &[Vec<u8>]) -> Option<AhoCorasick> {
+    let mut patterns: Vec<&[u8]> = whitespace.iter().map(|pattern| &pattern[..]).collect();
+    for def in defs {
+        patterns.extend(def.patterns())
+    }
+    if patterns.iter().any(|pattern| pattern.is_empty()) {
+        return None
+    }
+    AhoCorasick::builder()
+        .ascii_case_insensitive(true)
+        .match_kind(MatchKind::LeftmostFirst)
+        .build(patterns)
+        .ok()
 }
 
 impl Grammar {
-    pub fn new(defs: Vec<GrammarDef>, whitespace: Vec<Vec<u8>>) -> Self {
+    pub fn new(mut defs: Vec<GrammarDef>, whitespace: Vec<Vec<u8>>) -> Self {
+        defs.sort_by_key(GrammarDef::rank);
+        let prefilter = build_prefilter(&defs, &whitespace);
         Grammar {
             defs: defs,
             whitespace: whitespace,
+            case_insensitive: false,
+            lexer: None,
+            prefilter: prefilter,
         }
     }
 
     pub fn from_path<P: AsRef<Path> + Display>(path: P) -> Result<Grammar> {
         let f = File::open(&path).chain_err(|| format!("Failed to open grammar definition {}", path))?;
-        let cfg = serde_yaml::from_reader::<_, ConfigFormat>(f).chain_err(|| "Failed to parse grammar defintion")?;
+        Grammar::from_reader(f).chain_err(|| format!("Failed to load grammar definition {}", path))
+    }
+
+    pub fn from_reader<R: Read>(r: R) -> Result<Grammar> {
+        let cfg = serde_yaml::from_reader::<_, ConfigFormat>(r).chain_err(|| "Failed to parse grammar defintion")?;
+        let grammar = Grammar::from_config(cfg);
+        grammar.validate()?;
+        Ok(grammar)
+    }
+
+    // Like `from_path`, but reuses the compiled grammar in `cache_path` when
+    // it was built from identical config contents, and refreshes it when not.
+    pub fn from_path_cached<P: AsRef<Path> + Display, C: AsRef<Path>>(path: P, cache_path: C) -> Result<Grammar> {
+        Grammar::load_cached(path, cache_path).map(|(grammar, _)| grammar)
+    }
+
+    fn load_cached<P: AsRef<Path> + Display, C: AsRef<Path>>(path: P, cache_path: C) -> Result<(Grammar, bool)> {
+        let mut contents = Vec::new();
+        File::open(&path).and_then(|mut f| f.read_to_end(&mut contents)).chain_err(|| format!("Failed to open grammar definition {}", path))?;
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let config_hash = hasher.finish();
+
+        let cached = File::open(&cache_path).ok().and_then(|f| bincode::deserialize_from::<_, CachedGrammar>(f).ok());
+        if let Some(cached) = cached {
+            if cached.version == CACHE_VERSION && cached.config_hash == config_hash {
+                let mut grammar = Grammar::new(cached.defs, cached.whitespace);
+                grammar.case_insensitive = cached.case_insensitive;
+                return Ok((grammar, true))
+            }
+        }
+
+        let cfg = serde_yaml::from_slice::<ConfigFormat>(&contents).chain_err(|| "Failed to parse grammar defintion")?;
+        let grammar = Grammar::from_config(cfg);
+        grammar.validate()?;
+        let cached = CachedGrammar {
+            version: CACHE_VERSION,
+            config_hash: config_hash,
+            defs: grammar.defs.clone(),
+            whitespace: grammar.whitespace.clone(),
+            case_insensitive: grammar.case_insensitive,
+        };
+        // The cache is only an optimisation, so an unwritable cache path
+        // shouldn't stop the grammar loading.
+        if let Ok(f) = File::create(&cache_path) {
+            let _ = bincode::serialize_into(f, &cached);
+        }
+        Ok((grammar, false))
+    }
 
+    fn from_config(cfg: ConfigFormat) -> Grammar {
         let mut defs = Vec::new();
         let mut whitespace = Vec::new();
 
-        for (start_pattern, end_pattern) in cfg.delims {
-            defs.push(GrammarDef::Delim(start_pattern.into_bytes(), end_pattern.into_bytes()));
+        for delim in cfg.delims {
+            defs.push(match delim {
+                DelimFormat::Pair(start_pattern, end_pattern) => {
+                    GrammarDef::Delim(start_pattern.into_bytes(), end_pattern.into_bytes(), DelimOptions::default())
+                },
+                DelimFormat::Full { start, end, non_nesting, escape } => {
+                    let options = DelimOptions {
+                        non_nesting: non_nesting,
+                        escape: escape.map(|escape| escape.into_bytes()),
+                    };
+                    GrammarDef::Delim(start.into_bytes(), end.into_bytes(), options)
+                },
+            })
         }
 
         for pattern in cfg.whitespace {
@@ -67,15 +383,428 @@ impl Grammar {
             defs.push(GrammarDef::Breaker(pattern.into_bytes()))
         }
 
-        Ok(Grammar::new(defs, whitespace))
+        for tokenizer in cfg.tokenizers {
+            defs.push(match tokenizer {
+                TokenizerFormat::Pattern(pattern) => GrammarDef::Tokenizer(pattern.into_bytes(), None),
+                TokenizerFormat::Full { pattern, escape } => GrammarDef::Tokenizer(pattern.into_bytes(), escape.map(|escape| escape.into_bytes())),
+            })
+        }
+
+        for section in cfg.length_prefixed {
+            defs.push(GrammarDef::LengthPrefixed { width: section.width, endian: section.endian })
+        }
+
+        for (start_pattern, end_pattern) in cfg.comments {
+            defs.push(GrammarDef::Comment(start_pattern.into_bytes(), end_pattern.into_bytes()))
+        }
+
+        let mut grammar = Grammar::new(defs, whitespace);
+        grammar.case_insensitive = cfg.case_insensitive;
+        grammar
+    }
+
+    // The inverse of `from_config`. Defs of each kind keep their order, so
+    // loading the YAML gives back an equal grammar.
+    fn to_config(self: &Self) -> Result<ConfigFormat> {
+        let string = |pattern: &[u8]| String::from_utf8(pattern.to_vec()).chain_err(|| format!("Pattern {:?} is not valid UTF-8", String::from_utf8_lossy(pattern)));
+        if self.lexer.is_some() {
+            bail!("A grammar with a lexer has no config form");
+        }
+
+        let mut cfg = ConfigFormat {
+            delims: Vec::new(),
+            breaks: Vec::new(),
+            whitespace: self.whitespace.iter().map(|pattern| string(pattern)).collect::<Result<_>>()?,
+            case_insensitive: self.case_insensitive,
+            length_prefixed: Vec::new(),
+            tokenizers: Vec::new(),
+            comments: Vec::new(),
+        };
+        for def in &self.defs {
+            match *def {
+                GrammarDef::Delim(ref start_pattern, ref end_pattern, ref options) => {
+                    let (start, end) = (string(start_pattern)?, string(end_pattern)?);
+                    cfg.delims.push(if *options == DelimOptions::default() {
+                        DelimFormat::Pair(start, end)
+                    } else {
+                        DelimFormat::Full {
+                            start: start,
+                            end: end,
+                            non_nesting: options.non_nesting,
+                            escape: match options.escape {
+                                Some(ref escape) => Some(string(escape)?),
+                                None => None,
+                            },
+                        }
+                    })
+                },
+                GrammarDef::Breaker(ref pattern) => cfg.breaks.push(string(pattern)?),
+                GrammarDef::Comment(ref start_pattern, ref end_pattern) => cfg.comments.push((string(start_pattern)?, string(end_pattern)?)),
+                GrammarDef::Tokenizer(ref pattern, ref escape) => {
+                    cfg.tokenizers.push(match *escape {
+                        Some(ref escape) => TokenizerFormat::Full { pattern: string(pattern)?, escape: Some(string(escape)?) },
+                        None => TokenizerFormat::Pattern(string(pattern)?),
+                    })
+                },
+                GrammarDef::LengthPrefixed { width, endian } => {
+                    cfg.length_prefixed.push(LengthPrefixedFormat { width: width, endian: endian })
+                },
+            }
+        }
+        Ok(cfg)
+    }
+
+    pub fn to_yaml(self: &Self) -> Result<String> {
+        let cfg = self.to_config()?;
+        serde_yaml::to_string(&cfg).chain_err(|| "Failed to write grammar definition")
+    }
+
+    // Rejects definitions the parser can't make progress with. Duplicate
+    // defs are legal, `duplicate_delims` and `unreachable_defs` report them.
+    pub fn validate(self: &Self) -> Result<()> {
+        let show = |pattern: &[u8]| format!("{:?}", String::from_utf8_lossy(pattern));
+        for pattern in &self.whitespace {
+            if pattern.is_empty() {
+                bail!("Empty whitespace pattern");
+            }
+        }
+        for def in &self.defs {
+            match *def {
+                GrammarDef::Breaker(ref pattern) => {
+                    if pattern.is_empty() {
+                        bail!("Empty break pattern");
+                    }
+                },
+                GrammarDef::Comment(ref start_pattern, ref end_pattern) => {
+                    if start_pattern.is_empty() || end_pattern.is_empty() {
+                        bail!("Comment ({}, {}) has an empty pattern", show(start_pattern), show(end_pattern));
+                    }
+                },
+                GrammarDef::Delim(ref start_pattern, ref end_pattern, ref options) => {
+                    if start_pattern.is_empty() || end_pattern.is_empty() {
+                        bail!("Delim ({}, {}) has an empty pattern", show(start_pattern), show(end_pattern));
+                    }
+                    // Otherwise every end would open a new delim instead.
+                    if pattern_eq(start_pattern, end_pattern, self.case_insensitive) && !options.non_nesting {
+                        bail!("Delim ({}, {}) has the same start and end, so must be non_nesting", show(start_pattern), show(end_pattern));
+                    }
+                    if options.escape.as_ref().map_or(false, |escape| escape.is_empty()) {
+                        bail!("Delim ({}, {}) has an empty escape", show(start_pattern), show(end_pattern));
+                    }
+                },
+                GrammarDef::LengthPrefixed { width, .. } => {
+                    if width == 0 || width > 8 {
+                        bail!("Length-prefixed width {} is not between 1 and 8", width);
+                    }
+                },
+                GrammarDef::Tokenizer(ref pattern, ref escape) => {
+                    if pattern.is_empty() {
+                        bail!("Empty tokenizer pattern");
+                    }
+                    if escape.as_ref().map_or(false, |escape| escape.is_empty()) {
+                        bail!("Tokenizer {} has an empty escape", show(pattern));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Whitespace is tried before any def, so a whitespace prefix shadows a
+    // def. Between defs the longest match wins, so only an identical
+    // earlier pattern does.
+    pub fn unreachable_defs(self: &Self) -> Vec<usize> {
+        let mut earlier: Vec<&[u8]> = Vec::new();
+        let mut unreachable = Vec::new();
+        for (index, def) in self.defs.iter().enumerate() {
+            if let Some(pattern) = def.leading_pattern() {
+                let shadowed_by_whitespace = self.whitespace.iter().any(|prefix| starts_with(pattern, prefix, self.case_insensitive));
+                let shadowed_by_def = earlier.iter().any(|other| pattern_eq(pattern, other, self.case_insensitive));
+                if shadowed_by_whitespace || shadowed_by_def {
+                    unreachable.push(index)
+                }
+            }
+            match *def {
+                // A comment's end pattern only matches inside the comment.
+                GrammarDef::Comment(ref start_pattern, _) => earlier.push(start_pattern),
+                _ => earlier.extend(def.patterns()),
+            }
+        }
+        unreachable
+    }
+
+    // Pairs of (duplicate, original) indices for delims whose start and end
+    // both match an earlier delim's.
+    pub fn duplicate_delims(self: &Self) -> Vec<(usize, usize)> {
+        let mut duplicates = Vec::new();
+        for (index, def) in self.defs.iter().enumerate() {
+            if let GrammarDef::Delim(ref start_pattern, ref end_pattern, _) = *def {
+                let original = self.defs[..index].iter().position(|other| match *other {
+                    GrammarDef::Delim(ref other_start, ref other_end, _) => {
+                        pattern_eq(start_pattern, other_start, self.case_insensitive) && pattern_eq(end_pattern, other_end, self.case_insensitive)
+                    },
+                    _ => false,
+                });
+                if let Some(original) = original {
+                    duplicates.push((index, original))
+                }
+            }
+        }
+        duplicates
+    }
+
+    // True if any pattern contains a byte that isn't printable ASCII or
+    // common whitespace, or if the grammar has length-prefixed sections.
+    pub fn is_binary(self: &Self) -> bool {
+        let is_text = |b: &u8| (*b > 0x1f && *b < 0x7f) || *b == b'\t' || *b == b'\n' || *b == b'\r';
+        let binary_def = self.defs.iter().any(|def| match *def {
+            GrammarDef::LengthPrefixed { .. } => true,
+            _ => def.patterns().iter().any(|pattern| !pattern.iter().all(&is_text)),
+        });
+        binary_def || self.whitespace.iter().any(|pattern| !pattern.iter().all(&is_text))
     }
 
     pub fn delims<'g>(self: &'g Self) -> Vec<Delim<'g>> {
         self.defs.iter().filter_map(|def| {
             match def {
-                &GrammarDef::Delim(ref start_pattern, ref end_pattern) => Some(Delim::new(&start_pattern[..], &end_pattern[..])),
+                &GrammarDef::Delim(ref start_pattern, ref end_pattern, _) => Some(Delim::new(&start_pattern[..], &end_pattern[..])),
                 _ => None,
             }
         }).collect()
     }
 }
+
+#[test]
+fn test_is_binary() {
+    let grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b",".to_vec()),
+    ], vec![b" ".to_vec(), b"\r\n".to_vec()]);
+    assert!(!grammar.is_binary());
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Breaker(b"\x00".to_vec()),
+    ], vec![]);
+    assert!(grammar.is_binary());
+
+    let grammar = Grammar::new(vec![], vec![b"\xff".to_vec()]);
+    assert!(grammar.is_binary());
+}
+
+#[test]
+fn test_delim_matches_start() {
+    let delim = Delim::new(b"<<", b">>");
+    assert!(delim.matches_start(b"<<"));
+    assert!(delim.matches_start(b"<<1>>"));
+    assert!(!delim.matches_start(b"<"));
+    assert!(!delim.matches_start(b"1<<"));
+    assert!(!delim.matches_start(b">>"));
+}
+
+#[test]
+fn test_delim_matches_end() {
+    let delim = Delim::new(b"<<", b">>");
+    assert!(delim.matches_end(b">>"));
+    assert!(delim.matches_end(b">>1"));
+    assert!(!delim.matches_end(b">"));
+    assert!(!delim.matches_end(b"1>>"));
+    assert!(!delim.matches_end(b"<<"));
+}
+
+#[test]
+fn test_delim_matches_ignore_case() {
+    let mut delim = Delim::new(b"<div>", b"</div>");
+    assert!(!delim.matches_start(b"<DIV>"));
+    delim.ignore_case = true;
+    assert!(delim.matches_start(b"<DIV>"));
+    assert!(delim.matches_start(b"<Div>1"));
+    assert!(delim.matches_end(b"</dIV>"));
+    assert!(!delim.matches_end(b"</DIV"));
+}
+
+#[test]
+fn test_unreachable_defs() {
+    // A longer pattern still matches, so a shared prefix doesn't shadow it.
+    let grammar = Grammar::new(vec![
+        GrammarDef::Breaker(b"<!".to_vec()),
+        GrammarDef::Breaker(b"!".to_vec()),
+        GrammarDef::Delim(b"<".to_vec(), b">".to_vec(), DelimOptions::default()),
+        GrammarDef::Tokenizer(b">".to_vec(), None),
+    ], vec![b" ".to_vec()]);
+    assert!(grammar.unreachable_defs() == vec![1]);
+    assert!(grammar.defs[1] == GrammarDef::Tokenizer(b">".to_vec(), None));
+
+    let grammar = Grammar::new(vec![
+        GrammarDef::Breaker(b"<".to_vec()),
+        GrammarDef::Breaker(b" x".to_vec()),
+        GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+    ], vec![b" ".to_vec()]);
+    assert!(grammar.unreachable_defs() == vec![2]);
+
+    let mut grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<A".to_vec(), b">".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b"<a".to_vec()),
+    ], vec![]);
+    assert!(grammar.unreachable_defs().is_empty());
+    grammar.case_insensitive = true;
+    assert!(grammar.unreachable_defs() == vec![1]);
+}
+
+#[test]
+fn test_delim_format() {
+    let cfg: ConfigFormat = serde_yaml::from_str("
+delims:
+  - ['(', ')']
+  - { start: '//', end: \"\\n\", non_nesting: true }
+  - { start: '\"', end: '\"', non_nesting: true, escape: '\\' }
+breaks: []
+whitespace: []
+").unwrap();
+    match cfg.delims[0] {
+        DelimFormat::Pair(ref start, ref end) => assert!(start == "(" && end == ")"),
+        _ => panic!("expected pair"),
+    }
+    match cfg.delims[1] {
+        DelimFormat::Full { ref start, ref end, non_nesting, ref escape } => assert!(start == "//" && end == "\n" && non_nesting && escape.is_none()),
+        _ => panic!("expected full delim"),
+    }
+    match cfg.delims[2] {
+        DelimFormat::Full { ref escape, .. } => assert!(escape.as_ref().map(|escape| &escape[..]) == Some("\\")),
+        _ => panic!("expected full delim"),
+    }
+}
+
+#[test]
+fn test_validate() {
+    let load = |defs: &str| Grammar::from_str(&format!("breaks: []\nwhitespace: [' ']\n{}", defs));
+    assert!(load("delims: [['(', ')']]\n").is_ok());
+    assert!(load("delims: [['', ')']]\n").is_err());
+    assert!(load("delims: [['(', '']]\n").is_err());
+    assert!(load("delims: [['\"', '\"']]\n").is_err());
+    assert!(load("delims: [{ start: '\"', end: '\"', non_nesting: true }]\n").is_ok());
+    assert!(load("delims: [{ start: '\"', end: '\"', non_nesting: true, escape: '' }]\n").is_err());
+    assert!(load("delims: []\ntokenizers: ['']\n").is_err());
+    assert!(load("delims: []\ntokenizers: [{ pattern: ',', escape: '' }]\n").is_err());
+    assert!(load("delims: []\ntokenizers: [{ pattern: ',', escape: '\\' }]\n").is_ok());
+    assert!(load("delims: []\nlength_prefixed: [{ width: 0, endian: big }]\n").is_err());
+    assert!(load("delims: []\nlength_prefixed: [{ width: 9, endian: big }]\n").is_err());
+    assert!(Grammar::from_str("delims: []\nbreaks: ['']\nwhitespace: []\n").is_err());
+    assert!(Grammar::from_str("delims: []\nbreaks: []\nwhitespace: ['']\n").is_err());
+
+    // Duplicates load, and are reported instead.
+    let grammar = load("delims: [['(', ')'], ['(', ')']]\n").unwrap();
+    assert!(grammar.unreachable_defs() == vec![1]);
+    assert!(grammar.duplicate_delims() == vec![(1, 0)]);
+}
+
+#[test]
+fn test_duplicate_delims() {
+    let mut grammar = Grammar::new(vec![
+        GrammarDef::Delim(b"<a".to_vec(), b">".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b",".to_vec()),
+        GrammarDef::Delim(b"<a".to_vec(), b"/>".to_vec(), DelimOptions::default()),
+        GrammarDef::Delim(b"<A".to_vec(), b">".to_vec(), DelimOptions::default()),
+    ], vec![]);
+    assert!(grammar.duplicate_delims().is_empty());
+    grammar.case_insensitive = true;
+    let duplicates = grammar.duplicate_delims();
+    assert!(duplicates.len() == 1);
+    let (index, original) = duplicates[0];
+    assert!(grammar.defs[index] == GrammarDef::Delim(b"<A".to_vec(), b">".to_vec(), DelimOptions::default()));
+    assert!(grammar.defs[original] == GrammarDef::Delim(b"<a".to_vec(), b">".to_vec(), DelimOptions::default()));
+}
+
+#[test]
+fn test_from_str() {
+    let grammar = Grammar::from_str("
+delims:
+  - ['(', ')']
+breaks: [',']
+whitespace: [' ']
+").unwrap();
+    assert!(grammar.defs == vec![
+        GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        GrammarDef::Breaker(b",".to_vec()),
+    ]);
+    assert!(grammar.whitespace == vec![b" ".to_vec()]);
+
+    let err = Grammar::from_str("delims: [").unwrap_err();
+    assert!(err.iter().any(|e| e.to_string().contains("Failed to parse")));
+    assert!(Grammar::from_str("breaks: []").is_err());
+}
+
+#[test]
+fn test_to_yaml() {
+    let grammar = Grammar::from_str("
+delims:
+  - ['(', ')']
+  - { start: '//', end: \"\\n\", non_nesting: true }
+  - { start: '\"', end: '\"', non_nesting: true, escape: '\\' }
+breaks: [',', ';']
+whitespace: [' ', \"\\r\\n\"]
+case_insensitive: true
+length_prefixed: [{ width: 2, endian: little }]
+tokenizers: ['=', { pattern: ':', escape: '\\' }]
+comments: [['#', \"\\n\"], ['/*', '*/']]
+").unwrap();
+    let yaml = grammar.to_yaml().unwrap();
+    let reloaded = Grammar::from_str(&yaml).unwrap();
+    assert!(reloaded == grammar);
+    assert!(reloaded.to_yaml().unwrap() == yaml);
+
+    let grammar = Grammar::new(vec![GrammarDef::Breaker(vec![0xff])], vec![]);
+    assert!(grammar.to_yaml().is_err());
+}
+
+#[test]
+fn test_grammar_cache() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("shft-test-grammar-cache-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.yml");
+    let cache_path = dir.join("config.cache");
+    let config_name = config_path.display().to_string();
+
+    fs::write(&config_path, "delims: [['<<', '>>'], { start: '//', end: \"\\n\", non_nesting: true }]\nbreaks: ['/']\nwhitespace: [' ']\ncase_insensitive: true\n").unwrap();
+    let (parsed, hit) = Grammar::load_cached(&config_name, &cache_path).unwrap();
+    assert!(!hit);
+    let (cached, hit) = Grammar::load_cached(&config_name, &cache_path).unwrap();
+    assert!(hit);
+    assert!(cached.defs == parsed.defs && cached.whitespace == parsed.whitespace);
+    assert!(cached.case_insensitive && parsed.case_insensitive);
+    assert!(format!("{:?}", cached) == format!("{:?}", Grammar::from_path(&config_name).unwrap()));
+
+    fs::write(&config_path, "delims: []\nbreaks: ['/']\nwhitespace: []\n").unwrap();
+    let (changed, hit) = Grammar::load_cached(&config_name, &cache_path).unwrap();
+    assert!(!hit);
+    assert!(changed.defs == vec![GrammarDef::Breaker(b"/".to_vec())]);
+
+    let unwritable_path = dir.join("missing").join("config.cache");
+    let (loaded, hit) = Grammar::load_cached(&config_name, &unwritable_path).unwrap();
+    assert!(!hit);
+    assert!(loaded.defs == changed.defs);
+    assert!(!unwritable_path.exists());
+}
+
+#[test]
+fn test_length_field() {
+    let field = LengthField { width: 2, endian: Endian::Big };
+    assert!(field.read(b"\x01\x02rest") == Some(0x0102));
+    assert!(field.read(b"\x01") == None);
+    assert!(field.encode(0x0102) == b"\x01\x02");
+    assert!(field.max_len() == 0xffff);
+
+    let field = LengthField { width: 2, endian: Endian::Little };
+    assert!(field.read(b"\x01\x02") == Some(0x0201));
+    assert!(field.encode(0x0201) == b"\x01\x02");
+    assert!(field.encode(0x10203) == b"\x03\x02");
+
+    let width = mem::size_of::<usize>();
+    assert!(LengthField { width: width - 1, endian: Endian::Big }.max_len() == usize::max_value() >> 8);
+    assert!(LengthField { width: width, endian: Endian::Big }.max_len() == usize::max_value());
+    assert!(LengthField { width: 8, endian: Endian::Big }.max_len() == usize::max_value());
+}
@@ -1,5 +1,16 @@
-use grammar::{Delim, Grammar, GrammarDef};
+extern crate aho_corasick;
+
+use self::aho_corasick::Input;
+use fuzz::FuzzFile;
+use grammar::{self, Delim, DelimOptions, Endian, Grammar, GrammarDef, LengthField};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::ops::Range;
+use error::{Result, ResultExt};
 
 pub type NodeRef = usize;
 pub type RangeRef = usize;
@@ -8,15 +19,59 @@ pub type RangeRef = usize;
 enum Match<'buf> {
     Break(&'buf [u8], &'buf [u8]),
     Whitespace(&'buf [u8], &'buf [u8], &'buf [u8]),
-    DelimStart(&'buf [u8], &'buf [u8], Vec<u8>, &'buf [u8]),
+    Lexed(&'buf [u8], &'buf [u8], &'buf [u8]),
+    Token(&'buf [u8], &'buf [u8], &'buf [u8]),
+    DelimStart(&'buf [u8], &'buf [u8], Vec<u8>, DelimOptions, &'buf [u8]),
     DelimEnd(&'buf [u8], &'buf [u8], &'buf [u8]),
+    LengthPrefixed(LengthField, &'buf [u8], &'buf [u8]),
+}
+
+impl<'buf> Match<'buf> {
+    fn remainder(self: &Self) -> &'buf [u8] {
+        match *self {
+            Match::Break(_, remainder) |
+            Match::Whitespace(_, _, remainder) |
+            Match::Lexed(_, _, remainder) |
+            Match::Token(_, _, remainder) |
+            Match::DelimStart(_, _, _, _, remainder) |
+            Match::DelimEnd(_, _, remainder) |
+            Match::LengthPrefixed(_, _, remainder) => remainder,
+        }
+    }
+
+    // The input skipped before the match.
+    fn prefix(self: &Self) -> &'buf [u8] {
+        match *self {
+            Match::Break(prefix, _) |
+            Match::Whitespace(prefix, _, _) |
+            Match::Lexed(prefix, _, _) |
+            Match::Token(prefix, _, _) |
+            Match::DelimStart(prefix, _, _, _, _) |
+            Match::DelimEnd(prefix, _, _) => prefix,
+            Match::LengthPrefixed(_, _, _) => &[],
+        }
+    }
 }
 
 #[derive(Clone)]
 pub enum Node<'buf> {
     Delim(Delim<'buf>, RangeRef),
+    // The length field isn't stored, it is re-encoded from the serialized
+    // length of the range.
+    LengthPrefixed(LengthField, RangeRef),
     Range(RangeRef),
-    Token(&'buf [u8]),
+    Token(Cow<'buf, [u8]>),
+}
+
+impl<'buf> Node<'buf> {
+    pub fn into_owned(self: Self) -> Node<'static> {
+        match self {
+            Node::Delim(delim, rangeref) => Node::Delim(delim.into_owned(), rangeref),
+            Node::LengthPrefixed(field, rangeref) => Node::LengthPrefixed(field, rangeref),
+            Node::Range(rangeref) => Node::Range(rangeref),
+            Node::Token(token) => Node::Token(Cow::Owned(token.into_owned())),
+        }
+    }
 }
 
 fn fmt_token(f: &mut fmt::Write, token: &[u8]) -> fmt::Result {
@@ -37,20 +92,47 @@ fn fmt_token(f: &mut fmt::Write, token: &[u8]) -> fmt::Result {
     write!(f, "\"")
 }
 
+// Like `fmt_token`, but as a JSON string. A literal backslash is escaped as
+// `\\` so the decoded string is unambiguous.
+fn fmt_json_token(f: &mut fmt::Write, token: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for b in token {
+        if *b == b'"' {
+            write!(f, "\\\"")?
+        } else if *b == b'\\' {
+            write!(f, "\\\\\\\\")?
+        } else if *b > 0x1f && *b < 0x7f {
+            write!(f, "{}", *b as char)?
+        } else if *b == 0x09 {
+            write!(f, "\\\\t")?
+        } else if *b == 0x0a {
+            write!(f, "\\\\n")?
+        } else if *b == 0x0d {
+            write!(f, "\\\\r")?
+        } else {
+            write!(f, "\\\\x{:02x}", *b)?
+        }
+    }
+    write!(f, "\"")
+}
+
 impl<'buf> fmt::Debug for Node<'buf> {
     fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Node::Delim(ref delim, rangeref) => {
                 write!(f, "Delim(")?;
-                fmt_token(f, delim.start_pattern)?;
+                fmt_token(f, &delim.start_pattern)?;
                 write!(f, ", {}, ", rangeref)?;
-                fmt_token(f, delim.end_pattern)?;
+                fmt_token(f, &delim.end_pattern)?;
                 write!(f, ")")
             },
+            Node::LengthPrefixed(field, rangeref) => {
+                write!(f, "LengthPrefixed({}, {:?}, {})", field.width, field.endian, rangeref)
+            },
             Node::Range(rangeref) => {
                 write!(f, "Range({})", rangeref)
             },
-            Node::Token(token) => {
+            Node::Token(ref token) => {
                 write!(f, "Token(")?;
                 fmt_token(f, token)?;
                 write!(f, ")")
@@ -59,61 +141,268 @@ impl<'buf> fmt::Debug for Node<'buf> {
     }
 }
 
+// The tree walks below use an explicit stack rather than recursion, so that
+// arbitrarily deep trees can't overflow the call stack.
+enum DumpWork {
+    Open(NodeRef, usize),
+    Close(NodeRef, usize),
+}
+
+enum HashWork<'a> {
+    Node(NodeRef),
+    Range(&'a [NodeRef]),
+}
+
+// Indentation stops growing after a while, so that dumping a deep tree
+// isn't quadratic in its depth.
+const MAX_DUMP_DEPTH: usize = 64;
+
+fn dump_indent(depth: usize) -> usize {
+    4 * cmp::min(depth, MAX_DUMP_DEPTH)
+}
+
+// How finely a grammar split up an input, see `ParsedFile::stats`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ParseStats {
+    pub tokens: usize,
+    pub delims: usize,
+    pub length_prefixed: usize,
+    pub ranges: usize,
+    pub max_depth: usize,
+    // Bytes of input covered by the root nodes.
+    pub total_bytes: usize,
+    pub largest_token: usize,
+}
+
+impl fmt::Display for ParseStats {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "tokens: {}", self.tokens)?;
+        writeln!(f, "delims: {}", self.delims)?;
+        writeln!(f, "length-prefixed: {}", self.length_prefixed)?;
+        writeln!(f, "ranges: {}", self.ranges)?;
+        writeln!(f, "max depth: {}", self.max_depth)?;
+        writeln!(f, "total bytes: {}", self.total_bytes)?;
+        write!(f, "largest token: {}", self.largest_token)
+    }
+}
+
 pub struct ParsedFile<'buf> {
     pub root: Vec<NodeRef>,
     pub nodes: Vec<Node<'buf>>,
     pub ranges: Vec<Vec<NodeRef>>,
+    // The `[start, end)` offsets in the input covered by each node.
+    pub spans: Vec<Range<usize>>,
 }
 
+// A parsed file that doesn't borrow its input, so it can be kept after the
+// input is gone or shared between threads in an `Arc`.
+pub type OwnedParsedFile = ParsedFile<'static>;
+
 impl<'buf> ParsedFile<'buf> {
-    fn dump_noderef(self: &Self, indent: usize, noderef: NodeRef, f: &mut fmt::Write) -> fmt::Result {
-        match self.nodes[noderef] {
-            Node::Delim(ref delim, rangeref) => {
-                write!(f, "{:indent$}", "", indent=indent)?;
-                fmt_token(f, delim.start_pattern)?;
-                writeln!(f, " {{")?;
-                for noderef in &self.ranges[rangeref] {
-                    self.dump_noderef(indent + 4, *noderef, f)?
-                }
-                write!(f, "{:indent$}}} ", "", indent=indent)?;
-                fmt_token(f, delim.end_pattern)?;
-                writeln!(f, "")
-            },
-            Node::Range(rangeref) => {
-                writeln!(f, "{:indent$}{{", "", indent=indent)?;
-                for noderef in &self.ranges[rangeref] {
-                    self.dump_noderef(indent + 4, *noderef, f)?
-                }
-                writeln!(f, "{:indent$}}} ", "", indent=indent)
-            },
-            Node::Token(token) => {
-                write!(f, "{:indent$}", "", indent=indent)?;
-                fmt_token(f, token)?;
-                writeln!(f, "")
-            },
+    fn dump_noderef(self: &Self, noderef: NodeRef, f: &mut fmt::Write) -> fmt::Result {
+        let mut work = vec![DumpWork::Open(noderef, 0)];
+        while let Some(item) = work.pop() {
+            match item {
+                DumpWork::Open(noderef, depth) => {
+                    let indent = dump_indent(depth);
+                    let rangeref = match self.nodes[noderef] {
+                        Node::Delim(ref delim, rangeref) => {
+                            write!(f, "{:indent$}", "", indent=indent)?;
+                            fmt_token(f, &delim.start_pattern)?;
+                            writeln!(f, " @ {:?} {{", self.spans[noderef])?;
+                            rangeref
+                        },
+                        Node::LengthPrefixed(field, rangeref) => {
+                            writeln!(f, "{:indent$}[{} {:?}] @ {:?} {{", "", field.width, field.endian, self.spans[noderef], indent=indent)?;
+                            rangeref
+                        },
+                        Node::Range(rangeref) => {
+                            writeln!(f, "{:indent$}{{", "", indent=indent)?;
+                            rangeref
+                        },
+                        Node::Token(ref token) => {
+                            write!(f, "{:indent$}", "", indent=indent)?;
+                            fmt_token(f, token)?;
+                            writeln!(f, " @ {:?}", self.spans[noderef])?;
+                            continue
+                        },
+                    };
+                    work.push(DumpWork::Close(noderef, depth));
+                    for noderef in self.ranges[rangeref].iter().rev() {
+                        work.push(DumpWork::Open(*noderef, depth + 1))
+                    }
+                },
+                DumpWork::Close(noderef, depth) => {
+                    let indent = dump_indent(depth);
+                    match self.nodes[noderef] {
+                        Node::Delim(ref delim, _) => {
+                            write!(f, "{:indent$}}} ", "", indent=indent)?;
+                            fmt_token(f, &delim.end_pattern)?;
+                            writeln!(f)?
+                        },
+                        _ => writeln!(f, "{:indent$}}} ", "", indent=indent)?,
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_range<H: Hasher>(self: &Self, range: &[NodeRef], state: &mut H) {
+        let mut work = vec![HashWork::Range(range)];
+        while let Some(item) = work.pop() {
+            match item {
+                HashWork::Range(range) => {
+                    range.len().hash(state);
+                    for noderef in range.iter().rev() {
+                        work.push(HashWork::Node(*noderef))
+                    }
+                },
+                HashWork::Node(noderef) => {
+                    match self.nodes[noderef] {
+                        Node::Delim(ref delim, rangeref) => {
+                            0u8.hash(state);
+                            delim.start_pattern.hash(state);
+                            delim.end_pattern.hash(state);
+                            work.push(HashWork::Range(&self.ranges[rangeref]))
+                        },
+                        Node::Range(rangeref) => {
+                            1u8.hash(state);
+                            work.push(HashWork::Range(&self.ranges[rangeref]))
+                        },
+                        Node::Token(_) => {
+                            2u8.hash(state)
+                        },
+                        Node::LengthPrefixed(field, rangeref) => {
+                            3u8.hash(state);
+                            field.width.hash(state);
+                            work.push(HashWork::Range(&self.ranges[rangeref]))
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    // Hashes the shape of the tree and its delimiters, but not token contents.
+    pub fn structural_hash(self: &Self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash_range(&self.root[..], &mut hasher);
+        hasher.finish()
+    }
+
+    pub fn span(self: &Self, noderef: NodeRef) -> Range<usize> {
+        self.spans[noderef].clone()
+    }
+
+    // Copies every token and pattern still borrowed from the input.
+    pub fn into_owned(self: Self) -> OwnedParsedFile {
+        ParsedFile {
+            root: self.root,
+            nodes: self.nodes.into_iter().map(Node::into_owned).collect(),
+            ranges: self.ranges,
+            spans: self.spans,
+        }
+    }
+
+    pub fn stats(self: &Self) -> ParseStats {
+        let mut stats = ParseStats {
+            ranges: self.ranges.len(),
+            max_depth: FuzzFile::new(self).depth(),
+            total_bytes: self.root.iter().map(|noderef| self.spans[*noderef].len()).sum(),
+            ..ParseStats::default()
+        };
+        for node in &self.nodes {
+            match *node {
+                Node::Delim(..) => stats.delims += 1,
+                Node::LengthPrefixed(..) => stats.length_prefixed += 1,
+                Node::Range(_) => (),
+                Node::Token(ref token) => {
+                    stats.tokens += 1;
+                    stats.largest_token = cmp::max(stats.largest_token, token.len())
+                },
+            }
+        }
+        stats
+    }
+
+    pub fn subtree_bytes(self: &Self, noderef: NodeRef) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        FuzzFile::new(self).serialize_subtree(noderef, &mut bytes);
+        bytes
+    }
+
+    fn write_json(self: &Self, f: &mut fmt::Write) -> fmt::Result {
+        write!(f, "{{\"root\":{:?},\"nodes\":[", self.root)?;
+        for (noderef, node) in self.nodes.iter().enumerate() {
+            if noderef > 0 {
+                write!(f, ",")?
+            }
+            match *node {
+                Node::Delim(ref delim, rangeref) => {
+                    write!(f, "{{\"type\":\"delim\",\"start\":")?;
+                    fmt_json_token(f, &delim.start_pattern)?;
+                    write!(f, ",\"end\":")?;
+                    fmt_json_token(f, &delim.end_pattern)?;
+                    write!(f, ",\"range\":{}", rangeref)?
+                },
+                Node::LengthPrefixed(field, rangeref) => {
+                    let endian = match field.endian {
+                        Endian::Big => "big",
+                        Endian::Little => "little",
+                    };
+                    write!(f, "{{\"type\":\"length_prefixed\",\"width\":{},\"endian\":\"{}\",\"range\":{}", field.width, endian, rangeref)?
+                },
+                Node::Range(rangeref) => {
+                    write!(f, "{{\"type\":\"range\",\"range\":{}", rangeref)?
+                },
+                Node::Token(ref token) => {
+                    write!(f, "{{\"type\":\"token\",\"token\":")?;
+                    fmt_json_token(f, token)?
+                },
+            }
+            let span = &self.spans[noderef];
+            write!(f, ",\"span\":[{},{}]}}", span.start, span.end)?
         }
+        write!(f, "],\"ranges\":{:?}}}", self.ranges)
+    }
+
+    // The root, nodes and ranges as a JSON object. Tokens are strings
+    // escaped as in `dump`.
+    pub fn to_json(self: &Self) -> String {
+        let mut s = String::new();
+        self.write_json(&mut s).expect("ParsedFile.to_json");
+        s
     }
 
     pub fn dump(self: &Self) -> String {
         let mut s = String::new();
         for noderef in &self.root {
-            self.dump_noderef(0, *noderef, &mut s).expect("ParsedFile.dump");
+            self.dump_noderef(*noderef, &mut s).expect("ParsedFile.dump");
         }
         s
     }
 }
 
 struct SlurpState<'buf> {
-    start_pattern: &'buf [u8],
+    start_pattern: Cow<'buf, [u8]>,
+    start: usize,
     end_pattern: Vec<u8>,
+    ignore_case: bool,
+    non_nesting: bool,
+    escape: Option<Vec<u8>>,
     range: Vec<NodeRef>,
 }
 
 impl<'buf> SlurpState<'buf> {
-    fn new(start_pattern: &'buf [u8], end_pattern: Vec<u8>) -> Self {
+    fn new(start_pattern: Cow<'buf, [u8]>, start: usize, end_pattern: Vec<u8>, ignore_case: bool, options: DelimOptions) -> Self {
         SlurpState {
             start_pattern: start_pattern,
+            start: start,
             end_pattern: end_pattern,
+            ignore_case: ignore_case,
+            non_nesting: options.non_nesting,
+            escape: options.escape,
             range: Vec::new(),
         }
     }
@@ -123,21 +412,42 @@ impl<'buf> SlurpState<'buf> {
     }
 }
 
+// Builds the tree from matches, given the offset in the input of each piece.
+// Pieces are either borrowed from the input, or owned when the input is read
+// in chunks and doesn't outlive the parse.
 struct TreeBuilder<'buf> {
     root: Vec<NodeRef>,
     nodes: Vec<Node<'buf>>,
     ranges: Vec<Vec<NodeRef>>,
+    spans: Vec<Range<usize>>,
 
     stack: Vec<SlurpState<'buf>>,
+    // Start patterns that must not open a new delim, because a delim
+    // with that start is already open and is non-nesting. Kept in step
+    // with `stack` so it isn't rebuilt for every token.
+    blocked_starts: Vec<Cow<'buf, [u8]>>,
+    // Once this many delims are open, further start patterns are kept as
+    // plain tokens.
+    max_depth: usize,
+    // Offsets of unclosed start patterns and of end patterns with nothing
+    // open to close.
+    unbalanced: Vec<usize>,
+    // Offsets of start patterns kept as tokens because of `max_depth`.
+    too_deep: Vec<usize>,
 }
 
 impl<'buf> TreeBuilder<'buf> {
-    fn new() -> Self {
+    fn new(max_depth: usize) -> Self {
         TreeBuilder {
             root: Vec::new(),
             nodes: Vec::new(),
             ranges: Vec::new(),
+            spans: Vec::new(),
             stack: Vec::new(),
+            blocked_starts: Vec::new(),
+            max_depth: max_depth,
+            unbalanced: Vec::new(),
+            too_deep: Vec::new(),
         }
     }
 
@@ -150,15 +460,17 @@ impl<'buf> TreeBuilder<'buf> {
         }
     }
 
-    fn push_node(self: &mut Self, node: Node<'buf>) -> NodeRef {
+    fn push_node(self: &mut Self, node: Node<'buf>, span: Range<usize>) -> NodeRef {
         let noderef = self.nodes.len();
         self.nodes.push(node);
+        self.spans.push(span);
         noderef
     }
 
-    fn push_token(self: &mut Self, buf: &'buf [u8]) {
-        if !buf.is_empty() {
-            let noderef = self.push_node(Node::Token(buf));
+    fn push_token(self: &mut Self, start: usize, token: Cow<'buf, [u8]>) {
+        if !token.is_empty() {
+            let end = start + token.len();
+            let noderef = self.push_node(Node::Token(token), start..end);
 
             if !self.stack.is_empty() {
                 let index = self.stack.len() - 1;
@@ -175,61 +487,230 @@ impl<'buf> TreeBuilder<'buf> {
         index
     }
 
-    fn start_recurse(self: &mut Self, start_pattern: &'buf [u8], end_pattern: Vec<u8>) {
-        self.stack.push(SlurpState::new(start_pattern, end_pattern));
+    fn start_recurse(self: &mut Self, start: usize, start_pattern: Cow<'buf, [u8]>, end_pattern: Vec<u8>, ignore_case: bool, options: DelimOptions) {
+        if options.non_nesting {
+            self.blocked_starts.push(start_pattern.clone())
+        }
+        self.stack.push(SlurpState::new(start_pattern, start, end_pattern, ignore_case, options));
+    }
+
+    // The state of the open delims, for scanning `input` from `offset`.
+    fn context<'a>(self: &'a Self, input: &'a [u8], offset: usize) -> ScanContext<'a> {
+        let escape = match self.stack.last() {
+            Some(&SlurpState { ref end_pattern, escape: Some(ref escape), ignore_case, .. }) => Some(Escape {
+                end_pattern: end_pattern,
+                escape: escape,
+                ignore_case: ignore_case,
+            }),
+            _ => None,
+        };
+        ScanContext {
+            blocked_starts: &self.blocked_starts,
+            escape: escape,
+            open_end: self.stack.last().map(|state| &state.end_pattern[..]),
+            input: input,
+            offset: offset,
+        }
     }
 
-    fn state_with_end_pattern(self: &mut Self, end_pattern: &'buf [u8]) -> Option<SlurpState<'buf>> {
+    fn pop_state(self: &mut Self) -> Option<SlurpState<'buf>> {
+        let state = self.stack.pop();
+        if let Some(SlurpState { non_nesting: true, .. }) = state {
+            self.blocked_starts.pop();
+        }
+        state
+    }
+
+    fn state_with_end_pattern(self: &mut Self, end_pattern: &[u8]) -> Option<SlurpState<'buf>> {
         if self.stack.is_empty() {
             None
         } else {
             let index = self.stack.len() - 1;
-            if self.stack[index].end_pattern == end_pattern {
-                self.stack.pop()
+            let state = &self.stack[index];
+            if grammar::pattern_eq(&state.end_pattern, end_pattern, state.ignore_case) {
+                self.pop_state()
             } else {
                 None
             }
         }
     }
 
-    fn end_recurse(self: &mut Self, end_pattern: &'buf [u8]) {
-        match self.state_with_end_pattern(end_pattern) {
+    fn end_recurse(self: &mut Self, start: usize, end_pattern: Cow<'buf, [u8]>) {
+        match self.state_with_end_pattern(&end_pattern) {
             Some(state) => {
                 let rangeref = self.push_range(state.range);
-                let noderef = self.push_node(Node::Delim(Delim::new(state.start_pattern, end_pattern), rangeref));
+                let span = state.start..start + end_pattern.len();
+                let noderef = self.push_node(Node::Delim(Delim::new(state.start_pattern, end_pattern), rangeref), span);
                 self.add_node_ref(noderef)
             },
             None => {
-                self.push_token(end_pattern)
+                self.unbalanced.push(start);
+                self.push_token(start, end_pattern)
             },
         }
     }
 
-    fn finish(self: &mut Self) {
-        while let Some(state) = self.stack.pop() {
-            self.push_token(state.start_pattern);
-            for noderef in &state.range {
-                self.add_node_ref(*noderef)
+    fn push_length_prefixed(self: &mut Self, field: LengthField, start: usize, payload: Cow<'buf, [u8]>) {
+        let end = start + payload.len();
+        let mut range = Vec::new();
+        if !payload.is_empty() {
+            range.push(self.push_node(Node::Token(payload), start..end))
+        }
+        let rangeref = self.push_range(range);
+        let noderef = self.push_node(Node::LengthPrefixed(field, rangeref), start - field.width..end);
+        self.add_node_ref(noderef)
+    }
+
+    // Adds one match to the tree and returns the input left after it.
+    // `offset` gives the offset in the whole input of a slice of the buffer
+    // being scanned, and `bytes` turns it into node contents.
+    fn push_match<'a, O, B>(self: &mut Self, grammar: &Grammar, token_match: Match<'a>, offset: O, bytes: B) -> &'a [u8]
+        where O: Fn(&[u8]) -> usize, B: Fn(&'a [u8]) -> Cow<'buf, [u8]> {
+        match token_match {
+            Match::Whitespace(prefix, token, remainder) | Match::Lexed(prefix, token, remainder) | Match::Token(prefix, token, remainder) => {
+                self.push_token(offset(prefix), bytes(prefix));
+                self.push_token(offset(token), bytes(token));
+                remainder
+            },
+            Match::DelimStart(prefix, start_pattern, end_pattern, options, remainder) => {
+                self.push_token(offset(prefix), bytes(prefix));
+                if self.stack.len() >= self.max_depth {
+                    self.too_deep.push(offset(start_pattern));
+                    self.push_token(offset(start_pattern), bytes(start_pattern))
+                } else {
+                    self.start_recurse(offset(start_pattern), bytes(start_pattern), end_pattern, grammar.case_insensitive, options)
+                }
+                remainder
+            },
+            Match::DelimEnd(prefix, end_pattern, remainder) => {
+                self.push_token(offset(prefix), bytes(prefix));
+                self.end_recurse(offset(end_pattern), bytes(end_pattern));
+                remainder
+            },
+            Match::LengthPrefixed(field, payload, remainder) => {
+                self.push_length_prefixed(field, offset(payload), bytes(payload));
+                remainder
+            },
+            Match::Break(token, remainder) => {
+                self.push_token(offset(token), bytes(token));
+                remainder
+            },
+        }
+    }
+
+    // Unclosed delims are flattened into their parent, unless `auto_close`
+    // is set, in which case they are closed with their end pattern. A
+    // dangling start, an unclosed delim with nothing after it, is dropped
+    // entirely when `drop_dangling_start` is set. `len` is the length of
+    // the whole input.
+    fn finish(self: &mut Self, len: usize, auto_close: bool, drop_dangling_start: bool) {
+        while let Some(state) = self.pop_state() {
+            self.unbalanced.push(state.start);
+            if drop_dangling_start && state.range.is_empty() {
+                continue
+            } else if auto_close {
+                // An auto-closed delim runs to the end of the input.
+                let rangeref = self.push_range(state.range);
+                let span = state.start..len;
+                let noderef = self.push_node(Node::Delim(Delim::new(state.start_pattern, state.end_pattern), rangeref), span);
+                self.add_node_ref(noderef)
+            } else {
+                self.push_token(state.start, state.start_pattern);
+                for noderef in &state.range {
+                    self.add_node_ref(*noderef)
+                }
             }
         }
     }
+
+    // The end patterns of delims that were too deep to open are left
+    // unmatched, so too deep input is reported before unbalanced input.
+    fn check(self: &Self, recovery: RecoveryPolicy) -> Result<()> {
+        if recovery == RecoveryPolicy::Strict && !self.too_deep.is_empty() {
+            bail!("Delimiters nested deeper than max depth {} at offset {}", self.max_depth, self.too_deep[0])
+        }
+        if recovery == RecoveryPolicy::Strict && !self.unbalanced.is_empty() {
+            let mut offsets = self.unbalanced.clone();
+            offsets.sort();
+            let offsets: Vec<String> = offsets.iter().map(|offset| offset.to_string()).collect();
+            match offsets.len() {
+                1 => bail!("Unbalanced delimiter at offset {}", offsets[0]),
+                _ => bail!("Unbalanced delimiters at offsets {}", offsets.join(", ")),
+            }
+        }
+        Ok(())
+    }
+
+    fn into_parsed_file(self: Self) -> ParsedFile<'buf> {
+        ParsedFile {
+            root: self.root,
+            nodes: self.nodes,
+            ranges: self.ranges,
+            spans: self.spans,
+        }
+    }
+}
+
+// The escape of the innermost open delim.
+struct Escape<'a> {
+    end_pattern: &'a [u8],
+    escape: &'a [u8],
+    ignore_case: bool,
 }
 
-fn starts_with_any_pattern<'a, 'buf>(patterns: &'a [Vec<u8>], buf: &'buf [u8]) -> Option<&'a [u8]> {
-    for pattern in patterns {
-        if buf.starts_with(pattern) {
-            return Some(&pattern[..])
+// What the open delims change about matching at the current position.
+struct ScanContext<'a> {
+    // Start patterns that must not open a new delim.
+    blocked_starts: &'a [Cow<'a, [u8]>],
+    escape: Option<Escape<'a>>,
+    // The end pattern of the innermost open delim.
+    open_end: Option<&'a [u8]>,
+    // The whole input, and the offset in it of the buffer being scanned,
+    // as an escape can be in an earlier token.
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ScanContext<'a> {
+    // Whether `buf[i..]` is preceded by an odd number of `escape`s. An
+    // escaped escape doesn't count, so e.g. `\\"` still closes a string.
+    fn is_escaped(self: &Self, i: usize, escape: &[u8]) -> bool {
+        if escape.is_empty() {
+            return false
+        }
+        let mut before = &self.input[..self.offset + i];
+        let mut count = 0;
+        while before.ends_with(escape) {
+            before = &before[..before.len() - escape.len()];
+            count += 1;
+        }
+        count % 2 == 1
+    }
+
+    // The innermost open delim's end pattern, if it is escaped at `buf[i..]`.
+    fn escaped_end(self: &Self, buf: &[u8], i: usize) -> Option<&'a [u8]> {
+        match self.escape {
+            Some(ref escape) if grammar::starts_with(&buf[i..], escape.end_pattern, escape.ignore_case) && self.is_escaped(i, escape.escape) => Some(escape.end_pattern),
+            _ => None,
         }
     }
-    None
 }
 
-fn scan_whitespace<'buf>(whitespace: &[Vec<u8>], buf: &'buf [u8]) -> Option<(&'buf [u8], &'buf [u8])> {
+// The longest matching pattern wins, so that e.g. "\r\n" is matched as a
+// whole even when "\r" is also a pattern.
+fn starts_with_any_pattern<'a, 'buf>(patterns: &'a [Vec<u8>], buf: &'buf [u8], ignore_case: bool) -> Option<&'a [u8]> {
+    patterns.iter()
+        .filter(|pattern| !pattern.is_empty() && grammar::starts_with(buf, pattern, ignore_case))
+        .max_by_key(|pattern| pattern.len())
+        .map(|pattern| &pattern[..])
+}
+
+fn scan_whitespace<'buf>(whitespace: &[Vec<u8>], buf: &'buf [u8], ignore_case: bool) -> Option<(&'buf [u8], &'buf [u8])> {
     assert!(!buf.is_empty());
 
     let mut i = 0;
     while i < buf.len() {
-        match starts_with_any_pattern(whitespace, &buf[i..]) {
+        match starts_with_any_pattern(whitespace, &buf[i..], ignore_case) {
             Some(pattern) => {
                 i += pattern.len()
             },
@@ -246,67 +727,379 @@ fn scan_whitespace<'buf>(whitespace: &[Vec<u8>], buf: &'buf [u8]) -> Option<(&'b
     }
 }
 
-fn scan_next<'buf, 'cfg>(grammar: &'cfg Grammar, buf: &'buf [u8]) -> Match<'buf> {
-    for (i, _) in buf.iter().enumerate() {
-        if let Some((whitespace, remainder)) = scan_whitespace(&grammar.whitespace[..], &buf[i..]) {
-            return Match::Whitespace(&buf[..i], whitespace, remainder)
+// At each offset whitespace is tried first, then the grammar's lexer, then
+// the longest matching pattern among `grammar.defs`. `Grammar::new` sorts
+// the defs by rank, so among equally long matches the earliest def wins.
+// Delim starts listed in `blocked_starts` are skipped so that they are
+// treated as content of the open non-nesting delim, as is an escaped end
+// pattern of the innermost open delim. An end pattern that closes the
+// innermost open delim, `open_end`, beats longer end patterns that would
+// only be stray tokens, so with `<`/`>` and `<<`/`>>` both `<` in `a<b<c>>`
+// are closed.
+fn scan_at<'buf, 'cfg>(grammar: &'cfg Grammar, buf: &'buf [u8], i: usize, context: &ScanContext) -> Option<Match<'buf>> {
+    if let Some((whitespace, remainder)) = scan_whitespace(&grammar.whitespace[..], &buf[i..], grammar.case_insensitive) {
+        return Some(Match::Whitespace(&buf[..i], whitespace, remainder))
+    }
+
+    if let Some(ref lexer) = grammar.lexer {
+        match lexer(&buf[i..]) {
+            Some(len) if len > 0 && len <= buf.len() - i => {
+                return Some(Match::Lexed(&buf[..i], &buf[i..i+len], &buf[i+len..]))
+            },
+            _ => (),
         }
+    }
+
+    let escaped_end = context.escaped_end(buf, i);
+    let closing_end = match context.open_end {
+        Some(open_end) if escaped_end.is_none() && !open_end.is_empty() && grammar::starts_with(&buf[i..], open_end, grammar.case_insensitive) => Some(open_end),
+        _ => None,
+    };
+
+    // Every def is tried and the longest matching pattern wins. Ties go to
+    // the earlier def, so rank and then config order still decide between
+    // patterns of the same length.
+    // A comment's end pattern is only searched for once the comment has
+    // won, so it is kept alongside the match.
+    let mut best: Option<(usize, Match<'buf>, Option<&'cfg [u8]>)> = None;
+    {
+        let mut consider = |len: usize, token_match: Match<'buf>, comment_end: Option<&'cfg [u8]>| {
+            match best {
+                Some((best_len, _, _)) if best_len >= len => (),
+                _ => best = Some((len, token_match, comment_end)),
+            }
+        };
 
         for def in &grammar.defs {
             match *def {
-                GrammarDef::Delim(ref start_pattern, ref end_pattern) => {
-                    if buf[i..].starts_with(start_pattern) {
-                        return Match::DelimStart(&buf[..i], &buf[i..i+start_pattern.len()], end_pattern.clone(), &buf[i+start_pattern.len()..])
-                    } else if buf[i..].starts_with(end_pattern) {
-                        return Match::DelimEnd(&buf[..i], &buf[i..i+end_pattern.len()], &buf[i+end_pattern.len()..])
+                GrammarDef::Delim(ref start_pattern, ref end_pattern, ref options) => {
+                    let mut delim = Delim::new(start_pattern, end_pattern);
+                    delim.ignore_case = grammar.case_insensitive;
+                    let blocked = context.blocked_starts.iter().any(|blocked| grammar::pattern_eq(blocked, start_pattern, grammar.case_insensitive));
+                    let escaped = escaped_end.map_or(false, |escaped_end| grammar::pattern_eq(escaped_end, end_pattern, grammar.case_insensitive));
+                    let stray = closing_end.map_or(false, |closing_end| !grammar::pattern_eq(closing_end, end_pattern, grammar.case_insensitive));
+                    if !blocked && delim.matches_start(&buf[i..]) {
+                        consider(start_pattern.len(), Match::DelimStart(&buf[..i], &buf[i..i+start_pattern.len()], end_pattern.clone(), options.clone(), &buf[i+start_pattern.len()..]), None)
+                    } else if !escaped && !stray && delim.matches_end(&buf[i..]) {
+                        consider(end_pattern.len(), Match::DelimEnd(&buf[..i], &buf[i..i+end_pattern.len()], &buf[i+end_pattern.len()..]), None)
+                    }
+                },
+                GrammarDef::Comment(ref start_pattern, ref end_pattern) => {
+                    if grammar::starts_with(&buf[i..], start_pattern, grammar.case_insensitive) {
+                        let body = i + start_pattern.len();
+                        consider(start_pattern.len(), Match::Token(&buf[..i], &buf[i..body], &buf[body..]), Some(end_pattern))
                     }
                 },
                 GrammarDef::Breaker(ref pattern) => {
-                    if i != 0 && buf[i..].starts_with(pattern) {
-                        return Match::Break(&buf[..i], &buf[i..])
+                    if i != 0 && grammar::starts_with(&buf[i..], pattern, grammar.case_insensitive) {
+                        consider(pattern.len(), Match::Break(&buf[..i], &buf[i..]), None)
+                    }
+                },
+                GrammarDef::Tokenizer(ref pattern, ref escape) => {
+                    let escaped = escape.as_ref().map_or(false, |escape| context.is_escaped(i, escape));
+                    if !escaped && grammar::starts_with(&buf[i..], pattern, grammar.case_insensitive) {
+                        consider(pattern.len(), Match::Token(&buf[..i], &buf[i..i+pattern.len()], &buf[i+pattern.len()..]), None)
+                    }
+                },
+                GrammarDef::LengthPrefixed { width, endian } => {
+                    // Only the start of a token can be a length field, and
+                    // the whole payload has to be present.
+                    let field = LengthField { width: width, endian: endian };
+                    match field.read(buf) {
+                        Some(len) if i == 0 && len <= buf.len() - width => {
+                            consider(width, Match::LengthPrefixed(field, &buf[width..width+len], &buf[width+len..]), None)
+                        },
+                        _ => (),
                     }
                 },
             }
         }
     }
 
+    best.map(|(len, token_match, comment_end)| match comment_end {
+        Some(end_pattern) => {
+            let body = i + len;
+            let end = match find_pattern(&buf[body..], end_pattern, grammar.case_insensitive) {
+                Some(offset) => body + offset + end_pattern.len(),
+                None => buf.len(),
+            };
+            Match::Token(&buf[..i], &buf[i..end], &buf[end..])
+        },
+        None => token_match,
+    })
+}
+
+fn find_pattern(buf: &[u8], pattern: &[u8], ignore_case: bool) -> Option<usize> {
+    if pattern.is_empty() {
+        return Some(0)
+    }
+    if ignore_case {
+        buf.windows(pattern.len()).position(|window| window.eq_ignore_ascii_case(pattern))
+    } else {
+        buf.windows(pattern.len()).position(|window| window == pattern)
+    }
+}
+
+// Only the offsets the grammar's prefilter reports, plus offset 0 where a
+// length-prefixed section can start, are tried. A lexer can match anywhere,
+// so with one every offset is tried. Offsets before `from` are already
+// known not to match and are skipped.
+fn scan_next<'buf, 'cfg>(grammar: &'cfg Grammar, buf: &'buf [u8], from: usize, context: &ScanContext) -> Match<'buf> {
+    match (&grammar.lexer, &grammar.prefilter) {
+        (&None, &Some(ref prefilter)) => {
+            if from == 0 {
+                if let Some(token_match) = scan_at(grammar, buf, 0, context) {
+                    return token_match
+                }
+            }
+            let mut start = cmp::max(1, from);
+            while start < buf.len() {
+                let input = Input::new(buf).span(start..buf.len());
+                let candidate = match prefilter.find(input) {
+                    Some(candidate) => candidate.start(),
+                    None => break,
+                };
+                if let Some(token_match) = scan_at(grammar, buf, candidate, context) {
+                    return token_match
+                }
+                start = candidate + 1
+            }
+        },
+        _ => {
+            for i in from..buf.len() {
+                if let Some(token_match) = scan_at(grammar, buf, i, context) {
+                    return token_match
+                }
+            }
+        },
+    }
+
     Match::Break(buf, &buf[buf.len()..])
 }
 
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryPolicy {
+    // Keep unmatched end patterns as tokens and handle unclosed delims as
+    // `auto_close` and `drop_dangling_start` say.
+    Relaxed,
+    // Fail on any unmatched start or end pattern.
+    Strict,
+}
+
+pub struct ParseConfig {
+    pub max_nodes: Option<usize>,
+    // Start patterns nested deeper than this are kept as plain tokens, so
+    // adversarial inputs can't build arbitrarily deep trees.
+    pub max_depth: usize,
+    // Close unclosed delims instead of flattening them. The output no
+    // longer round-trips, as the missing end patterns are added.
+    pub auto_close: bool,
+    // Drop unclosed delims that have no contents, such as an opener at the
+    // very end of the input. The output no longer round-trips.
+    pub drop_dangling_start: bool,
+    pub recovery: RecoveryPolicy,
+}
+
+impl ParseConfig {
+    pub fn new() -> Self {
+        ParseConfig {
+            max_nodes: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            auto_close: false,
+            drop_dangling_start: false,
+            recovery: RecoveryPolicy::Relaxed,
+        }
+    }
+}
+
 pub fn slurp<'buf>(grammar: &Grammar, buf: &'buf [u8]) -> ParsedFile<'buf> {
-    let mut builder = TreeBuilder::new();
+    slurp_with(grammar, buf, &ParseConfig::new())
+}
+
+// Parses as if `config.recovery` were `RecoveryPolicy::Relaxed`, use
+// `try_slurp_with` to honour it.
+pub fn slurp_with<'buf>(grammar: &Grammar, buf: &'buf [u8], config: &ParseConfig) -> ParsedFile<'buf> {
+    build_tree(grammar, buf, config).into_parsed_file()
+}
+
+// Only fails with `RecoveryPolicy::Strict`.
+pub fn try_slurp_with<'buf>(grammar: &Grammar, buf: &'buf [u8], config: &ParseConfig) -> Result<ParsedFile<'buf>> {
+    let builder = build_tree(grammar, buf, config);
+    builder.check(config.recovery)?;
+    Ok(builder.into_parsed_file())
+}
+
+fn build_tree<'buf>(grammar: &Grammar, buf: &'buf [u8], config: &ParseConfig) -> TreeBuilder<'buf> {
+    let mut builder = TreeBuilder::new(config.max_depth);
+    // Every piece of a match is a subslice of the input, so its offset
+    // comes from its position in memory.
+    let offset = |slice: &[u8]| slice.as_ptr() as usize - buf.as_ptr() as usize;
 
     let mut remainder = buf;
     while !remainder.is_empty() {
-        let token_match = scan_next(grammar, remainder);
-        remainder = match token_match {
-            Match::Whitespace(prefix, token, remainder) => {
-                builder.push_token(prefix);
-                builder.push_token(token);
-                remainder
-            },
-            Match::DelimStart(prefix, start_pattern, end_pattern, remainder) => {
-                builder.push_token(prefix);
-                builder.start_recurse(start_pattern, end_pattern);
-                remainder
-            },
-            Match::DelimEnd(prefix, end_pattern, remainder) => {
-                builder.push_token(prefix);
-                builder.end_recurse(end_pattern);
-                remainder
-            },
-            Match::Break(token, remainder) => {
-                builder.push_token(token);
-                remainder
-            },
+        // Once the node budget is spent, keep the rest of the input as a
+        // single trailing token so it still serializes unchanged.
+        if config.max_nodes.map_or(false, |max_nodes| builder.nodes.len() >= max_nodes) {
+            builder.push_token(offset(remainder), Cow::Borrowed(remainder));
+            break
         }
+
+        let token_match = {
+            let context = builder.context(buf, offset(remainder));
+            scan_next(grammar, remainder, 0, &context)
+        };
+        remainder = builder.push_match(grammar, token_match, &offset, Cow::Borrowed);
     }
 
-    builder.finish();
+    builder.finish(buf.len(), config.auto_close, config.drop_dangling_start);
+    builder
+}
+
+// The most input any single pattern or length field can span.
+fn max_pattern_len(grammar: &Grammar) -> usize {
+    let defs = grammar.defs.iter().map(|def| match *def {
+        GrammarDef::Comment(ref start_pattern, ref end_pattern) | GrammarDef::Delim(ref start_pattern, ref end_pattern, _) => cmp::max(start_pattern.len(), end_pattern.len()),
+        GrammarDef::Breaker(ref pattern) | GrammarDef::Tokenizer(ref pattern, _) => pattern.len(),
+        GrammarDef::LengthPrefixed { width, .. } => width,
+    });
+    let whitespace = grammar.whitespace.iter().map(|pattern| pattern.len());
+    cmp::max(1, defs.chain(whitespace).max().unwrap_or(0))
+}
+
+// Whether `buf` starts with a length field whose payload isn't all there.
+fn awaits_payload(grammar: &Grammar, buf: &[u8]) -> bool {
+    grammar.defs.iter().any(|def| match *def {
+        GrammarDef::LengthPrefixed { width, endian } => {
+            let field = LengthField { width: width, endian: endian };
+            field.read(buf).map_or(false, |len| len > buf.len() - width)
+        },
+        _ => false,
+    })
+}
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Parses input read in chunks, for inputs too large to load up front. Only
+// as much is buffered as it takes to be sure of the next match, so a
+// pattern split across two reads still matches, and the tree owns its
+// tokens. The tree is the same as `slurp_with` gives for the whole input,
+// as long as the grammar's lexer, if any, decides each match from what is
+// buffered.
+pub struct StreamingParser<'g, R: Read> {
+    grammar: &'g Grammar,
+    reader: R,
+    config: ParseConfig,
+    pub chunk_size: usize,
+}
+
+impl<'g, R: Read> StreamingParser<'g, R> {
+    pub fn new(grammar: &'g Grammar, reader: R) -> Self {
+        StreamingParser::with_config(grammar, reader, ParseConfig::new())
+    }
+
+    pub fn with_config(grammar: &'g Grammar, reader: R, config: ParseConfig) -> Self {
+        StreamingParser {
+            grammar: grammar,
+            reader: reader,
+            config: config,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    // Appends up to `chunk_size` bytes to `buf`, returning true at the end
+    // of the input.
+    fn read_chunk(self: &mut Self, buf: &mut Vec<u8>) -> Result<bool> {
+        let chunk_size = cmp::max(1, self.chunk_size) as u64;
+        let len = self.reader.by_ref().take(chunk_size).read_to_end(buf).chain_err(|| "Could not read input")?;
+        Ok(len == 0)
+    }
+
+    pub fn parse(mut self: Self) -> Result<OwnedParsedFile> {
+        let grammar = self.grammar;
+        let chunk_size = cmp::max(1, self.chunk_size);
+        let lookahead = max_pattern_len(grammar);
+        let escape_bytes: Vec<u8> = grammar.defs.iter().flat_map(|def| match *def {
+            GrammarDef::Delim(_, _, DelimOptions { escape: Some(ref escape), .. }) |
+            GrammarDef::Tokenizer(_, Some(ref escape)) => escape.clone(),
+            _ => Vec::new(),
+        }).collect();
+
+        let mut builder = TreeBuilder::new(self.config.max_depth);
+        // The input from offset `base` on. Bytes before `start` are already
+        // parsed, and are only kept while they could be escapes that a later
+        // end pattern has to look back at.
+        let mut buf = Vec::new();
+        let mut base = 0;
+        let mut start = 0;
+        // Offsets from `start` to `start + scanned` can't begin a match, so
+        // a rescan after reading more input resumes from there.
+        let mut scanned = 0;
+        let mut eof = false;
+        // How much unparsed input to buffer before the next scan.
+        let mut wanted = chunk_size;
+        loop {
+            if !eof && buf.len() - start < wanted {
+                eof = self.read_chunk(&mut buf)?;
+                continue
+            }
+            if start == buf.len() {
+                break
+            }
+
+            if self.config.max_nodes.map_or(false, |max_nodes| builder.nodes.len() >= max_nodes) {
+                self.reader.read_to_end(&mut buf).chain_err(|| "Could not read input")?;
+                builder.push_token(base + start, Cow::Owned(buf[start..].to_vec()));
+                break
+            }
+
+            let remainder_len = {
+                let remainder = &buf[start..];
+                let token_match = {
+                    let context = builder.context(&buf, start);
+                    scan_next(grammar, remainder, scanned, &context)
+                };
+                // With too little after the match, a longer pattern or more
+                // whitespace might still follow in the next chunk.
+                let consumed = remainder.len() - token_match.remainder().len();
+                let awaiting = awaits_payload(grammar, remainder);
+                if !eof && (consumed + lookahead > remainder.len() || awaiting) {
+                    // No match began before the one found, and offsets at
+                    // least `lookahead` from the end saw every byte they
+                    // could match.
+                    if !awaiting {
+                        scanned = cmp::min(token_match.prefix().len(), remainder.len().saturating_sub(lookahead));
+                    }
+                    None
+                } else {
+                    let buf_start = buf.as_ptr() as usize;
+                    let offset = |slice: &[u8]| base + slice.as_ptr() as usize - buf_start;
+                    Some(builder.push_match(grammar, token_match, &offset, |slice: &[u8]| Cow::Owned(slice.to_vec())).len())
+                }
+            };
+            match remainder_len {
+                Some(remainder_len) => {
+                    start = buf.len() - remainder_len;
+                    scanned = 0;
+                    wanted = chunk_size;
+                },
+                None => {
+                    wanted = buf.len() - start + chunk_size;
+                    continue
+                },
+            }
+
+            if start >= chunk_size {
+                let kept = buf[..start].iter().rev().take_while(|b| escape_bytes.contains(b)).count();
+                buf.drain(..start - kept);
+                base += start - kept;
+                start = kept;
+            }
+        }
 
-    ParsedFile {
-        root: builder.root,
-        nodes: builder.nodes,
-        ranges: builder.ranges,
+        builder.finish(base + buf.len(), self.config.auto_close, self.config.drop_dangling_start);
+        builder.check(self.config.recovery)?;
+        Ok(builder.into_parsed_file())
     }
 }
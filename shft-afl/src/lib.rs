@@ -3,33 +3,77 @@ extern crate libc;
 extern crate rand;
 extern crate libshft;
 
+mod session;
+
 use libc::{c_void, size_t};
 use rand::isaac;
+use std::env;
+use std::panic;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use libshft::grammar::Grammar;
-use libshft::parse::{ParsedFile, slurp};
 use libshft::fuzz;
+use session::Session;
 
 static mut GRAMMAR: Option<Grammar> = None;
-static mut PARSED_FILE: Option<ParsedFile> = None;
-static mut RNG: Option<isaac::Isaac64Rng> = None;
+static mut SESSION: Option<Session<'static>> = None;
+
+fn reseed_interval() -> Result<Option<usize>, ()> {
+    match env::var("SHFT_RESEED_INTERVAL") {
+        Ok(interval) => usize::from_str(&interval).map(Some).map_err(|_| ()),
+        Err(_) => Ok(None),
+    }
+}
+
+// The value of `SHFT_CONFIG`, if set, or `./config.yml`.
+fn config_path(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "./config.yml".to_string())
+}
 
-static mut FUZZ_CONFIG: Option<fuzz::FuzzConfig> = None;
+// A grammar that fails to load, or a panic while loading it, is reported as
+// an error rather than unwinding into AFL.
+fn load_grammar(path: &str) -> Option<Grammar> {
+    match panic::catch_unwind(|| Grammar::from_path(path)) {
+        Ok(Ok(grammar)) => Some(grammar),
+        _ => None,
+    }
+}
+
+extern fn flush_stats() {
+    unsafe {
+        if let Some(ref session) = SESSION {
+            session.flush_stats()
+        }
+    }
+}
 
+// Loads the grammar from the path in `SHFT_CONFIG`, or `./config.yml` if
+// unset. Returns non-zero if the grammar or any other setting is invalid.
 #[no_mangle]
 pub unsafe extern fn afl_fuzz_init() -> size_t {
-    RNG = Some(isaac::Isaac64Rng::new_unseeded());
-    GRAMMAR = match Grammar::from_path("/home/phil/super-happy-fuzz-time/config.yml") {
-        Ok(grammar) => Some(grammar),
+    init(&config_path(env::var("SHFT_CONFIG").ok()))
+}
+
+unsafe fn init(config_path: &str) -> size_t {
+    GRAMMAR = match load_grammar(config_path) {
+        Some(grammar) => Some(grammar),
+        None => return 1,
+    };
+
+    let reseed_interval = match reseed_interval() {
+        Ok(reseed_interval) => reseed_interval,
         Err(_) => return 1,
     };
 
-    FUZZ_CONFIG = Some(fuzz::FuzzConfig {
-        max_mutations: 5,
-        max_duplications: 5,
-        valid_actions: fuzz::default_mutations(),
-        all_delims: GRAMMAR.as_ref().unwrap().delims()
-    });
+    let config = fuzz::FuzzConfig::new(GRAMMAR.as_ref().unwrap().delims());
+    let mut session = Session::new(isaac::Isaac64Rng::new_unseeded(), config);
+    session.set_reseed_interval(reseed_interval);
+    if let Ok(stats_path) = env::var("SHFT_STATS_FILE") {
+        session.set_stats_path(PathBuf::from(stats_path));
+        libc::atexit(flush_stats);
+    }
+    SESSION = Some(session);
     0
 }
 
@@ -39,7 +83,7 @@ pub unsafe extern fn afl_parse_one(in_buf: *const c_void, in_len: size_t) -> siz
         1
     } else {
         let in_slice = std::slice::from_raw_parts(in_buf as *const u8, in_len as usize);
-        PARSED_FILE = Some(slurp(GRAMMAR.as_mut().unwrap(), in_slice));
+        SESSION.as_mut().unwrap().parse_one(GRAMMAR.as_ref().unwrap(), in_slice);
         0
     }
 }
@@ -49,16 +93,21 @@ pub unsafe extern fn afl_fuzz_one(out_buf: *mut c_void, out_len: size_t) -> size
     if out_buf.is_null() || out_len == 0 {
         0
     } else {
-        let result = fuzz::fuzz_one(PARSED_FILE.as_mut().unwrap(), RNG.as_mut().unwrap(), FUZZ_CONFIG.as_mut().unwrap());
-
-        match result {
-            Some(fuzzed_file) => {
-                let out_slice = std::slice::from_raw_parts_mut(out_buf as *mut u8, out_len as usize);
-                let mut serialized = fuzz::SliceSerializer::new(out_slice);
-                fuzzed_file.serialize(&mut serialized);
-                serialized.bytes_written()
-            },
-            None => 0,
-        }
+        let out_slice = std::slice::from_raw_parts_mut(out_buf as *mut u8, out_len as usize);
+        SESSION.as_mut().unwrap().fuzz_one(out_slice)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_path, init, load_grammar};
+
+    #[test]
+    fn test_config_path() {
+        assert!(config_path(None) == "./config.yml");
+        assert!(config_path(Some("/etc/shft.yml".to_string())) == "/etc/shft.yml");
+
+        assert!(load_grammar("/nonexistent/config.yml").is_none());
+        assert!(unsafe { init("/nonexistent/config.yml") } == 1);
     }
 }
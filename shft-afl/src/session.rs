@@ -0,0 +1,230 @@
+use rand::{self, Rng};
+use rand::isaac;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use libshft::grammar::Grammar;
+use libshft::parse::{ParsedFile, slurp};
+use libshft::fuzz::{self, Mutation};
+
+const STATS_FLUSH_INTERVAL: usize = 10000;
+
+pub struct Session<'buf> {
+    rng: isaac::Isaac64Rng,
+    config: fuzz::FuzzConfig<'buf>,
+    parsed_file: Option<ParsedFile<'buf>>,
+    reseed_interval: Option<usize>,
+    iterations: usize,
+    // Per-mutation apply counts, only kept while tracing.
+    stats: Option<HashMap<Mutation, usize>>,
+    stats_path: Option<PathBuf>,
+    // Reused by every `fuzz_one`, as AFL calls it for each execution.
+    buffers: fuzz::FuzzBuffers,
+}
+
+impl<'buf> Session<'buf> {
+    pub fn new(rng: isaac::Isaac64Rng, config: fuzz::FuzzConfig<'buf>) -> Self {
+        Session {
+            rng: rng,
+            config: config,
+            parsed_file: None,
+            reseed_interval: None,
+            iterations: 0,
+            stats: None,
+            stats_path: None,
+            buffers: fuzz::FuzzBuffers::default(),
+        }
+    }
+
+    pub fn set_tracing(self: &mut Self, tracing: bool) {
+        self.stats = if tracing {
+            Some(HashMap::new())
+        } else {
+            None
+        }
+    }
+
+    // Enables tracing and writes the stats to `stats_path` every
+    // `STATS_FLUSH_INTERVAL` iterations.
+    pub fn set_stats_path(self: &mut Self, stats_path: PathBuf) {
+        self.set_tracing(true);
+        self.stats_path = Some(stats_path)
+    }
+
+    pub fn write_stats<W: Write>(self: &Self, mut out: W) -> io::Result<()> {
+        if let Some(ref stats) = self.stats {
+            let mut counts: Vec<_> = stats.iter().map(|(mutation, count)| (format!("{}", mutation), count)).collect();
+            counts.sort();
+            for (name, count) in counts {
+                writeln!(out, "{} {}", name, count)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The harness has nowhere to report errors, so a failed flush is
+    // simply retried at the next interval.
+    pub fn flush_stats(self: &Self) {
+        if let Some(ref stats_path) = self.stats_path {
+            if let Ok(f) = File::create(stats_path) {
+                let _ = self.write_stats(f);
+            }
+        }
+    }
+
+    pub fn set_reseed_interval(self: &mut Self, reseed_interval: Option<usize>) {
+        self.reseed_interval = reseed_interval
+    }
+
+    pub fn parse_one(self: &mut Self, grammar: &Grammar, buf: &'buf [u8]) {
+        self.parsed_file = Some(slurp(grammar, buf))
+    }
+
+    fn maybe_reseed(self: &mut Self) {
+        if let Some(interval) = self.reseed_interval {
+            if interval > 0 && self.iterations > 0 && self.iterations % interval == 0 {
+                self.rng = rand::thread_rng().gen()
+            }
+        }
+        self.iterations += 1
+    }
+
+    // Counts the mutations left in `buffers` by the latest `fuzz_one`.
+    fn record(self: &mut Self) {
+        if let Some(ref mut stats) = self.stats {
+            for mutation in &self.buffers.applied {
+                *stats.entry(mutation.clone()).or_insert(0) += 1
+            }
+        }
+        if self.iterations % STATS_FLUSH_INTERVAL == 0 {
+            self.flush_stats()
+        }
+    }
+
+    // Returns 0 rather than a truncated file when the output doesn't fit.
+    pub fn fuzz_one(self: &mut Self, out: &mut [u8]) -> usize {
+        self.maybe_reseed();
+
+        let len = {
+            let parsed_file = match self.parsed_file {
+                Some(ref parsed_file) => parsed_file,
+                None => return 0,
+            };
+
+            match fuzz::fuzz_one_buffered(parsed_file, &mut self.rng, &self.config, &mut self.buffers) {
+                Some(fuzzed_file) => {
+                    let mut serialized = fuzz::SliceSerializer::new(out);
+                    fuzzed_file.serialize(&mut serialized);
+                    if serialized.was_truncated() {
+                        self.buffers.applied.clear();
+                        0
+                    } else {
+                        serialized.bytes_written()
+                    }
+                },
+                None => 0,
+            }
+        };
+
+        self.record();
+        len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand::isaac;
+    use libshft::grammar::{DelimOptions, Grammar, GrammarDef};
+    use libshft::fuzz::{self, Mutation};
+    use super::Session;
+
+    fn outputs_sized(session: &mut Session, count: usize, out_len: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|_| {
+            let mut out = vec![0; out_len];
+            let len = session.fuzz_one(&mut out[..]);
+            out[..len].to_vec()
+        }).collect()
+    }
+
+    fn outputs(session: &mut Session, count: usize) -> Vec<Vec<u8>> {
+        outputs_sized(session, count, 256)
+    }
+
+    fn new_session<'buf>(grammar: &'buf Grammar, buf: &'buf [u8]) -> Session<'buf> {
+        let rng = isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let mut session = Session::new(rng, config);
+        session.parse_one(grammar, buf);
+        session
+    }
+
+    fn test_grammar() -> Grammar {
+        Grammar::new(vec![
+            GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        ], vec![b" ".to_vec()])
+    }
+
+    #[test]
+    fn test_reseed_interval() {
+        let grammar = test_grammar();
+        let buf = b"1 <<2 (3 4) <<5>> (6 <<7>>)>> 8 (9)";
+        let new_session = || new_session(&grammar, buf);
+
+        let mut baseline = new_session();
+        let mut reseeded = new_session();
+        reseeded.set_reseed_interval(Some(4));
+
+        assert!(outputs(&mut baseline, 4) == outputs(&mut reseeded, 4));
+        assert!(outputs(&mut baseline, 32) != outputs(&mut reseeded, 32));
+    }
+
+    #[test]
+    fn test_undersized_output() {
+        let grammar = test_grammar();
+        let buf = b"1 <<2 (3 4) <<5>> (6 <<7>>)>> 8 (9)";
+        let out_len = buf.len();
+
+        let full = outputs_sized(&mut new_session(&grammar, buf), 100, 4096);
+        let sized = outputs_sized(&mut new_session(&grammar, buf), 100, out_len);
+        assert!(full.iter().any(|output| output.len() > out_len));
+        for (full, sized) in full.iter().zip(sized.iter()) {
+            if full.len() > out_len {
+                assert!(sized.is_empty());
+            } else {
+                assert!(full == sized);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let grammar = test_grammar();
+        let buf = b"1 <<2 (3 4) <<5>> (6 <<7>>)>> 8 (9)";
+
+        let mut untraced = new_session(&grammar, buf);
+        outputs(&mut untraced, 10);
+        assert!(untraced.stats.is_none());
+
+        let mut session = new_session(&grammar, buf);
+        session.config.valid_actions = vec![Mutation::SwapDelim, Mutation::ShuffleRanges];
+        session.set_tracing(true);
+        outputs(&mut session, 100);
+        {
+            let stats = session.stats.as_ref().unwrap();
+            assert!(stats.keys().all(|mutation| *mutation == Mutation::SwapDelim || *mutation == Mutation::ShuffleRanges));
+            let total: usize = stats.values().sum();
+            assert!(total > 100 && total <= 100 * session.config.max_mutations);
+        }
+
+        let mut written = Vec::new();
+        session.write_stats(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert!(lines.len() == 2);
+        assert!(lines[0].starts_with("shuffle-ranges ") && lines[1].starts_with("swap-delim "));
+    }
+}
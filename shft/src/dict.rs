@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use libshft::error::*;
+
+// Parses the body of a quoted entry, handling `\\`, `\"` and `\xNN`.
+fn parse_entry(quoted: &str) -> Result<Vec<u8>> {
+    let mut entry = Vec::new();
+    let mut bytes = quoted.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            entry.push(b);
+            continue
+        }
+        match bytes.next() {
+            Some(b'\\') => entry.push(b'\\'),
+            Some(b'"') => entry.push(b'"'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let byte = match String::from_utf8(hex) {
+                    Ok(ref hex) if hex.len() == 2 => u8::from_str_radix(hex, 16).ok(),
+                    _ => None,
+                };
+                match byte {
+                    Some(byte) => entry.push(byte),
+                    None => bail!("Invalid \\x escape"),
+                }
+            },
+            _ => bail!("Invalid escape"),
+        }
+    }
+    Ok(entry)
+}
+
+// Parses an AFL-style dictionary: one `"value"` or `name="value"` per line,
+// with `#` comments and blank lines ignored.
+pub fn parse_dictionary(text: &str) -> Result<Vec<Vec<u8>>> {
+    let mut dictionary = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let (start, end) = match (line.find('"'), line.rfind('"')) {
+            (Some(start), Some(end)) if start < end && line.ends_with('"') => (start, end),
+            _ => bail!("line {}: expected a quoted value", index + 1),
+        };
+        let entry = parse_entry(&line[start+1..end]).chain_err(|| format!("line {}: invalid value", index + 1))?;
+        dictionary.push(entry)
+    }
+    Ok(dictionary)
+}
+
+pub fn read_dictionary<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<u8>>> {
+    let path = path.as_ref();
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text)).chain_err(|| format!("Could not read dictionary {}", path.display()))?;
+    parse_dictionary(&text).chain_err(|| format!("Invalid dictionary {}", path.display()))
+}
+
+#[test]
+fn test_parse_dictionary() {
+    let dictionary = parse_dictionary("# comment\n\nkw1=\"GET\"\n\"\\x00\\xffa\"\nquote@2=\"\\\"\\\\\"\n").unwrap();
+    assert!(dictionary == vec![b"GET".to_vec(), vec![0x00, 0xff, b'a'], b"\"\\".to_vec()]);
+
+    assert!(parse_dictionary("kw=GET\n").is_err());
+    assert!(parse_dictionary("\"\\x0\"\n").is_err());
+    assert!(parse_dictionary("\"\\q\"\n").is_err());
+}
@@ -4,32 +4,98 @@ extern crate error_chain;
 extern crate clap;
 extern crate rand;
 extern crate libshft;
+#[macro_use]
+extern crate serde_json;
+extern crate tar;
+#[cfg(feature = "gzip")]
+extern crate flate2;
 
+mod dict;
 mod output;
 
 use clap::{Arg, ArgMatches, App, SubCommand};
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 use rand::isaac;
-use std::io::{Read, Write};
-use std::fmt::Display;
-use std::fs::File;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::any::Any;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufWriter, Read, Write};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::process::Command;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use libshft::error::*;
-use libshft::grammar::Grammar;
-use libshft::parse::{ParsedFile, slurp};
+use libshft::grammar::{Grammar, GrammarDef};
+use libshft::parse::{DEFAULT_MAX_DEPTH, ParseConfig, ParsedFile, slurp_with, try_slurp_with};
 use libshft::fuzz;
 use output::OutputPattern;
 
 #[cfg(test)]
 mod test {
-    use libshft::grammar::{Grammar, GrammarDef};
-    use libshft::parse::slurp;
-    use libshft::fuzz::{FuzzFile, SliceSerializer};
+    use rand::{Rng, SeedableRng};
+    use rand::isaac;
+    use libshft::grammar::{Delim, DelimOptions, Endian, Grammar, GrammarDef};
+    use libshft::parse::{Node, OwnedParsedFile, ParseConfig, ParseStats, ParsedFile, RecoveryPolicy, StreamingParser, slurp, slurp_with, try_slurp_with};
+    use libshft::fuzz::{self, FuzzFile, Mutation, SliceSerializer};
+    use std::borrow::Cow;
+    use std::collections::hash_map::DefaultHasher;
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs::{self, File};
+    use std::hash::{Hash, Hasher};
+    use std::io::{Read, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+    use std::process;
+    use std::slice;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use output::OutputPattern;
+    use serde_json;
+    use tar;
+    use super::{app, check_inputs, check_invariants, do_fuzz, do_import, do_minimize, effective_jobs, fuzz_config, output_paths, parse_byte, read_file, read_input, write_manifest, DryRun, FuzzOptions};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shft-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_all(path: PathBuf) -> Vec<u8> {
+        let mut buf = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    // A single `<<`/`>>` delim, which most tests build on.
+    fn angle_grammar(whitespace: &[&[u8]]) -> Grammar {
+        Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+        ], whitespace.iter().map(|w| w.to_vec()).collect())
+    }
+
+    fn test_rng() -> isaac::Isaac64Rng {
+        isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4])
+    }
 
     fn roundtrip(grammar: &Grammar, buf: &[u8]) {
         let parsed_file = slurp(grammar, buf);
         println!("parsed = {:?}", parsed_file.dump());
+        // Parsing copies no token bytes out of the input.
+        for node in &parsed_file.nodes {
+            if let Node::Token(ref token) = *node {
+                assert!(match *token { Cow::Borrowed(_) => true, Cow::Owned(_) => false });
+            }
+        }
         let ff = FuzzFile::new(&parsed_file);
         {
             let mut serialized = Vec::new();
@@ -64,111 +130,3329 @@ mod test {
         roundtrip(&grammar, buf)
     }
 
+    #[test]
+    fn test_whitespace_tokens() {
+        let tokens = |grammar: &Grammar, buf: &[u8]| -> Vec<Vec<u8>> {
+            let parsed_file = slurp(grammar, buf);
+            parsed_file.root.iter().map(|noderef| match parsed_file.nodes[*noderef] {
+                Node::Token(ref token) => token.to_vec(),
+                _ => panic!("Unexpected node"),
+            }).collect()
+        };
+
+        let grammar = Grammar::new(vec![], vec![b" ".to_vec()]);
+        assert!(tokens(&grammar, b"1 2 3") == vec![b"1".to_vec(), b" ".to_vec(), b"2".to_vec(), b" ".to_vec(), b"3".to_vec()]);
+
+        let grammar = Grammar::new(vec![], vec![b"\r".to_vec(), b" ".to_vec(), b"\r\n".to_vec()]);
+        assert!(tokens(&grammar, b"1\r\n2") == vec![b"1".to_vec(), b"\r\n".to_vec(), b"2".to_vec()]);
+        roundtrip(&grammar, b"1\r\n2\r3 \r\n4");
+    }
+
     #[test]
     fn test_delim() {
-        let grammar = Grammar::new(vec![
-            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>']),
-        ], vec![]);
+        let grammar = angle_grammar(&[]);
         roundtrip(&grammar, b"1<<2<<3>>4>>5");
         roundtrip(&grammar, b"1<<2<<3>>4");
         roundtrip(&grammar, b"1<<2>>3>>4");
         roundtrip(&grammar, b"1<<2");
         roundtrip(&grammar, b"1>>2")
     }
-}
 
-fn read_file<P: AsRef<Path> + Display>(path: P) -> Result<Vec<u8>> {
-    let mut f = File::open(&path).chain_err(|| format!("Could not open input file {}", path))?;
-    let mut buffer = Vec::new();
+    #[test]
+    fn test_trailing_bytes() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Breaker(vec![b'/']),
+        ], vec![b" ".to_vec()]);
 
-    f.read_to_end(&mut buffer).chain_err(|| format!("Could not read input file {}", path))?;
-    Ok(buffer)
-}
+        for &(buf, tail) in &[
+            (&b"1 <<2>>tail"[..], &b"tail"[..]),
+            (&b"<<2>> /a\x00\x01"[..], &b"/a\x00\x01"[..]),
+            (&b"1 <<2 tail"[..], &b"tail"[..]),
+            (&b"1 >>tail"[..], &b"tail"[..]),
+        ] {
+            let parsed_file = slurp(&grammar, buf);
+            match parsed_file.nodes[*parsed_file.root.last().unwrap()] {
+                Node::Token(ref token) => assert!(&token[..] == tail),
+                _ => panic!("expected trailing token"),
+            }
+            roundtrip(&grammar, buf);
+        }
+    }
+
+    #[test]
+    fn test_max_nodes() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Breaker(vec![b',']),
+        ], vec![]);
+        let buf = vec![b','; 1_000_000];
+
+        let mut config = ParseConfig::new();
+        config.max_nodes = Some(100);
+        let parsed_file = slurp_with(&grammar, &buf, &config);
+        assert!(parsed_file.nodes.len() == 101);
+        match parsed_file.nodes[*parsed_file.root.last().unwrap()] {
+            Node::Token(ref token) => assert!(token.len() == buf.len() - 100),
+            _ => panic!("expected trailing token"),
+        }
 
-fn do_fuzz<'buf>(parsed_file: &ParsedFile<'buf>, pattern: &OutputPattern, num_iterations: usize, config: &fuzz::FuzzConfig) -> Result<()> {
-    let mut rng = isaac::Isaac64Rng::from_seed(&[1, 2, 3, 4]);
-    for i in 0..num_iterations {
-        let result = fuzz::fuzz_one(parsed_file, &mut rng, config);
+        let mut serialized = Vec::new();
+        FuzzFile::new(&parsed_file).serialize(&mut serialized);
+        assert!(serialized == buf);
+    }
+
+    #[test]
+    fn test_parse_max_depth() {
+        let grammar = angle_grammar(&[]);
+        let mut buf = b"<<".repeat(100_000);
+        buf.extend(b">>".repeat(100_000));
+
+        let parsed_file = slurp(&grammar, &buf);
+        assert!(parsed_file.stats().max_depth == 1024);
+
+        let mut config = ParseConfig::new();
+        config.max_depth = 2;
+        let parsed_file = slurp_with(&grammar, b"<<1<<2<<3>>4>>5>>", &config);
+        assert!(parsed_file.stats().max_depth == 2);
+        let mut serialized = Vec::new();
+        FuzzFile::new(&parsed_file).serialize(&mut serialized);
+        assert!(serialized == b"<<1<<2<<3>>4>>5>>");
+    }
+
+    #[test]
+    fn test_auto_close() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![]);
+        let mut config = ParseConfig::new();
+        config.auto_close = true;
 
-        if let Some(fuzzed_file) = result {
+        for &(buf, closed) in &[
+            (&b"1<<2"[..], &b"1<<2>>"[..]),
+            (&b"1<<2(3"[..], &b"1<<2(3)>>"[..]),
+            (&b"1<<2>>3"[..], &b"1<<2>>3"[..]),
+        ] {
+            let parsed_file = slurp_with(&grammar, buf, &config);
             let mut serialized = Vec::new();
-            fuzzed_file.serialize(&mut serialized);
+            FuzzFile::new(&parsed_file).serialize(&mut serialized);
+            assert!(serialized == closed);
+        }
+    }
 
-            let out_filename = pattern.with(i+1);
-            let mut file = File::create(&out_filename).chain_err(|| format!("Could not create output file {:?}", out_filename))?;
-            file.write_all(&serialized[..]).chain_err(|| format!("Could not write output file {:?}", out_filename))?;
+    #[test]
+    fn test_dangling_start() {
+        let grammar = angle_grammar(&[b" "]);
+
+        let parsed_file = slurp(&grammar, b"1<<");
+        assert!(parsed_file.root.len() == 2);
+        match parsed_file.nodes[parsed_file.root[1]] {
+            Node::Token(ref token) => assert!(&token[..] == b"<<"),
+            _ => panic!("expected start pattern token"),
+        }
+        for buf in &[&b"1<<"[..], b"<<", b"1 <<", b"1<<2<<", b"<<<<"] {
+            roundtrip(&grammar, buf);
+        }
+
+        let mut config = ParseConfig::new();
+        config.drop_dangling_start = true;
+        for &(buf, expected) in &[
+            (&b"1<<"[..], &b"1"[..]),
+            (&b"1 <<"[..], &b"1 "[..]),
+            (&b"1<<2<<"[..], &b"1<<2"[..]),
+            (&b"1<<<<"[..], &b"1"[..]),
+            (&b"1<<2"[..], &b"1<<2"[..]),
+        ] {
+            let parsed_file = slurp_with(&grammar, buf, &config);
+            let mut serialized = Vec::new();
+            FuzzFile::new(&parsed_file).serialize(&mut serialized);
+            assert!(serialized == expected);
         }
     }
-    Ok(())
-}
 
-fn lookup<'a>(matches: &'a ArgMatches, key: &str) -> &'a str {
-    matches.value_of(key).expect("impossible")
-}
+    #[test]
+    fn test_strict_recovery() {
+        let grammar = angle_grammar(&[]);
+        let mut config = ParseConfig::new();
+        config.recovery = RecoveryPolicy::Strict;
 
-fn go() -> Result<()> {
-    let app = App::new("super-happy-fuzz-time")
-        .arg(Arg::with_name("INPUT")
-            .help("File to fuzz")
-            .long("input")
-            .short("i")
-            .number_of_values(1)
-            .required(true))
-        .arg(Arg::with_name("CONFIG")
-            .help("Config file")
-            .long("config")
-            .short("c")
-            .number_of_values(1)
-            .required(true))
-        .subcommand(
-            SubCommand::with_name("dump")
-                .help("Parse and dump input file"))
-        .subcommand(
-            SubCommand::with_name("fuzz")
-                .help("Fuzz input file")
-                .arg(Arg::with_name("OUTPUT")
-                    .help("Output pattern")
-                    .long("output")
-                    .short("o")
-                    .number_of_values(1)
-                    .required(true))
-                .arg(Arg::with_name("ITERATIONS")
-                    .help("Number of files to generate")
-                    .long("num")
-                    .short("n")
-                    .number_of_values(1)
-                    .required(true)));
+        assert!(try_slurp_with(&grammar, b"1<<2<<3>>4>>5", &config).is_ok());
+        for &(buf, expected) in &[
+            (&b"1<<2"[..], "Unbalanced delimiter at offset 1"),
+            (&b"1>>2"[..], "Unbalanced delimiter at offset 1"),
+            (&b"1>>2<<<<3>>"[..], "Unbalanced delimiters at offsets 1, 4"),
+        ] {
+            match try_slurp_with(&grammar, buf, &config) {
+                Err(e) => assert!(e.to_string() == expected),
+                Ok(_) => panic!("expected unbalanced delimiter error"),
+            }
+            // The relaxed default still parses everything.
+            roundtrip(&grammar, buf);
+            slurp_with(&grammar, buf, &config);
+        }
 
-    let matches = app.clone().get_matches();
+        // Too deep input isn't reported as its leftover end patterns.
+        config.max_depth = 2;
+        match try_slurp_with(&grammar, b"<<1<<2<<3>>4>>5>>", &config) {
+            Err(e) => assert!(e.to_string() == "Delimiters nested deeper than max depth 2 at offset 6"),
+            Ok(_) => panic!("expected max depth error"),
+        }
+        assert!(slurp_with(&grammar, b"<<1<<2<<3>>4>>5>>", &config).stats().max_depth == 2);
+    }
 
-    let config_filename = lookup(&matches, "CONFIG");
-    let input_filename = lookup(&matches, "INPUT");
+    #[test]
+    fn test_case_insensitive() {
+        let mut grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"<div>".to_vec(), b"</div>".to_vec(), DelimOptions::default()),
+            GrammarDef::Breaker(b"br".to_vec()),
+        ], vec![]);
+        grammar.case_insensitive = true;
 
-    let grammar = Grammar::from_path(config_filename).chain_err(|| format!("Could not load config {}", config_filename))?;
+        let parsed_file = slurp(&grammar, b"<DIV>1BR2</div>");
+        assert!(parsed_file.root.len() == 1);
+        match parsed_file.nodes[parsed_file.root[0]] {
+            Node::Delim(ref delim, rangeref) => {
+                assert!(&delim.start_pattern[..] == b"<DIV>" && &delim.end_pattern[..] == b"</div>");
+                assert!(parsed_file.ranges[rangeref].len() == 2);
+            },
+            _ => panic!("expected delim"),
+        }
+        roundtrip(&grammar, b"<DIV>1BR2</div>");
+        roundtrip(&grammar, b"<dIv><Div>1</DIV>2</div>");
 
-    match matches.subcommand() {
-        ("dump", _) => {
-            let buf = read_file(input_filename)?;
-            let parsed_file = slurp(&grammar, &buf);
-            println!("{}", parsed_file.dump());
-        },
-        ("fuzz", Some(fuzz_matches)) => {
-            let output = lookup(fuzz_matches, "OUTPUT");
-            let iterations = lookup(fuzz_matches, "ITERATIONS");
-            let num_iterations = usize::from_str(iterations).chain_err(|| format!("Invalid iterations: {}", iterations))?;
-            let pattern = OutputPattern::from_path(output).chain_err(|| format!("Invalid output pattern: {}", output))?;
-            let config = fuzz::FuzzConfig {
-                max_mutations: 5,
-                max_duplications: 5,
-                valid_actions: fuzz::default_mutations(),
-                all_delims: grammar.delims(),
-            };
-            let buf = read_file(input_filename)?;
-            let parsed_file = slurp(&grammar, &buf);
-            do_fuzz(&parsed_file, &pattern, num_iterations, &config).chain_err(|| "Error fuzzing input file")?;
+        let mut grammar = Grammar::new(vec![
+            GrammarDef::Breaker(b"SELECT".to_vec()),
+            GrammarDef::Tokenizer(b"FROM".to_vec(), None),
+        ], vec![b" ".to_vec()]);
+        grammar.case_insensitive = true;
+        let parsed_file = slurp(&grammar, b"x select a from b");
+        let tokens: Vec<&[u8]> = parsed_file.root.iter().filter_map(|noderef| match parsed_file.nodes[*noderef] {
+            Node::Token(ref token) if &token[..] != b" " => Some(&token[..]),
+            _ => None,
+        }).collect();
+        assert!(tokens == vec![&b"x"[..], b"select", b"a", b"from", b"b"]);
+        roundtrip(&grammar, b"x select a from b");
+    }
+
+    #[test]
+    fn test_def_ordering() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Breaker(vec![b'<']),
+            GrammarDef::Delim(vec![b'<'], vec![b'>'], DelimOptions::default()),
+        ], vec![]);
+        assert!(grammar.defs[0] == GrammarDef::Delim(vec![b'<'], vec![b'>'], DelimOptions::default()));
+        assert!(grammar.defs[1] == GrammarDef::Breaker(vec![b'<']));
+
+        let parsed_file = slurp(&grammar, b"a<b>c");
+        assert!(parsed_file.root.len() == 3);
+        match parsed_file.nodes[parsed_file.root[1]] {
+            Node::Delim(ref delim, _) => assert!(&delim.start_pattern[..] == b"<"),
+            _ => panic!("expected delim"),
+        }
+        roundtrip(&grammar, b"a<b>c");
+    }
+
+    #[test]
+    fn test_lexer() {
+        let mut grammar = Grammar::new(vec![
+            GrammarDef::Breaker(b".".to_vec()),
+            GrammarDef::Breaker(b"-".to_vec()),
+        ], vec![b" ".to_vec()]);
+        grammar.lexer = Some(Box::new(|buf: &[u8]| {
+            // A signed decimal number with an optional exponent.
+            let digits = |start: usize| start + buf[start..].iter().take_while(|b| b.is_ascii_digit()).count();
+            let mut i = if buf.first() == Some(&b'-') { 1 } else { 0 };
+            let int_end = digits(i);
+            if int_end == i {
+                return None
+            }
+            i = int_end;
+            if buf.get(i) == Some(&b'.') && digits(i + 1) > i + 1 {
+                i = digits(i + 1);
+            }
+            if buf.get(i) == Some(&b'e') && digits(i + 1) > i + 1 {
+                i = digits(i + 1);
+            }
+            Some(i)
+        }));
+
+        let parsed_file = slurp(&grammar, b"x -1.5e3 y");
+        let tokens: Vec<Vec<u8>> = parsed_file.root.iter().map(|noderef| {
+            match parsed_file.nodes[*noderef] {
+                Node::Token(ref token) => token.to_vec(),
+                _ => panic!("expected token"),
+            }
+        }).collect();
+        assert!(tokens == vec![b"x".to_vec(), b" ".to_vec(), b"-1.5e3".to_vec(), b" ".to_vec(), b"y".to_vec()]);
+        roundtrip(&grammar, b"x -1.5e3 y");
+        roundtrip(&grammar, b"a.b-c 1.2.3");
+    }
+
+    #[test]
+    fn test_spans() {
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1 <<2 <<3>> 4>> 5";
+        let parsed_file = slurp(&grammar, buf);
+        assert!(parsed_file.spans.len() == parsed_file.nodes.len());
+        for noderef in 0..parsed_file.nodes.len() {
+            let span = parsed_file.span(noderef);
+            assert!(&buf[span] == &parsed_file.subtree_bytes(noderef)[..]);
+        }
+
+        let outer = parsed_file.root[2];
+        assert!(parsed_file.span(outer) == (2..15));
+        let dump = parsed_file.dump();
+        assert!(dump.starts_with("\"1\" @ 0..1\n\" \" @ 1..2\n\"<<\" @ 2..15 {\n"));
+        assert!(dump.contains("        \"3\" @ 8..9\n"));
+
+        let mut config = ParseConfig::new();
+        config.auto_close = true;
+        let parsed_file = slurp_with(&grammar, b"1 <<2 <<3", &config);
+        assert!(parsed_file.span(parsed_file.root[2]) == (2..9));
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let grammar = angle_grammar(&[]);
+        let depth = 100000;
+        let buf = [b"<<".repeat(depth), b"x".to_vec(), b">>".repeat(depth)].concat();
+        let mut config = ParseConfig::new();
+        config.max_depth = depth;
+        let parsed_file = slurp_with(&grammar, &buf, &config);
+        let ff = FuzzFile::new(&parsed_file);
+        assert!(ff.depth() == depth);
+        let mut serialized = Vec::new();
+        ff.serialize(&mut serialized);
+        assert!(serialized == buf);
+        assert!(ff.serialized_len() == buf.len());
+        parsed_file.structural_hash();
+
+        let dump = parsed_file.dump();
+        assert!(dump.lines().count() == 2 * depth + 1);
+        assert!(dump.contains("\"x\" @ 200000..200001"));
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let defs = || vec![
+            GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"//".to_vec(), b"\n".to_vec(), DelimOptions { non_nesting: true, ..DelimOptions::default() }),
+            GrammarDef::Delim(b"Begin".to_vec(), b"End".to_vec(), DelimOptions::default()),
+            GrammarDef::Breaker(b",".to_vec()),
+            GrammarDef::Tokenizer(b";".to_vec(), None),
+        ];
+        let whitespace = || vec![b" ".to_vec(), b"\r\n".to_vec()];
+        let alphabet = b"<>(),;/ \r\nabBEGINend";
+        let mut rng = test_rng();
+
+        for case_insensitive in vec![false, true] {
+            let mut grammar = Grammar::new(defs(), whitespace());
+            let mut linear = Grammar::new(defs(), whitespace());
+            assert!(grammar.prefilter.is_some());
+            linear.prefilter = None;
+            grammar.case_insensitive = case_insensitive;
+            linear.case_insensitive = case_insensitive;
+
+            for _ in 0..100 {
+                let buf: Vec<u8> = (0..200).map(|_| *rng.choose(alphabet).unwrap()).collect();
+                assert!(slurp(&grammar, &buf).dump() == slurp(&linear, &buf).dump());
+                roundtrip(&grammar, &buf[..128]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_parser() {
+        let grammars = vec![
+            (Grammar::new(vec![
+                GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+                GrammarDef::Delim(b"\"".to_vec(), b"\"".to_vec(), DelimOptions { non_nesting: true, escape: Some(b"\\".to_vec()) }),
+                GrammarDef::Delim(b"Begin".to_vec(), b"End".to_vec(), DelimOptions::default()),
+                GrammarDef::Breaker(b",".to_vec()),
+                GrammarDef::Tokenizer(b";".to_vec(), None),
+            ], vec![b" ".to_vec(), b"\r\n".to_vec()]), &b"<>\",;\\ \r\nBeginEnd"[..]),
+            (Grammar::new(vec![
+                GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+                GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+            ], vec![]), &b"()\x00\x01\x03ab"[..]),
+        ];
+        let mut rng = test_rng();
+
+        for (grammar, alphabet) in grammars {
+            for _ in 0..50 {
+                let buf: Vec<u8> = (0..200).map(|_| *rng.choose(alphabet).unwrap()).collect();
+                let expected = slurp(&grammar, &buf);
+                for chunk_size in vec![1, 2, 3, 7, 64, 1024] {
+                    let mut parser = StreamingParser::new(&grammar, &buf[..]);
+                    parser.chunk_size = chunk_size;
+                    let parsed_file = parser.parse().unwrap();
+                    assert!(parsed_file.dump() == expected.dump());
+                    assert!(parsed_file.nodes.iter().all(|node| match *node {
+                        Node::Token(Cow::Borrowed(_)) => false,
+                        _ => true,
+                    }));
+                }
+            }
+        }
+
+        // A delimiter split across two reads still matches.
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"Begin".to_vec(), b"End".to_vec(), DelimOptions::default()),
+        ], vec![]);
+        let mut parser = StreamingParser::new(&grammar, &b"xBeginyEndz"[..]);
+        parser.chunk_size = 3;
+        let parsed_file = parser.parse().unwrap();
+        assert!(parsed_file.root.len() == 3 && parsed_file.ranges.len() == 1);
+
+        // A long token spans many reads, each only scanning the new input
+        // rather than the whole token again.
+        let grammar = angle_grammar(&[b" "]);
+        let mut buf = b"1 <<".to_vec();
+        let len = buf.len() + 4 * 1024 * 1024;
+        buf.resize(len, b'x');
+        buf.extend_from_slice(b">> 2");
+        let mut parser = StreamingParser::new(&grammar, &buf[..]);
+        parser.chunk_size = 16;
+        let parsed_file = parser.parse().unwrap();
+        assert!(parsed_file.dump() == slurp(&grammar, &buf).dump());
+    }
+
+    #[test]
+    fn test_comments() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+            GrammarDef::Comment(b"#".to_vec(), b"\n".to_vec()),
+            GrammarDef::Comment(b"/*".to_vec(), b"*/".to_vec()),
+        ], vec![b" ".to_vec()]);
+        let buf = b"a (b # c ) d\n e) /* ( # */ f /* (";
+        roundtrip(&grammar, buf);
+
+        let parsed_file = slurp(&grammar, buf);
+        let root_tokens: Vec<&[u8]> = parsed_file.root.iter().filter_map(|noderef| match parsed_file.nodes[*noderef] {
+            Node::Token(ref token) if &token[..] != b" " => Some(&token[..]),
+            _ => None,
+        }).collect();
+        assert!(root_tokens == vec![&b"a"[..], b"/* ( # */", b"f", b"/* ("]);
+        match parsed_file.nodes[parsed_file.root[2]] {
+            Node::Delim(_, rangeref) => {
+                let range = &parsed_file.ranges[rangeref];
+                assert!(range.iter().any(|noderef| match parsed_file.nodes[*noderef] {
+                    Node::Token(ref token) => &token[..] == b"# c ) d\n",
+                    _ => false,
+                }));
+            },
+            _ => panic!("expected delim"),
+        }
+
+        for chunk_size in vec![1, 2, 3, 64] {
+            let mut parser = StreamingParser::new(&grammar, &buf[..]);
+            parser.chunk_size = chunk_size;
+            assert!(parser.parse().unwrap().dump() == parsed_file.dump());
+        }
+
+        // Comment starts that lose to a longer delim never search for their
+        // end, which here would take a pass over the rest of the input each.
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"#(".to_vec(), b")".to_vec(), DelimOptions::default()),
+            GrammarDef::Comment(b"#".to_vec(), b"\n".to_vec()),
+        ], vec![]);
+        let mut buf = Vec::new();
+        for _ in 0..50000 {
+            buf.extend_from_slice(b"#(x)");
+        }
+        buf.extend_from_slice(b"# end\nx");
+        let parsed_file = slurp(&grammar, &buf);
+        assert!(parsed_file.ranges.len() == 50000);
+        assert!(parsed_file.root.len() == 50002);
+        let mut serialized = Vec::new();
+        FuzzFile::new(&parsed_file).serialize(&mut serialized);
+        assert!(serialized == buf);
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1 <<2 <<3>> 4>> 5".to_vec();
+        let expected = slurp(&grammar, &buf).dump();
+        let parsed_file: Arc<OwnedParsedFile> = Arc::new(slurp(&grammar, &buf).into_owned());
+        drop(buf);
+        assert!(parsed_file.dump() == expected);
+
+        let delims = grammar.delims().into_iter().map(Delim::into_owned).collect();
+        let config = Arc::new(fuzz::FuzzConfig::new(delims));
+        let workers: Vec<_> = (0..4).map(|seed| {
+            let parsed_file = parsed_file.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                let mut rng = isaac::Isaac64Rng::from_seed(&[seed]);
+                fuzz::fuzz_one(&parsed_file, &mut rng, &config).map(|ff| ff.serialized_len())
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        let string_options = DelimOptions { non_nesting: true, escape: Some(b"\\".to_vec()) };
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"\"".to_vec(), b"\"".to_vec(), string_options),
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        ], vec![b" ".to_vec()]);
+        let contents = |parsed_file: &ParsedFile| -> Vec<Vec<u8>> {
+            parsed_file.root.iter().filter_map(|noderef| match parsed_file.nodes[*noderef] {
+                Node::Delim(..) => Some(parsed_file.subtree_bytes(*noderef)),
+                _ => None,
+            }).collect()
+        };
+
+        let buf = br#"a "b\"c" d "e\\" f "g\\\"h" i"#;
+        let parsed_file = slurp(&grammar, buf);
+        assert!(contents(&parsed_file) == vec![br#""b\"c""#.to_vec(), br#""e\\""#.to_vec(), br#""g\\\"h""#.to_vec()]);
+        roundtrip(&grammar, buf);
+
+        // The escape only applies inside the delim that declared it.
+        let buf = br#"(a\) "b\)" c)"#;
+        let parsed_file = slurp(&grammar, buf);
+        assert!(contents(&parsed_file) == vec![br#"(a\)"#.to_vec(), br#""b\)""#.to_vec()]);
+        roundtrip(&grammar, buf);
+
+        let buf = br#"("x\" y")"#;
+        let parsed_file = slurp(&grammar, buf);
+        assert!(contents(&parsed_file) == vec![buf.to_vec()]);
+        assert!(parsed_file.ranges.len() == 2);
+        roundtrip(&grammar, buf);
+    }
+
+    #[test]
+    fn test_longest_match() {
+        let defs = || vec![
+            GrammarDef::Delim(b"<".to_vec(), b">".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"<<".to_vec(), b">>".to_vec(), DelimOptions::default()),
+            GrammarDef::Tokenizer(b"<=".to_vec(), None),
+        ];
+        let grammar = Grammar::new(defs(), vec![b" ".to_vec()]);
+        let mut linear = Grammar::new(defs(), vec![b" ".to_vec()]);
+        linear.prefilter = None;
+        let starts = |parsed_file: &ParsedFile| -> Vec<Vec<u8>> {
+            parsed_file.root.iter().filter_map(|noderef| match parsed_file.nodes[*noderef] {
+                Node::Delim(ref delim, _) => Some(delim.start_pattern.to_vec()),
+                Node::Token(ref token) if &token[..] != b" " => Some(token.to_vec()),
+                _ => None,
+            }).collect()
+        };
+
+        let buf = b"<<a>> <b> a<=b";
+        let parsed_file = slurp(&grammar, buf);
+        assert!(starts(&parsed_file) == vec![b"<<".to_vec(), b"<".to_vec(), b"a".to_vec(), b"<=".to_vec(), b"b".to_vec()]);
+        assert!(parsed_file.dump() == slurp(&linear, buf).dump());
+        roundtrip(&grammar, buf);
+
+        // `<<` opens the longer delim, so a lone `>` is just content.
+        let parsed_file = slurp(&grammar, b"<<a>b>>");
+        assert!(parsed_file.root.len() == 1);
+        assert!(parsed_file.subtree_bytes(parsed_file.root[0]) == b"<<a>b>>".to_vec());
+
+        // `>>` is longer, but only `>` closes what is open.
+        let buf = b"a<b<c>>";
+        let parsed_file = slurp(&grammar, buf);
+        assert!(starts(&parsed_file) == vec![b"a".to_vec(), b"<".to_vec()]);
+        assert!(parsed_file.subtree_bytes(parsed_file.root[1]) == buf[1..].to_vec());
+        assert!(parsed_file.stats().max_depth == 2);
+        assert!(parsed_file.dump() == slurp(&linear, buf).dump());
+        roundtrip(&grammar, buf);
+    }
+
+    #[test]
+    fn test_non_nesting() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"//".to_vec(), b"\n".to_vec(), DelimOptions { non_nesting: true, ..DelimOptions::default() }),
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+        ], vec![b" ".to_vec()]);
+
+        let parsed_file = slurp(&grammar, b"// a // b\n");
+        assert!(parsed_file.root.len() == 1);
+        match parsed_file.nodes[parsed_file.root[0]] {
+            Node::Delim(ref delim, rangeref) => {
+                assert!(&delim.start_pattern[..] == b"//" && &delim.end_pattern[..] == b"\n");
+                for noderef in &parsed_file.ranges[rangeref] {
+                    match parsed_file.nodes[*noderef] {
+                        Node::Token(_) => (),
+                        _ => panic!("expected only tokens in comment"),
+                    }
+                }
+            },
+            _ => panic!("expected delim"),
+        }
+        roundtrip(&grammar, b"// a // b\n");
+
+        // Other delims still nest, and the comment start stays literal inside them.
+        let parsed_file = slurp(&grammar, b"// (a // b)\n");
+        assert!(parsed_file.root.len() == 1);
+        assert!(parsed_file.ranges.len() == 2);
+        roundtrip(&grammar, b"// (a // b)\n");
+    }
+
+    #[test]
+    fn test_non_nesting_quotes() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"'".to_vec(), b"'".to_vec(), DelimOptions { non_nesting: true, ..DelimOptions::default() }),
+        ], vec![b" ".to_vec()]);
+        // An inner quote closes the open one, rather than nesting.
+        let parsed_file = slurp(&grammar, b"'a'b'c'");
+        assert!(parsed_file.ranges.len() == 2);
+        let quoted: Vec<Vec<u8>> = parsed_file.root.iter().filter_map(|noderef| match parsed_file.nodes[*noderef] {
+            Node::Delim(..) => Some(parsed_file.subtree_bytes(*noderef)),
+            _ => None,
+        }).collect();
+        assert!(quoted == vec![b"'a'".to_vec(), b"'c'".to_vec()]);
+        roundtrip(&grammar, b"'a'b'c'");
+        roundtrip(&grammar, b"echo 'a b' 'c' 'unclosed");
+    }
+
+    #[test]
+    fn test_apply_all_positions() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![]);
+        let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5(6)");
+        let ff = FuzzFile::new(&parsed_file);
+
+        let variants = |mutation: &Mutation| -> Vec<Vec<u8>> {
+            let mut variants: Vec<Vec<u8>> = ff.apply_all_positions(mutation).unwrap().map(|variant| {
+                let mut serialized = Vec::new();
+                variant.serialize(&mut serialized);
+                serialized
+            }).collect();
+            variants.sort();
+            variants
+        };
+        for mutation in &[Mutation::EmptyDelim, Mutation::NestDelim, Mutation::RemoveDelim, Mutation::SwapDelim] {
+            let mut variants = variants(mutation);
+            assert!(variants.len() == 3);
+            variants.dedup();
+            assert!(variants.len() == 3);
+        }
+
+        // One variant per range, each only changing that range.
+        let reversed = variants(&Mutation::ReverseRange);
+        assert!(reversed.len() == parsed_file.ranges.len());
+        assert!(reversed == vec![b"1<<2<<3>>4>>5(6)".to_vec(), b"1<<2<<3>>4>>5(6)".to_vec(), b"1<<4<<3>>2>>5(6)".to_vec()]);
+        let duplicated = variants(&Mutation::DuplicateRange);
+        assert!(duplicated.len() == parsed_file.ranges.len());
+        assert!(duplicated == vec![b"1<<2<<33>>4>>5(6)".to_vec(), b"1<<2<<3>>42<<3>>4>>5(6)".to_vec(), b"1<<2<<3>>4>>5(66)".to_vec()]);
+
+        // Mutations with other random choices can't be enumerated.
+        assert!(ff.apply_all_positions(&Mutation::ShuffleRanges).is_none());
+        assert!(ff.apply_all_positions(&Mutation::RepeatDelim).is_none());
+    }
+
+    #[test]
+    fn test_inject_extra_close() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![]);
+        let mut rng = test_rng();
+
+        let parsed_file = slurp(&grammar, b"1<<2>>3");
+        let mut ff = FuzzFile::new(&parsed_file);
+        assert!(ff.inject_extra_close(&mut rng));
+        let mut serialized = Vec::new();
+        ff.serialize(&mut serialized);
+        assert!(serialized == b"1<<2>>>>3");
+
+        let parsed_file = slurp(&grammar, b"(<<a>>)");
+        for _ in 0..10 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.inject_extra_close(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized == b"(<<a>>>>)" || serialized == b"(<<a>>))");
+        }
+
+        let parsed_file = slurp(&grammar, b"123");
+        assert!(!FuzzFile::new(&parsed_file).inject_extra_close(&mut rng));
+    }
+
+    #[test]
+    fn test_intensity() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"<<1>>");
+        let mut rng = test_rng();
+
+        let mut average_len = |intensity| {
+            let mut config = fuzz::FuzzConfig::new(grammar.delims());
+            config.max_mutations = 1;
+            config.max_duplications = 10;
+            config.valid_actions = vec![Mutation::DuplicateRange];
+            config.intensity = intensity;
+
+            let mut total = 0;
+            for _ in 0..200 {
+                let ff = fuzz::fuzz_one(&parsed_file, &mut rng, &config).unwrap();
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                total += serialized.len();
+            }
+            total / 200
+        };
+
+        let low = average_len(0.1);
+        let high = average_len(1.0);
+        assert!(low == 6);
+        assert!(high > low + 2);
+    }
+
+    #[test]
+    fn test_prefer_small_ranges() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![b" ".to_vec()]);
+        let parsed_file = slurp(&grammar, b"<<a>>(b c d e f g h i j k)");
+        let mut rng = test_rng();
+
+        let mut small_fraction = |prefer_small_ranges| {
+            let mut config = fuzz::FuzzConfig::new(grammar.delims());
+            config.max_mutations = 1;
+            config.max_duplications = 1;
+            config.valid_actions = vec![Mutation::DuplicateRange];
+            config.prefer_small_ranges = prefer_small_ranges;
+
+            let mut small = 0;
+            for _ in 0..1000 {
+                let ff = fuzz::fuzz_one(&parsed_file, &mut rng, &config).unwrap();
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                if serialized.starts_with(b"<<aa>>") {
+                    small += 1
+                }
+            }
+            small as f64 / 1000.0
+        };
+
+        let uniform = small_fraction(false);
+        let weighted = small_fraction(true);
+        assert!(uniform > 0.4 && uniform < 0.6);
+        assert!(weighted > 0.8);
+    }
+
+    #[test]
+    fn test_content_only() {
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1234 <<abcd <<xy>>>> 5678";
+        let parsed_file = slurp(&grammar, buf);
+        let mut rng = test_rng();
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.content_only = true;
+
+        let mut num_changed = 0;
+        for _ in 0..100 {
+            if let Some(ff) = fuzz::fuzz_one(&parsed_file, &mut rng, &config) {
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                assert!(serialized.len() == buf.len());
+                for &(start, end) in &[(5, 7), (12, 14), (16, 20)] {
+                    assert!(serialized[start..end] == buf[start..end]);
+                }
+                if &serialized[..] != &buf[..] {
+                    num_changed += 1
+                }
+            }
+        }
+        assert!(num_changed > 50);
+    }
+
+    #[test]
+    fn test_presets() {
+        let grammar = angle_grammar(&[]);
+        let configs: Vec<_> = ["text", "binary", "structured"].iter().map(|preset| {
+            let matches = app().get_matches_from(vec!["shft", "fuzz", "-o", "out/{}", "-n", "1", "--preset", preset]);
+            fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap()
+        }).collect();
+
+        for config in &configs {
+            assert!(!config.valid_actions.is_empty());
+            assert!(config.valid_actions.iter().all(|mutation| fuzz::default_mutations().contains(mutation)));
+            assert!(config.max_mutations > 0 && config.max_duplications > 0);
+        }
+        for (i, a) in configs.iter().enumerate() {
+            for b in &configs[i+1..] {
+                assert!(a.valid_actions != b.valid_actions);
+            }
+        }
+        assert!(configs[2].valid_actions.iter().all(|mutation| !mutation.is_content()));
+    }
+
+    #[test]
+    fn test_randomize_token() {
+        let grammar = angle_grammar(&[]);
+        let buf = [&[b'a'; 64][..], b"<<", &[b'b'; 64][..], b">>", &[b'c'; 64][..]].concat();
+        let parsed_file = slurp(&grammar, &buf);
+        let mut rng = test_rng();
+
+        for _ in 0..10 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.randomize_token(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+
+            assert!(serialized.len() == buf.len());
+            assert!(&serialized[64..66] == b"<<" && &serialized[130..132] == b">>");
+            let changed = [(0, 64), (66, 130), (132, 196)].iter().filter(|&&(start, end)| {
+                serialized[start..end] != buf[start..end]
+            }).count();
+            assert!(changed == 1);
+        }
+    }
+
+    #[test]
+    fn test_permute_bytes() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"aaaa<<abcdef>>zzzz");
+        let mut rng = test_rng();
+
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.permute_bytes(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+
+            assert!(&serialized[..6] == b"aaaa<<" && &serialized[12..] == b">>zzzz");
+            let permuted = &serialized[6..12];
+            assert!(permuted != b"abcdef");
+            let mut sorted = permuted.to_vec();
+            sorted.sort();
+            assert!(sorted == b"abcdef");
+        }
+
+        let parsed_file = slurp(&grammar, b"aaaa<<bb>>");
+        assert!(!FuzzFile::new(&parsed_file).permute_bytes(&mut rng));
+        // Single-byte tokens can't be shuffled.
+        let parsed_file = slurp(&grammar, b"a<<b>>c");
+        assert!(!FuzzFile::new(&parsed_file).permute_bytes(&mut rng));
+
+        // `jumble-token` is accepted as another name for it.
+        for args in vec![vec!["--only", "jumble-token"], vec!["--mutations", "jumble-token"]] {
+            let mut argv = vec!["shft", "fuzz", "-n", "1", "-o", "out/{}"];
+            argv.extend(args);
+            let matches = app().get_matches_from_safe(argv).unwrap();
+            let config = fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap();
+            assert!(config.valid_actions == vec![Mutation::PermuteBytes]);
+        }
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let dir = temp_dir("tokenizer");
+        let config_path = dir.join("config.yml");
+        fs::write(&config_path, "delims: []\nbreaks: []\nwhitespace: []\ntokenizers: [';']\n").unwrap();
+        let grammar = Grammar::from_path(config_path.to_str().unwrap()).unwrap();
+        assert!(grammar.defs == vec![GrammarDef::Tokenizer(b";".to_vec(), None)]);
+
+        let parsed_file = slurp(&grammar, b"a;b");
+        let tokens: Vec<Vec<u8>> = parsed_file.root.iter().map(|noderef| match parsed_file.nodes[*noderef] {
+            Node::Token(ref token) => token.to_vec(),
+            _ => panic!("Unexpected node"),
+        }).collect();
+        assert!(tokens == vec![b"a".to_vec(), b";".to_vec(), b"b".to_vec()]);
+
+        roundtrip(&grammar, b"a;b");
+        roundtrip(&grammar, b";;a;;b;");
+    }
+
+    #[test]
+    fn test_escaped_tokenizer() {
+        let dir = temp_dir("escaped-tokenizer");
+        let config_path = dir.join("config.yml");
+        fs::write(&config_path, "delims: []\nbreaks: []\nwhitespace: [' ']\ntokenizers: [{ pattern: ',', escape: '\\' }]\n").unwrap();
+        let grammar = Grammar::from_path(config_path.to_str().unwrap()).unwrap();
+        assert!(grammar.defs == vec![GrammarDef::Tokenizer(b",".to_vec(), Some(b"\\".to_vec()))]);
+
+        let tokens = |buf: &[u8]| -> Vec<Vec<u8>> {
+            let parsed_file = slurp(&grammar, buf);
+            let mut streaming = StreamingParser::new(&grammar, buf);
+            streaming.chunk_size = 1;
+            assert!(streaming.parse().unwrap().dump() == parsed_file.dump());
+            parsed_file.root.iter().map(|noderef| match parsed_file.nodes[*noderef] {
+                Node::Token(ref token) => token.to_vec(),
+                _ => panic!("Unexpected node"),
+            }).collect()
+        };
+        assert!(tokens(b"a,b") == vec![b"a".to_vec(), b",".to_vec(), b"b".to_vec()]);
+        assert!(tokens(b"a\\,b,c") == vec![b"a\\,b".to_vec(), b",".to_vec(), b"c".to_vec()]);
+        // An escaped escape doesn't escape the separator.
+        assert!(tokens(b"a\\\\,b") == vec![b"a\\\\".to_vec(), b",".to_vec(), b"b".to_vec()]);
+        // The escape can end an earlier token.
+        assert!(tokens(b"x \\,y") == vec![b"x".to_vec(), b" ".to_vec(), b"\\,y".to_vec()]);
+
+        roundtrip(&grammar, b"a\\,b,c\\\\,d");
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+        ], vec![]);
+        let buf = b"\x03abc\x00\x02de\x05xy";
+        let parsed_file = slurp(&grammar, buf);
+        assert!(parsed_file.root.len() == 4);
+        let payloads: Vec<Vec<u8>> = parsed_file.root.iter().map(|noderef| match parsed_file.nodes[*noderef] {
+            Node::LengthPrefixed(_, rangeref) => parsed_file.ranges[rangeref].iter().flat_map(|noderef| parsed_file.subtree_bytes(*noderef)).collect(),
+            Node::Token(ref token) => token.to_vec(),
+            _ => panic!("Unexpected node"),
+        }).collect();
+        // The last record claims more payload than there is, so it's left as a token.
+        assert!(payloads == vec![b"abc".to_vec(), b"".to_vec(), b"de".to_vec(), b"\x05xy".to_vec()]);
+
+        let mut serialized = Vec::new();
+        FuzzFile::new(&parsed_file).serialize(&mut serialized);
+        assert!(&serialized[..] == &buf[..]);
+    }
+
+    #[test]
+    fn test_resize_payload() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+        ], vec![]);
+        let parsed_file = slurp(&grammar, b"\x03abc");
+        let mut rng = test_rng();
+
+        let mut lengths = Vec::new();
+        for _ in 0..50 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            if !ff.resize_payload(&mut rng) {
+                continue
+            }
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized[0] as usize == serialized.len() - 1);
+            assert!(serialized.len() - 1 != 3);
+            lengths.push(serialized.len() - 1);
+        }
+        assert!(lengths.iter().any(|len| *len < 3) && lengths.iter().any(|len| *len > 3));
+
+        let parsed_file = slurp(&grammar, b"");
+        assert!(!FuzzFile::new(&parsed_file).resize_payload(&mut rng));
+    }
+
+    #[test]
+    fn test_transpose_delim_bytes() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(b"<!".to_vec(), b"!>".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"(".to_vec(), b")".to_vec(), DelimOptions::default()),
+            GrammarDef::Delim(b"[[".to_vec(), b"]]".to_vec(), DelimOptions::default()),
+        ], vec![]);
+        let parsed_file = slurp(&grammar, b"a<!b(c)[[d]]e!>f");
+        let mut rng = test_rng();
+
+        let mut seen = Vec::new();
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.transpose_delim_bytes(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized == b"a!<b(c)[[d]]e!>f" || serialized == b"a<!b(c)[[d]]e>!f");
+            seen.push(serialized);
+        }
+        assert!(seen.iter().any(|serialized| serialized[1] == b'!') && seen.iter().any(|serialized| serialized[1] == b'<'));
+
+        let parsed_file = slurp(&grammar, b"a(b)[[c]]");
+        assert!(!FuzzFile::new(&parsed_file).transpose_delim_bytes(&mut rng));
+    }
+
+    #[test]
+    fn test_repeat_delim() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2>> 3");
+        let mut rng = test_rng();
+
+        let mut depths = Vec::new();
+        for _ in 0..50 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.repeat_delim(&mut rng, 8));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            let depth = (serialized.len() - b"1 <<2>> 3".len()) / 4;
+            assert!(depth >= 1 && depth <= 8);
+            let expected = format!("1 {}2{} 3", "<<".repeat(depth + 1), ">>".repeat(depth + 1));
+            assert!(serialized == expected.as_bytes());
+            assert!(ff.depth() == depth + 1);
+            depths.push(depth);
+        }
+        assert!(depths.iter().any(|depth| *depth > 1));
+
+        assert!(!FuzzFile::new(&parsed_file).repeat_delim(&mut rng, 0));
+        let parsed_file = slurp(&grammar, b"1 2 3");
+        assert!(!FuzzFile::new(&parsed_file).repeat_delim(&mut rng, 8));
+    }
+
+    #[test]
+    fn test_flip_bits() {
+        let grammar = angle_grammar(&[b" "]);
+        let input = b"12 <<34>>";
+        let parsed_file = slurp(&grammar, input);
+        let mut rng = test_rng();
+
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.flip_bits(&mut rng, 1));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized.len() == input.len());
+            let flipped: u32 = serialized.iter().zip(input.iter()).map(|(a, b)| (a ^ b).count_ones()).sum();
+            assert!(flipped == 1);
+        }
+
+        assert!(!FuzzFile::new(&parsed_file).flip_bits(&mut rng, 0));
+        let parsed_file = slurp(&grammar, b"<<>>");
+        assert!(!FuzzFile::new(&parsed_file).flip_bits(&mut rng, 8));
+    }
+
+    #[test]
+    fn test_fuzzer() {
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1 <<2 <<3>> 4>> 5";
+        let fuzzer = fuzz::Fuzzer::new(slurp(&grammar, buf), fuzz::FuzzConfig::new(grammar.delims()), test_rng());
+        let outputs: Vec<Vec<u8>> = fuzzer.take(10).collect();
+
+        // The same as driving `fuzz_one` by hand with the same seed.
+        let parsed_file = slurp(&grammar, buf);
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let mut rng = test_rng();
+        let mut expected = Vec::new();
+        while expected.len() < 10 {
+            if let Some(ff) = fuzz::fuzz_one(&parsed_file, &mut rng, &config) {
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                expected.push(serialized);
+            }
+        }
+        assert!(outputs == expected);
+
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::RemoveDelim];
+        let mut fuzzer = fuzz::Fuzzer::new(slurp(&grammar, b"1 2"), config, test_rng());
+        assert!(fuzzer.parsed().root.len() == 3);
+        assert!(fuzzer.next().is_none());
+    }
+
+    #[test]
+    fn test_swap_tokens() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2>>");
+        let mut rng = test_rng();
+
+        let mut seen = Vec::new();
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.swap_tokens(&mut rng));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized == b" 1<<2>>" || serialized == b"2 <<1>>" || serialized == b"12<< >>");
+            seen.push(serialized);
+        }
+        assert!(seen.contains(&b"2 <<1>>".to_vec()));
+
+        let parsed_file = slurp(&grammar, b"<<1>>");
+        assert!(!FuzzFile::new(&parsed_file).swap_tokens(&mut rng));
+    }
+
+    #[test]
+    fn test_reverse_range() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"<<1 2 3>>");
+        let mut rng = test_rng();
+
+        let mut ff = FuzzFile::new(&parsed_file);
+        assert!(ff.reverse_range(&mut rng));
+        let mut serialized = Vec::new();
+        ff.serialize(&mut serialized);
+        assert!(serialized == b"<<3 2 1>>");
+
+        let parsed_file = slurp(&grammar, b"1 2 <<3>><<>>");
+        for _ in 0..10 {
+            assert!(!FuzzFile::new(&parsed_file).reverse_range(&mut rng));
+        }
+    }
+
+    #[test]
+    fn test_collapse_delim() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 3>> 4");
+        let mut rng = test_rng();
+
+        let mut ff = FuzzFile::new(&parsed_file);
+        assert!(ff.collapse_delim(&mut rng));
+        let mut serialized = Vec::new();
+        ff.serialize(&mut serialized);
+        assert!(serialized == b"1 2 3 4");
+        assert!(ff.depth() == 0);
+
+        let parsed_file = slurp(&grammar, b"<<1 <<2>> 3>>");
+        for _ in 0..10 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.collapse_delim(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized == b"1 <<2>> 3" || serialized == b"<<1 2 3>>");
+        }
+
+        assert!(!FuzzFile::new(&slurp(&grammar, b"1 2 3")).collapse_delim(&mut rng));
+    }
+
+    #[test]
+    fn test_mutate_number() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"abc <<42>>");
+        let mut rng = test_rng();
+
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.mutate_number(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized.starts_with(b"abc <<") && serialized.ends_with(b">>"));
+            let number = serialized[6..serialized.len()-2].to_vec();
+            assert!(number != b"42");
+            seen.push(number);
+        }
+        for expected in &[&b"0"[..], b"41", b"43", b"-42", b"9223372036854775807"] {
+            assert!(seen.contains(&expected.to_vec()));
+        }
+
+        let parsed_file = slurp(&grammar, b"abc <<4x2>> -");
+        assert!(!FuzzFile::new(&parsed_file).mutate_number(&mut rng));
+    }
+
+    #[test]
+    fn test_insert_random_token() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"1<<2>>3");
+        let mut rng = test_rng();
+
+        let mut inside = false;
+        for _ in 0..50 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.insert_random_token(&mut rng, 4));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized.len() > 7 && serialized.len() <= 11);
+            inside |= serialized.starts_with(b"1<<") && serialized.ends_with(b">>3");
+        }
+        assert!(inside);
+
+        assert!(!FuzzFile::new(&parsed_file).insert_random_token(&mut rng, 0));
+        let parsed_file = slurp(&grammar, b"");
+        let mut ff = FuzzFile::new(&parsed_file);
+        assert!(ff.insert_random_token(&mut rng, 1));
+        assert!(ff.serialized_len() == 1);
+    }
+
+    #[test]
+    fn test_delete_token() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2>>");
+        let mut rng = test_rng();
+
+        let mut seen = Vec::new();
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.delete_token(&mut rng));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized == b" <<2>>" || serialized == b"1<<2>>" || serialized == b"1 <<>>");
+            seen.push(serialized);
+        }
+        assert!(seen.contains(&b"1 <<>>".to_vec()) && seen.contains(&b" <<2>>".to_vec()));
+
+        let parsed_file = slurp(&grammar, b"<<>>");
+        assert!(!FuzzFile::new(&parsed_file).delete_token(&mut rng));
+    }
+
+    #[test]
+    fn test_dict_token() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2>>");
+        let mut rng = test_rng();
+        let dict = vec![b"GET".to_vec(), vec![0xff]];
+
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.replace_token_with_dict(&mut rng, &dict));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized.windows(3).any(|w| w == b"GET") || serialized.contains(&0xff));
+        }
+        assert!(!FuzzFile::new(&parsed_file).replace_token_with_dict(&mut rng, &[]));
+
+        let dir = temp_dir("dict-token");
+        let dict_path = dir.join("tokens.dict");
+        fs::write(&dict_path, "get=\"GET\"\n").unwrap();
+        let matches = app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "-o", "out/{}", "--only", "dict-token", "--dict", dict_path.to_str().unwrap()]).unwrap();
+        let config = fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap();
+        assert!(config.dictionary == vec![b"GET".to_vec()]);
+        let fuzzed = fuzz::fuzz_one(&parsed_file, &mut rng, &config).unwrap();
+        let mut serialized = Vec::new();
+        fuzzed.serialize(&mut serialized);
+        assert!(serialized.windows(3).any(|w| w == b"GET"));
+    }
+
+    #[test]
+    fn test_dict_level() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 3>> 4");
+        let mut rng = test_rng();
+        let dir = temp_dir("dict-level");
+        let dict_path = dir.join("tokens.dict");
+        fs::write(&dict_path, "get=\"GET\"\n").unwrap();
+
+        let mut count = |level: &str| {
+            let matches = app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "-o", "out/{}", "--dict", dict_path.to_str().unwrap(), "--dict-level", level]).unwrap();
+            let config = fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap();
+            (0..200).filter_map(|_| fuzz::fuzz_one_traced(&parsed_file, &mut rng, &config))
+                .map(|(_, applied)| applied.iter().filter(|mutation| **mutation == Mutation::DictToken).count())
+                .sum::<usize>()
+        };
+        let counts: Vec<usize> = ["0", "1", "2", "3"].iter().map(|level| count(level)).collect();
+        assert!(counts[0] == 0);
+        assert!(counts[1] > 0 && counts[1] < counts[2] && counts[2] < counts[3]);
+
+        let matches = app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "-o", "out/{}", "--only", "swap-delim", "--dict-level", "2"]).unwrap();
+        assert!(fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).is_err());
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "-o", "out/{}", "--dict-level", "4"]).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let mut rng = test_rng();
+
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.truncate_range(&mut rng));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            assert!(serialized.len() < 17);
+            assert!(serialized.starts_with(b"1 <<") && serialized.ends_with(b">> 5"));
+        }
+
+        let parsed_file = slurp(&grammar, b"<<>>");
+        assert!(!FuzzFile::new(&parsed_file).truncate_range(&mut rng));
+    }
+
+    #[test]
+    fn test_weighted_mutations() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let mut rng = test_rng();
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        assert!(fuzz::default_weighted_mutations().into_iter().map(|(mutation, _)| mutation).collect::<Vec<_>>() == config.valid_actions);
+
+        let mut counts = |config: &fuzz::FuzzConfig| {
+            let mut counts = (0, 0);
+            for _ in 0..200 {
+                if let Some((_, applied)) = fuzz::fuzz_one_traced(&parsed_file, &mut rng, config) {
+                    counts.0 += applied.iter().filter(|mutation| **mutation == Mutation::SwapDelim).count();
+                    counts.1 += applied.iter().filter(|mutation| **mutation == Mutation::ShuffleRanges).count();
+                }
+            }
+            counts
+        };
+
+        config.set_weighted_mutations(vec![(Mutation::SwapDelim, 1), (Mutation::ShuffleRanges, 0)]);
+        assert!(config.validate().is_ok());
+        let (swaps, shuffles) = counts(&config);
+        assert!(swaps > 0 && shuffles == 0);
+
+        config.set_weighted_mutations(vec![(Mutation::SwapDelim, 9), (Mutation::ShuffleRanges, 1)]);
+        let (swaps, shuffles) = counts(&config);
+        assert!(shuffles > 0 && swaps > 4 * shuffles);
+
+        config.weights = vec![1];
+        assert!(config.validate().is_err());
+        counts(&config);
+    }
+
+    #[test]
+    fn test_splice() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![b" ".to_vec()]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let donor = slurp(&grammar, b"a (b (c) d) e");
+        let mut rng = test_rng();
+
+        let mut spliced = 0;
+        for _ in 0..50 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.splice(&mut rng, &donor));
+            assert!(ff.validate().is_ok());
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+            if serialized.iter().any(|b| b"abcde()".contains(b)) {
+                spliced += 1;
+                // Grafted delims keep their contents intact.
+                let opens = serialized.iter().filter(|b| **b == b'(').count();
+                let closes = serialized.iter().filter(|b| **b == b')').count();
+                assert!(opens == closes);
+            }
+        }
+        assert!(spliced > 0);
+
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::Splice];
+        assert!(fuzz::fuzz_one(&parsed_file, &mut rng, &config).is_none());
+        assert!(fuzz::fuzz_one_with_donor(&parsed_file, &donor, &mut rng, &config).is_some());
+    }
+
+    #[test]
+    fn test_stats() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+        ], vec![b" ".to_vec()]);
+        let stats = slurp(&grammar, b"\x02xy abc <<1 <<22>>>>").stats();
+        assert!(stats == ParseStats {
+            tokens: 7,
+            delims: 2,
+            length_prefixed: 1,
+            ranges: 3,
+            max_depth: 2,
+            total_bytes: 20,
+            largest_token: 3,
+        });
+        assert!(stats.to_string().lines().next() == Some("tokens: 7"));
+
+        let stats = slurp(&grammar, b"").stats();
+        assert!(stats == ParseStats::default());
+        assert!(app().get_matches_from_safe(vec!["shft", "stats"]).is_ok());
+    }
+
+    #[test]
+    fn test_dump_json() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::LengthPrefixed { width: 1, endian: Endian::Big },
+        ], vec![b" ".to_vec()]);
+        let parsed_file = slurp(&grammar, b"a\\\"<<\x7f\t>> \x02xy");
+        let json: serde_json::Value = serde_json::from_str(&parsed_file.to_json()).unwrap();
+
+        let nodes = json["nodes"].as_array().unwrap();
+        assert!(nodes.len() == parsed_file.nodes.len());
+        assert!(json["root"].as_array().unwrap().len() == parsed_file.root.len());
+        assert!(json["ranges"].as_array().unwrap().len() == parsed_file.ranges.len());
+        for (noderef, node) in nodes.iter().enumerate() {
+            let span = parsed_file.span(noderef);
+            assert!(node["span"] == json!([span.start, span.end]));
+        }
+
+        let tokens: Vec<&str> = nodes.iter().filter(|node| node["type"] == "token").map(|node| node["token"].as_str().unwrap()).collect();
+        assert!(tokens.contains(&"a\\\\\""));
+        assert!(tokens.contains(&"\\x7f\\t"));
+        assert!(tokens.contains(&"xy"));
+        assert!(nodes.iter().any(|node| node["type"] == "delim" && node["start"] == "<<" && node["end"] == ">>"));
+        assert!(nodes.iter().any(|node| node["type"] == "length_prefixed" && node["width"] == 1 && node["endian"] == "big"));
+
+        let matches = app().get_matches_from_safe(vec!["shft", "dump", "--format", "json"]).unwrap();
+        assert!(matches.subcommand_matches("dump").unwrap().value_of("FORMAT") == Some("json"));
+        let matches = app().get_matches_from_safe(vec!["shft", "dump"]).unwrap();
+        assert!(matches.subcommand_matches("dump").unwrap().value_of("FORMAT") == Some("text"));
+        assert!(app().get_matches_from_safe(vec!["shft", "dump", "--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_minimize() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let mut serialized = Vec::new();
+        fuzz::minimize(&parsed_file, |buf| buf.contains(&b'3')).serialize(&mut serialized);
+        assert!(serialized == b"3");
+
+        serialized.clear();
+        fuzz::minimize(&parsed_file, |buf| buf.windows(2).any(|w| w == b"<<") && buf.contains(&b'4')).serialize(&mut serialized);
+        assert!(serialized == b"<<4>>");
+
+        let dir = temp_dir("minimize");
+        let output = dir.join("min.bin");
+        let command = ["sh", "-c", "! grep -q 3 \"$0\"", "@@"];
+        assert!(do_minimize(&grammar, &ParseConfig::new(), b"1 <<2 <<3>> 4>> 5", &output, &command).unwrap() == 1);
+        assert!(read_all(output.clone()) == b"3");
+        assert!(do_minimize(&grammar, &ParseConfig::new(), b"1 2", &output, &command).is_err());
+
+        // Only whole nodes of the tree the parse config gives are dropped.
+        let mut parse_config = ParseConfig::new();
+        parse_config.max_nodes = Some(1);
+        assert!(do_minimize(&grammar, &parse_config, b"1 <<2 <<3>> 4>> 5", &output, &command).unwrap() == 15);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5");
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::NestDelim];
+        config.max_mutations = 10;
+        let mut rng = test_rng();
+
+        let unbounded = (0..20).filter_map(|_| fuzz::fuzz_one(&parsed_file, &mut rng, &config)).map(|ff| ff.depth()).max();
+        assert!(unbounded.unwrap() > 4);
+
+        config.max_depth = Some(4);
+        for _ in 0..20 {
+            if let Some((ff, applied)) = fuzz::fuzz_one_traced(&parsed_file, &mut rng, &config) {
+                assert!(ff.depth() <= 4);
+                assert!(applied.len() <= 2);
+            }
+        }
+
+        // A deep donor can't be spliced in past the limit either.
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'<', b'<'], vec![b'>', b'>'], DelimOptions::default()),
+            GrammarDef::Delim(vec![b'('], vec![b')'], DelimOptions::default()),
+        ], vec![]);
+        let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5");
+        let donor = slurp(&grammar, b"a((((((((b))))))))c");
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::Splice];
+        config.max_mutations = 4;
+
+        let unbounded = (0..20).filter_map(|_| fuzz::fuzz_one_with_donor(&parsed_file, &donor, &mut rng, &config)).map(|ff| ff.depth()).max();
+        assert!(unbounded.unwrap() > 4);
+
+        config.max_depth = Some(4);
+        for _ in 0..50 {
+            if let Some(ff) = fuzz::fuzz_one_with_donor(&parsed_file, &donor, &mut rng, &config) {
+                assert!(ff.depth() <= 4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_total_nodes() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5");
+        let initial = FuzzFile::new(&parsed_file).total_nodes();
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::NestDelim, Mutation::DuplicateRootNode, Mutation::RepeatDelim];
+        config.max_mutations = 20;
+        let mut rng = test_rng();
+
+        let unbounded = (0..20).filter_map(|_| fuzz::fuzz_one(&parsed_file, &mut rng, &config)).map(|ff| ff.total_nodes()).max();
+        assert!(unbounded.unwrap() > initial + 20);
+
+        // Once the budget is spent no growing mutation applies.
+        config.max_total_nodes = Some(initial);
+        for _ in 0..20 {
+            assert!(fuzz::fuzz_one(&parsed_file, &mut rng, &config).is_none());
+        }
+
+        // A single mutation can't overshoot the budget either, however
+        // much it adds at once.
+        config.valid_actions = vec![Mutation::RepeatDelim];
+        config.max_nest_depth = 1000;
+        config.max_mutations = 1;
+        config.max_total_nodes = None;
+        let unbounded = (0..20).filter_map(|_| fuzz::fuzz_one(&parsed_file, &mut rng, &config)).map(|ff| ff.total_nodes()).max();
+        assert!(unbounded.unwrap() > initial + 20);
+
+        config.max_total_nodes = Some(initial + 20);
+        let mut applied = 0;
+        for _ in 0..100 {
+            if let Some(ff) = fuzz::fuzz_one(&parsed_file, &mut rng, &config) {
+                assert!(ff.total_nodes() <= initial + 20);
+                applied += 1;
+            }
+        }
+        assert!(applied > 0);
+    }
+
+    #[test]
+    fn test_min_mutations() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"abc");
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::RemoveDelim, Mutation::RandomizeToken];
+        config.max_mutations = 1;
+        config.min_mutations = 3;
+        let mut rng = test_rng();
+
+        let mut num_fuzzed = 0;
+        for _ in 0..20 {
+            if let Some((_, applied)) = fuzz::fuzz_one_traced(&parsed_file, &mut rng, &config) {
+                assert!(applied == vec![Mutation::RandomizeToken; 3]);
+                num_fuzzed += 1;
+            }
+        }
+        assert!(num_fuzzed > 0);
+
+        // Nothing applies, so every attempt is used up and then given up on.
+        config.valid_actions = vec![Mutation::RemoveDelim];
+        assert!(fuzz::fuzz_one(&parsed_file, &mut rng, &config).is_none());
+
+        // No mutations are allowed, so none is forced in to reach the minimum.
+        config.valid_actions = vec![Mutation::RandomizeToken];
+        config.max_mutations = 0;
+        assert!(config.validate().is_err());
+        for _ in 0..20 {
+            assert!(fuzz::fuzz_one(&parsed_file, &mut rng, &config).is_none());
+        }
+    }
+
+    #[test]
+    fn test_substring_splice() {
+        let grammar = angle_grammar(&[]);
+        let originals: Vec<&[u8]> = vec![b"abcd", b"efgh", b"ijkl"];
+        let parsed_file = slurp(&grammar, b"abcd<<efgh>>ijkl");
+        let mut rng = test_rng();
+
+        let is_splice_of = |spliced: &[u8], original: &[u8]| {
+            let len = spliced.len() - original.len();
+            (0..original.len() + 1).any(|position| {
+                let inserted = &spliced[position..position + len];
+                &spliced[..position] == &original[..position]
+                    && &spliced[position + len..] == &original[position..]
+                    && originals.iter().any(|token| token.windows(len).any(|window| window == inserted))
+            })
+        };
+
+        let mut crossed = false;
+        for _ in 0..20 {
+            let mut ff = FuzzFile::new(&parsed_file);
+            assert!(ff.substring_splice(&mut rng));
+            let mut serialized = Vec::new();
+            ff.serialize(&mut serialized);
+
+            let start = serialized.iter().position(|b| *b == b'<').unwrap();
+            let end = serialized.iter().position(|b| *b == b'>').unwrap();
+            assert!(&serialized[start..start + 2] == b"<<" && &serialized[end..end + 2] == b">>");
+            let tokens = [&serialized[..start], &serialized[start + 2..end], &serialized[end + 2..]];
+
+            let changed: Vec<usize> = (0..3).filter(|i| tokens[*i] != originals[*i]).collect();
+            assert!(changed.len() == 1);
+            let spliced = tokens[changed[0]];
+            assert!(spliced.len() > originals[changed[0]].len());
+            assert!(is_splice_of(spliced, originals[changed[0]]));
+            crossed |= spliced.iter().any(|b| !originals[changed[0]].contains(b));
+        }
+        assert!(crossed);
+    }
+
+    #[test]
+    fn test_swap_endianness() {
+        let grammar = Grammar::new(vec![
+            GrammarDef::Delim(vec![b'['], vec![b']'], DelimOptions::default()),
+        ], vec![b" ".to_vec()]);
+        let parsed_file = slurp(&grammar, b"abc [abcd] abcdefghi");
+        let mut rng = test_rng();
+
+        let mut ff = FuzzFile::new(&parsed_file);
+        assert!(ff.swap_endianness(&mut rng));
+        let mut serialized = Vec::new();
+        ff.serialize(&mut serialized);
+        assert!(serialized == b"abc [dcba] abcdefghi");
+
+        let parsed_file = slurp(&grammar, b"abc [abba] abcdefghi");
+        assert!(!FuzzFile::new(&parsed_file).swap_endianness(&mut rng));
+    }
+
+    #[test]
+    fn test_subtree_bytes() {
+        let grammar = angle_grammar(&[]);
+        let parsed_file = slurp(&grammar, b"1<<2<<3>>4>>5");
+        let outer = parsed_file.root[1];
+        assert!(parsed_file.subtree_bytes(outer) == b"<<2<<3>>4>>");
+
+        let inner = match parsed_file.nodes[outer] {
+            Node::Delim(_, rangeref) => parsed_file.ranges[rangeref][1],
+            _ => panic!("expected delim"),
+        };
+        assert!(parsed_file.subtree_bytes(inner) == b"<<3>>");
+        assert!(parsed_file.subtree_bytes(parsed_file.root[0]) == b"1");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_input() {
+        use std::io::Write;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use super::decompress;
+
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1 <<2 <<3>> 4>> 5";
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(buf).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let decompressed = decompress(compressed.clone(), 1024).unwrap();
+        assert!(slurp(&grammar, &decompressed).dump() == slurp(&grammar, buf).dump());
+        assert!(decompress(buf.to_vec(), 1024).unwrap() == buf);
+        assert!(decompress(compressed, 4).is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let grammar = angle_grammar(&[b" "]);
+        let queue = temp_dir("import-queue");
+        let corpus = temp_dir("import-corpus");
+        let samples: &[&[u8]] = &[b"1 <<2>> 3", b"4 <<5>> 6", b"1 <<2 <<3>>>>", b"7 <<8 <<9>>>>"];
+        for (i, sample) in samples.iter().enumerate() {
+            fs::write(queue.join(format!("id:{:06}", i)), sample).unwrap();
+        }
+
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", corpus.display())).unwrap();
+        assert!(do_import(&grammar, &ParseConfig::new(), &queue, &pattern, 1024).unwrap() == 2);
+        assert!(fs::read_dir(&corpus).unwrap().count() == 2);
+        assert!(read_all(corpus.join("1.bin")) == samples[0]);
+        assert!(read_all(corpus.join("2.bin")) == samples[2]);
+
+        // Under the parse config every sample is the same flat tree.
+        let corpus = temp_dir("import-corpus-max-nodes");
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", corpus.display())).unwrap();
+        let mut parse_config = ParseConfig::new();
+        parse_config.max_nodes = Some(1);
+        assert!(do_import(&grammar, &parse_config, &queue, &pattern, 1024).unwrap() == 1);
+    }
+
+    #[test]
+    fn test_sidecars() {
+        let grammar = angle_grammar(&[b" "]);
+        let dir = temp_dir("sidecars");
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let options = FuzzOptions {
+            sidecar_seed_paths: Some(&["seed.bin"]),
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+
+        let mut num_outputs = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == "bin" {
+                num_outputs += 1;
+                let meta: serde_json::Value = serde_json::from_slice(&read_all(path.with_extension("bin.meta"))).unwrap();
+                assert!(meta["seed_path"] == "seed.bin");
+                assert!(meta["rng_seed"] == 1);
+                let mutations = meta["mutations"].as_array().unwrap();
+                assert!(!mutations.is_empty());
+                assert!(mutations.iter().all(|mutation| mutation.as_str().unwrap().parse::<Mutation>().is_ok()));
+                let iteration = meta["iteration"].as_u64().unwrap() + 1;
+                assert!(path.file_stem().unwrap().to_str() == Some(&iteration.to_string()[..]));
+            }
+        }
+        assert!(num_outputs > 0);
+        assert!(fs::read_dir(&dir).unwrap().count() == num_outputs * 2);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let grammar = angle_grammar(&[b" "]);
+        let dir = temp_dir("manifest");
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let manifest = Mutex::new(Vec::new());
+        let options = FuzzOptions {
+            seed: 1,
+            jobs: 2,
+            manifest: Some(&manifest),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+
+        let config_path = dir.join("grammar.yaml");
+        File::create(&config_path).unwrap().write_all(b"delims: []\n").unwrap();
+        let manifest_path = dir.join("manifest.json");
+        write_manifest(manifest_path.to_str().unwrap(), config_path.to_str().unwrap(), &["seed.bin"], &config, &options, 10, &mut manifest.lock().unwrap()).unwrap();
+
+        let manifest: serde_json::Value = serde_json::from_slice(&read_all(manifest_path)).unwrap();
+        assert!(manifest["seed"] == 1 && manifest["jobs"] == 2 && manifest["iterations"] == 10);
+        assert!(manifest["inputs"] == json!(["seed.bin"]));
+        assert!(manifest["grammar"]["path"] == config_path.to_str().unwrap());
+        assert!(manifest["config"]["max_mutations"] == config.max_mutations);
+        assert!(manifest["config"]["mutations"].as_array().unwrap().len() == config.valid_actions.len());
+
+        let outputs = manifest["outputs"].as_array().unwrap();
+        assert!(!outputs.is_empty());
+        let mut last_index = 0;
+        for output in outputs {
+            let index = output["index"].as_u64().unwrap();
+            assert!(index > last_index);
+            last_index = index;
+            assert!(output["iteration"].as_u64().unwrap() + 1 == index);
+            assert!(output["input"] == 0);
+            let contents = read_all(PathBuf::from(output["path"].as_str().unwrap()));
+            let mut hasher = DefaultHasher::new();
+            contents.hash(&mut hasher);
+            assert!(output["hash"] == format!("{:016x}", hasher.finish()));
+            assert!(!output["mutations"].as_array().unwrap().is_empty());
+        }
+
+        // Packed outputs are located by their record in the merged file,
+        // whichever job wrote them.
+        let pack_path = dir.join("outputs.pack");
+        let manifest = Mutex::new(Vec::new());
+        let options = FuzzOptions {
+            seed: 1,
+            jobs: 2,
+            pack_path: Some(&pack_path),
+            manifest: Some(&manifest),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), None, 10, &config, &options, &mut None).unwrap();
+        let manifest_path = dir.join("pack-manifest.json");
+        write_manifest(manifest_path.to_str().unwrap(), config_path.to_str().unwrap(), &["seed.bin"], &config, &options, 10, &mut manifest.lock().unwrap()).unwrap();
+
+        let manifest: serde_json::Value = serde_json::from_slice(&read_all(manifest_path)).unwrap();
+        assert!(manifest["jobs"] == 2);
+        assert!(manifest["pack"] == pack_path.to_str().unwrap());
+        let packed = read_all(pack_path);
+        let records: Vec<Vec<u8>> = fuzz::read_packed(&packed[..]).collect();
+        let outputs = manifest["outputs"].as_array().unwrap();
+        assert!(outputs.len() == records.len());
+        for (n, output) in outputs.iter().enumerate() {
+            assert!(output["path"].is_null());
+            assert!(output["record"] == n);
+            let offset = output["offset"].as_u64().unwrap() as usize;
+            assert!(packed[offset + 4..].starts_with(&records[n]));
+            let mut hasher = DefaultHasher::new();
+            records[n].hash(&mut hasher);
+            assert!(output["hash"] == format!("{:016x}", hasher.finish()));
+        }
+    }
+
+    #[test]
+    fn test_multiple_seeds() {
+        let grammar = angle_grammar(&[b" "]);
+        let dir = temp_dir("multiple-seeds");
+        let inputs = vec![b"1 <<2 <<3>> 4>> 5".to_vec(), b"a <<b>> c".to_vec()];
+        let parsed_files: Vec<_> = inputs.iter().map(|input| slurp(&grammar, input)).collect();
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let options = FuzzOptions {
+            sidecar_seed_paths: Some(&["first", "second"]),
+            paranoid_inputs: Some(&inputs),
+            seed: 1,
+            jobs: 3,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(&parsed_files, Some(&pattern), 20, &config, &options, &mut None).unwrap();
+
+        let mut seed_paths = Vec::new();
+        for index in 1..21 {
+            let path = dir.join(format!("{}.bin.meta", index));
+            if path.exists() {
+                let meta: serde_json::Value = serde_json::from_slice(&read_all(path)).unwrap();
+                assert!(meta["seed_path"] == if index % 2 == 1 { "first" } else { "second" });
+                seed_paths.push(meta["seed_path"].as_str().unwrap().to_string());
+            }
+        }
+        assert!(seed_paths.iter().any(|path| path == "first") && seed_paths.iter().any(|path| path == "second"));
+
+        let matches = app().get_matches_from(vec!["shft", "-i", "a", "-i", "b", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1"]);
+        assert!(matches.values_of("INPUT").unwrap().collect::<Vec<_>>() == vec!["a", "b"]);
+        assert!(check_inputs(&matches).is_ok());
+
+        for args in &[
+            vec!["shft", "-i", "a", "-i", "b", "-c", "cfg", "dump"],
+            vec!["shft", "-i", "a", "-i", "b", "-c", "cfg", "stats"],
+            vec!["shft", "-i", "a", "-i", "b", "-c", "cfg", "minimize", "-o", "out", "--", "true"],
+            vec!["shft", "-i", "-", "-i", "-", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1"],
+        ] {
+            assert!(check_inputs(&app().get_matches_from(args.clone())).is_err());
+        }
+        assert!(check_inputs(&app().get_matches_from(vec!["shft", "-i", "-", "-i", "a", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1"])).is_ok());
+        assert!(check_inputs(&app().get_matches_from(vec!["shft", "-i", "a", "-c", "cfg", "dump"])).is_ok());
+    }
+
+    #[test]
+    fn test_with_dump() {
+        let grammar = angle_grammar(&[b" "]);
+        let dir = temp_dir("with-dump");
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let mut parse_config = ParseConfig::new();
+        parse_config.max_depth = 1;
+        let options = FuzzOptions {
+            dump_grammar: Some((&grammar, &parse_config)),
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+
+        let mut num_outputs = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == "bin" {
+                num_outputs += 1;
+                let tree = read_all(path.with_extension("bin.tree"));
+                assert!(tree == slurp_with(&grammar, &read_all(path), &parse_config).dump().into_bytes());
+            }
+        }
+        assert!(num_outputs > 0);
+        assert!(fs::read_dir(&dir).unwrap().count() == num_outputs * 2);
+    }
+
+    #[test]
+    fn test_paranoid() {
+        let grammar = angle_grammar(&[b" "]);
+        let dir = temp_dir("paranoid");
+        let buf = b"1 <<2 <<3>> 4>> 5";
+        let parsed_file = slurp(&grammar, buf);
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let inputs = vec![buf.to_vec()];
+        let options = FuzzOptions {
+            paranoid_inputs: Some(&inputs),
+            abort_on_panic: true,
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 100, &config, &options, &mut None).unwrap();
+
+        // A violation aborts even without `abort_on_panic`.
+        let inputs = vec![b"1 <<2 <<3>> 4>> 6".to_vec()];
+        let options = FuzzOptions {
+            paranoid_inputs: Some(&inputs),
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None)));
+        assert!(result.is_err());
+    }
+
+    // Range 1 is only reachable after SwapRanges, and serializing it
+    // panics, so the iterations with an odd number of swaps panic.
+    fn panicking_file() -> ParsedFile<'static> {
+        ParsedFile {
+            root: vec![1],
+            nodes: vec![Node::Token((&b"a"[..]).into()), Node::Range(0)],
+            ranges: vec![vec![0], vec![5]],
+            spans: vec![0..1, 0..1],
+        }
+    }
+
+    #[test]
+    fn test_warmup() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let first_output = |name: &str, warmup: usize| {
+            let dir = temp_dir(name);
+            let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+            let options = FuzzOptions {
+                warmup: warmup,
+                seed: 1,
+                ..FuzzOptions::default()
+            };
+            do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 1, &config, &options, &mut None).unwrap();
+            read_all(dir.join("1.bin"))
+        };
+
+        let cold = first_output("warmup-0", 0);
+        let warm = first_output("warmup-64", 64);
+        assert!(cold != warm);
+        assert!(warm == first_output("warmup-64-again", 64));
+    }
+
+    #[test]
+    fn test_seed() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let outputs = |name: &str, seed: u64| {
+            let dir = temp_dir(name);
+            let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+            let options = FuzzOptions {
+                seed: seed,
+                ..FuzzOptions::default()
+            };
+            do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+            (1..11).map(|i| read_all(dir.join(format!("{}.bin", i)))).collect::<Vec<_>>()
+        };
+
+        let first = outputs("seed-1", 1);
+        assert!(first == outputs("seed-1-again", 1));
+        assert!(first != outputs("seed-2", 2));
+    }
+
+    #[test]
+    fn test_jobs() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let outputs = |name: &str, jobs: usize| {
+            let dir = temp_dir(name);
+            let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+            let options = FuzzOptions {
+                seed: 1,
+                jobs: jobs,
+                ..FuzzOptions::default()
+            };
+            do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 21, &config, &options, &mut None).unwrap();
+            assert!(fs::read_dir(&dir).unwrap().count() == 21);
+            (1..22).map(|i| read_all(dir.join(format!("{}.bin", i)))).collect::<Vec<_>>()
+        };
+
+        let parallel = outputs("jobs-4", 4);
+        assert!(parallel == outputs("jobs-4-again", 4));
+        // The job count doesn't change what gets written.
+        assert!(parallel == outputs("jobs-1", 1));
+        assert!(parallel == outputs("jobs-0", 0));
+        // More jobs than iterations.
+        assert!(parallel == outputs("jobs-64", 64));
+    }
+
+    #[test]
+    fn test_tar() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let dir = temp_dir("tar");
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let tar_path = env::temp_dir().join(format!("shft-test-tar-{}.tar", process::id()));
+        let mut options = FuzzOptions {
+            seed: 1,
+            tar_path: Some(&tar_path),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+        assert!(fs::read_dir(&dir).unwrap().count() == 0);
+
+        options.tar_path = None;
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&tar_path).unwrap());
+        let mut names = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            assert!(contents == read_all(dir.join(&name)));
+            names.push(name);
+        }
+        assert!(names == (1..11).map(|i| PathBuf::from(format!("{}.bin", i))).collect::<Vec<_>>());
+        fs::remove_file(&tar_path).unwrap();
+    }
+
+    #[test]
+    fn test_pack() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2 <<3>> 4>> 5");
+        let config = fuzz::FuzzConfig::new(grammar.delims());
+        let dir = temp_dir("pack");
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let pack_path = env::temp_dir().join(format!("shft-test-pack-{}.bin", process::id()));
+        let mut options = FuzzOptions {
+            seed: 1,
+            pack_path: Some(&pack_path),
+            jobs: 4,
+            ..FuzzOptions::default()
+        };
+        assert!(effective_jobs(&options, 10) == 4);
+        do_fuzz(slice::from_ref(&parsed_file), None, 10, &config, &options, &mut None).unwrap();
+        let packed = read_all(pack_path.clone());
+
+        // Jobs finish in any order, but the packed file is the same.
+        do_fuzz(slice::from_ref(&parsed_file), None, 10, &config, &options, &mut None).unwrap();
+        assert!(read_all(pack_path.clone()) == packed);
+        options.jobs = 1;
+        do_fuzz(slice::from_ref(&parsed_file), None, 10, &config, &options, &mut None).unwrap();
+        assert!(read_all(pack_path.clone()) == packed);
+
+        options.pack_path = None;
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 10, &config, &options, &mut None).unwrap();
+
+        let records: Vec<Vec<u8>> = fuzz::read_packed(&packed[..]).collect();
+        let outputs: Vec<Vec<u8>> = (1..11).map(|i| read_all(dir.join(format!("{}.bin", i)))).collect();
+        assert!(records == outputs);
+        fs::remove_file(&pack_path).unwrap();
+        assert!(do_fuzz(slice::from_ref(&parsed_file), None, 10, &config, &options, &mut None).is_err());
+
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "--pack", "out.bin"]).is_ok());
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "--pack", "out.bin", "-o", "out/{}"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_skip_panics() {
+        let dir = temp_dir("skip-panics");
+        let parsed_file = panicking_file();
+        let mut config = fuzz::FuzzConfig::new(vec![]);
+        config.valid_actions = vec![Mutation::SwapRanges, Mutation::ShuffleRanges];
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+        let mut options = FuzzOptions {
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 50, &config, &options, &mut None).unwrap();
+
+        let num_outputs = fs::read_dir(&dir).unwrap().count();
+        assert!(num_outputs > 0 && num_outputs < 50);
+        for entry in fs::read_dir(&dir).unwrap() {
+            assert!(read_all(entry.unwrap().path()) == b"a");
+        }
+
+        options.abort_on_panic = true;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 50, &config, &options, &mut None)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "no longer round-trips")]
+    fn test_paranoid_roundtrip_failure() {
+        let grammar = angle_grammar(&[b" "]);
+        let parsed_file = slurp(&grammar, b"1 <<2>> 3");
+        let ff = FuzzFile::new(&parsed_file);
+        check_invariants(&parsed_file, b"1 <<2>> 4", &ff, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "mutated file is invalid")]
+    fn test_paranoid_invalid_mutation() {
+        let grammar = angle_grammar(&[b" "]);
+        let buf = b"1 <<2>> 3";
+        let parsed_file = slurp(&grammar, buf);
+        // As if a mutation had left a range pointing past the last node.
+        let mut corrupted = slurp(&grammar, buf);
+        let dangling = corrupted.nodes.len();
+        corrupted.ranges[0].push(dangling);
+        let ff = FuzzFile::new(&corrupted).apply_all_positions(&Mutation::ReverseRange).unwrap().next().unwrap();
+        check_invariants(&parsed_file, buf, &ff, 0);
+    }
+
+    #[test]
+    fn test_exclude_bytes() {
+        let grammar = angle_grammar(&[]);
+        let dir = temp_dir("exclude-bytes");
+        let buf = [&[b'a'; 64][..], b"<<", &[b'b'; 64][..], b">>"].concat();
+        let parsed_file = slurp(&grammar, &buf);
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::RandomizeToken];
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+
+        let matches = app().get_matches_from(vec!["shft", "fuzz", "-o", "out/{}", "-n", "1", "--exclude-byte", "0x00", "--exclude-byte", "10"]);
+        let excluded_bytes: Vec<u8> = matches.subcommand_matches("fuzz").unwrap().values_of("EXCLUDE_BYTE").unwrap().map(|s| parse_byte(s).unwrap()).collect();
+        assert!(excluded_bytes == vec![0x00, b'\n']);
+        assert!(parse_byte("0x100").is_err());
+
+        let options = FuzzOptions {
+            excluded_bytes: excluded_bytes,
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 50, &config, &options, &mut None).unwrap();
+
+        let outputs: Vec<Vec<u8>> = fs::read_dir(&dir).unwrap().map(|entry| read_all(entry.unwrap().path())).collect();
+        assert!(outputs.len() == 50);
+        for output in &outputs {
+            assert!(!output.contains(&0x00) && !output.contains(&b'\n'));
+        }
+    }
+
+    #[test]
+    fn test_max_output_size() {
+        let grammar = angle_grammar(&[]);
+        let dir = temp_dir("max-output-size");
+        let buf = b"1<<2<<3>>4>>5";
+        let parsed_file = slurp(&grammar, buf);
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::DuplicateRange, Mutation::DuplicateRootNode];
+        config.max_mutations = 8;
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+
+        let options = FuzzOptions {
+            seed: 1,
+            max_output_size: Some(64),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 50, &config, &options, &mut None).unwrap();
+
+        // Iterations whose first attempt fits write exactly what they would
+        // without a cap, the rest re-roll into complete outputs that fit.
+        let uncapped_dir = temp_dir("max-output-size-uncapped");
+        let uncapped_pattern = OutputPattern::from_path(format!("{}/{{}}.bin", uncapped_dir.display())).unwrap();
+        let options = FuzzOptions {
+            seed: 1,
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&uncapped_pattern), 50, &config, &options, &mut None).unwrap();
+
+        let mut strict = ParseConfig::new();
+        strict.recovery = RecoveryPolicy::Strict;
+        let (mut fitting, mut oversized) = (0, 0);
+        for index in 1..51 {
+            let uncapped = read_all(uncapped_dir.join(format!("{}.bin", index)));
+            let path = dir.join(format!("{}.bin", index));
+            if uncapped.len() <= 64 {
+                assert!(read_all(path) == uncapped);
+                fitting += 1;
+            } else {
+                oversized += 1;
+                if path.exists() {
+                    let output = read_all(path);
+                    assert!(output.len() <= 64);
+                    assert!(try_slurp_with(&grammar, &output, &strict).is_ok());
+                }
+            }
+        }
+        assert!(fitting > 0 && oversized > 0);
+    }
+
+    #[test]
+    fn test_unique() {
+        let grammar = angle_grammar(&[]);
+        let dir = temp_dir("unique");
+        let parsed_file = slurp(&grammar, b"a<<b>>c");
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::EmptyDelim, Mutation::RemoveDelim];
+        config.max_mutations = 1;
+        let pattern = OutputPattern::from_path(format!("{}/{{}}.bin", dir.display())).unwrap();
+
+        let options = FuzzOptions {
+            seed: 1,
+            jobs: 4,
+            unique: true,
+            ..FuzzOptions::default()
+        };
+        assert!(do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 20, &config, &options, &mut None).unwrap() == 18);
+
+        let mut outputs = vec![read_all(dir.join("1.bin")), read_all(dir.join("2.bin"))];
+        outputs.sort();
+        assert!(outputs == vec![b"a<<>>c".to_vec(), b"abc".to_vec()]);
+        assert!(fs::read_dir(&dir).unwrap().count() == 2);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let grammar = angle_grammar(&[]);
+        let dir = temp_dir("dry-run");
+        let parsed_file = slurp(&grammar, b"a<<b>>c");
+        let mut config = fuzz::FuzzConfig::new(grammar.delims());
+        config.valid_actions = vec![Mutation::EmptyDelim, Mutation::RemoveDelim];
+        config.max_mutations = 1;
+        let pattern = OutputPattern::from_path(format!("{}/{{shard:5}}/{{}}.bin", dir.display())).unwrap();
+
+        let dry_run = Mutex::new(DryRun::new());
+        let options = FuzzOptions {
+            seed: 1,
+            jobs: 4,
+            dry_run: Some(&dry_run),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), Some(&pattern), 20, &config, &options, &mut None).unwrap();
+        assert!(fs::read_dir(&dir).unwrap().count() == 0);
+
+        let dry_run = dry_run.into_inner().unwrap();
+        assert!(dry_run.sizes.len() == 20);
+        assert!(dry_run.sizes.iter().all(|&size| size == 3 || size == 6));
+        assert!(dry_run.mutations.values().sum::<usize>() == 20);
+        assert!(dry_run.mutations.keys().all(|mutation| *mutation == Mutation::EmptyDelim || *mutation == Mutation::RemoveDelim));
+
+        // Nothing is written, so no output pattern is needed.
+        let dry_run = Mutex::new(DryRun::new());
+        let options = FuzzOptions {
+            seed: 1,
+            dry_run: Some(&dry_run),
+            ..FuzzOptions::default()
+        };
+        do_fuzz(slice::from_ref(&parsed_file), None, 20, &config, &options, &mut None).unwrap();
+        assert!(dry_run.into_inner().unwrap().sizes.len() == 20);
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-n", "1", "--dry-run"]).is_ok());
+
+        assert!(app().get_matches_from_safe(vec!["shft", "fuzz", "-o", "out/{}", "-n", "1", "--dry-run", "--tar", "out.tar"]).is_err());
+    }
+
+    #[test]
+    fn test_max_input_size() {
+        let dir = temp_dir("max-input-size");
+        let path = dir.join("input");
+        fs::write(&path, &[b'x'; 100][..]).unwrap();
+
+        assert!(read_file(&path, 99).is_err());
+        assert!(read_file(&path, 100).unwrap().len() == 100);
+        assert!(read_input(path.to_str().unwrap(), 100).unwrap().len() == 100);
+    }
+
+    #[test]
+    fn test_stdin_input() {
+        let matches = app().get_matches_from_safe(vec!["shft", "-i", "-", "dump"]).unwrap();
+        assert!(matches.value_of("INPUT") == Some("-"));
+        let matches = app().get_matches_from_safe(vec!["shft", "dump", "--input", "-"]).unwrap();
+        assert!(matches.value_of("INPUT") == Some("-"));
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let matches = app().get_matches_from(vec!["shft", "paths", "-o", "out/{}.bin", "-n", "3", "--start", "5"]);
+        let paths_matches = matches.subcommand_matches("paths").unwrap();
+        assert!(paths_matches.value_of("START") == Some("5"));
+
+        let pattern = OutputPattern::from_path("out/{}.bin").unwrap();
+        let paths = output_paths(&pattern, 5, 3);
+        assert!(paths == (5..8).map(|i| pattern.with(i)).collect::<Vec<_>>());
+        assert!(paths == vec![OsString::from("out/5.bin"), OsString::from("out/6.bin"), OsString::from("out/7.bin")]);
+        assert!(output_paths(&pattern, 1, 0).is_empty());
+    }
+
+    #[test]
+    fn test_only_mutation() {
+        let grammar = angle_grammar(&[]);
+        let matches = app().get_matches_from(vec!["shft", "-i", "in", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1", "--only", "SwapDelim"]);
+        let fuzz_matches = matches.subcommand_matches("fuzz").unwrap();
+        let config = fuzz_config(&grammar, fuzz_matches).unwrap();
+        assert!(config.valid_actions == vec![Mutation::SwapDelim]);
+
+        let parsed_file = slurp(&grammar, b"1<<2>>3");
+        let mut rng = test_rng();
+        for _ in 0..100 {
+            if let Some(ff) = fuzz::fuzz_one(&parsed_file, &mut rng, &config) {
+                let mut serialized = Vec::new();
+                ff.serialize(&mut serialized);
+                assert!(serialized == b"1<<2>>3" || serialized == b"1>>2<<3");
+            }
+        }
+
+        let matches = app().get_matches_from(vec!["shft", "-i", "in", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1", "--only", "Bogus"]);
+        assert!(fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_mutations_flag() {
+        let grammar = angle_grammar(&[]);
+        let fuzz_args = |extra: &[&'static str]| {
+            let mut args = vec!["shft", "-i", "in", "-c", "cfg", "fuzz", "-o", "out/{}", "-n", "1"];
+            args.extend(extra);
+            app().get_matches_from_safe(args)
+        };
+
+        let matches = fuzz_args(&["--mutations", "nest-delim,swap_ranges,BitFlip"]).unwrap();
+        let config = fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap();
+        assert!(config.valid_actions == vec![Mutation::NestDelim, Mutation::SwapRanges, Mutation::BitFlip]);
+
+        let matches = fuzz_args(&[]).unwrap();
+        let config = fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()).unwrap();
+        assert!(config.valid_actions == fuzz::default_mutations());
+
+        let matches = fuzz_args(&["--mutations", "nest-delim,bogus"]).unwrap();
+        match fuzz_config(&grammar, matches.subcommand_matches("fuzz").unwrap()) {
+            Err(e) => {
+                assert!(e.to_string() == "Invalid mutation: bogus");
+                let cause = e.iter().nth(1).unwrap().to_string();
+                assert!(cause.starts_with("Unknown mutation: bogus, expected one of bit-flip, collapse-delim,"));
+            },
+            Ok(_) => panic!("expected invalid mutation error"),
+        }
+
+        assert!(fuzz_args(&["--mutations", "nest-delim", "--only", "bit-flip"]).is_err());
+    }
+}
+
+const DEFAULT_MAX_INPUT_SIZE: u64 = 256 * 1024 * 1024;
+
+fn read_bounded<R: Read>(reader: R, max_size: u64) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.take(max_size + 1).read_to_end(&mut buffer).chain_err(|| "Read failed")?;
+    if buffer.len() as u64 > max_size {
+        bail!("Input is larger than the maximum size of {} bytes", max_size);
+    }
+    Ok(buffer)
+}
+
+fn read_file<P: AsRef<Path>>(path: P, max_size: u64) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    let f = File::open(path).chain_err(|| format!("Could not open input file {}", path.display()))?;
+    let buffer = read_bounded(f, max_size).chain_err(|| format!("Could not read input file {}", path.display()))?;
+    decompress(buffer, max_size).chain_err(|| format!("Could not decompress input file {}", path.display()))
+}
+
+// An input filename of `-` reads from stdin.
+fn read_input(input_filename: &str, max_size: u64) -> Result<Vec<u8>> {
+    if input_filename != "-" {
+        return read_file(input_filename, max_size)
+    }
+    let stdin = io::stdin();
+    let buffer = read_bounded(stdin.lock(), max_size).chain_err(|| "Could not read input from stdin")?;
+    decompress(buffer, max_size).chain_err(|| "Could not decompress input from stdin")
+}
+
+#[cfg(feature = "gzip")]
+fn decompress(buffer: Vec<u8>, max_size: u64) -> Result<Vec<u8>> {
+    if buffer.starts_with(&[0x1f, 0x8b]) {
+        read_bounded(flate2::read::GzDecoder::new(&buffer[..]), max_size).chain_err(|| "Invalid gzip data")
+    } else {
+        Ok(buffer)
+    }
+}
+
+#[cfg(not(feature = "gzip"))]
+fn decompress(buffer: Vec<u8>, _max_size: u64) -> Result<Vec<u8>> {
+    Ok(buffer)
+}
+
+struct Profile {
+    parse: Duration,
+    fuzz: Duration,
+    serialize: Duration,
+}
+
+impl Profile {
+    fn new() -> Self {
+        Profile {
+            parse: Duration::from_secs(0),
+            fuzz: Duration::from_secs(0),
+            serialize: Duration::from_secs(0),
+        }
+    }
+
+    // Totals are summed over jobs, so they can exceed the wall-clock time.
+    fn add(self: &mut Self, other: &Profile) {
+        self.parse += other.parse;
+        self.fuzz += other.fuzz;
+        self.serialize += other.serialize;
+    }
+
+    fn report(self: &Self) {
+        eprintln!("parse:     {:?}", self.parse);
+        eprintln!("fuzz:      {:?}", self.fuzz);
+        eprintln!("serialize: {:?}", self.serialize);
+    }
+}
+
+fn timed<T, F: FnOnce() -> T>(total: Option<&mut Duration>, f: F) -> T {
+    match total {
+        Some(total) => {
+            let start = Instant::now();
+            let result = f();
+            *total += start.elapsed();
+            result
+        },
+        None => f(),
+    }
+}
+
+fn write_sidecar(out_filename: &OsString, seed_path: &str, seed: u64, iteration: usize, mutations: &[fuzz::Mutation]) -> Result<()> {
+    let mut meta_filename = out_filename.clone();
+    meta_filename.push(".meta");
+    let meta = json!({
+        "seed_path": seed_path,
+        "rng_seed": seed,
+        "iteration": iteration,
+        "mutations": mutations.iter().map(|mutation| mutation.to_string()).collect::<Vec<_>>(),
+    });
+    let file = File::create(&meta_filename).chain_err(|| format!("Could not create sidecar file {:?}", meta_filename))?;
+    serde_json::to_writer_pretty(file, &meta).chain_err(|| format!("Could not write sidecar file {:?}", meta_filename))
+}
+
+// Records the parameters of a fuzz run and every output it wrote, so a
+// single output can be reproduced later.
+fn write_manifest(manifest_path: &str, config_filename: &str, input_filenames: &[&str], config: &fuzz::FuzzConfig, options: &FuzzOptions, num_iterations: usize, entries: &mut [ManifestEntry]) -> Result<()> {
+    let grammar_source = read_file(config_filename, DEFAULT_MAX_INPUT_SIZE)?;
+    entries.sort_by_key(|entry| entry.index);
+    let manifest = json!({
+        "seed": options.seed,
+        "warmup": options.warmup,
+        "jobs": effective_jobs(options, num_iterations),
+        "iterations": num_iterations,
+        "inputs": input_filenames,
+        "grammar": {
+            "path": config_filename,
+            "hash": format!("{:016x}", content_hash(&grammar_source)),
+        },
+        "config": {
+            "max_mutations": config.max_mutations,
+            "max_duplications": config.max_duplications,
+            "mutations": config.valid_actions.iter().map(|mutation| mutation.to_string()).collect::<Vec<_>>(),
+        },
+        "pack": options.pack_path.map(|pack_path| pack_path.to_string_lossy()),
+        "outputs": entries.iter().map(|entry| {
+            let mut output = json!({
+                "index": entry.index,
+                "iteration": entry.iteration,
+                "input": entry.seed,
+                "hash": format!("{:016x}", entry.hash),
+                "mutations": entry.mutations.iter().map(|mutation| mutation.to_string()).collect::<Vec<_>>(),
+            });
+            match entry.location {
+                OutputLocation::File(ref path) => {
+                    output["path"] = json!(path.to_string_lossy());
+                },
+                OutputLocation::Packed { record, offset } => {
+                    output["record"] = json!(record);
+                    output["offset"] = json!(offset);
+                },
+            }
+            output
+        }).collect::<Vec<_>>(),
+    });
+    let file = File::create(manifest_path).chain_err(|| format!("Could not create manifest {}", manifest_path))?;
+    serde_json::to_writer_pretty(file, &manifest).chain_err(|| format!("Could not write manifest {}", manifest_path))
+}
+
+fn content_hash(buf: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buf.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn write_tree(out_filename: &OsString, grammar: &Grammar, parse_config: &ParseConfig, serialized: &[u8]) -> Result<()> {
+    let mut tree_filename = out_filename.clone();
+    tree_filename.push(".tree");
+    let mut file = File::create(&tree_filename).chain_err(|| format!("Could not create tree file {:?}", tree_filename))?;
+    file.write_all(slurp_with(grammar, serialized, parse_config).dump().as_bytes()).chain_err(|| format!("Could not write tree file {:?}", tree_filename))
+}
+
+// Per-output options for do_fuzz.
+struct FuzzOptions<'a> {
+    // Write a JSON `.meta` file recording where the output came from,
+    // given the path of each seed.
+    sidecar_seed_paths: Option<&'a [&'a str]>,
+    // Write a `.tree` dump of the output re-parsed under this grammar and
+    // parse config.
+    dump_grammar: Option<(&'a Grammar, &'a ParseConfig)>,
+    // Check invariants before each output, given the original input of
+    // each seed. A violation always aborts, whatever `abort_on_panic` says.
+    paranoid_inputs: Option<&'a [Vec<u8>]>,
+    // Outputs containing any of these bytes are discarded and re-rolled.
+    excluded_bytes: Vec<u8>,
+    // Propagate a panic in any iteration instead of skipping it.
+    abort_on_panic: bool,
+    // Number of RNG outputs to discard at the start of each iteration.
+    warmup: usize,
+    // Seeds the RNG; a fixed seed gives the same outputs every run.
+    seed: u64,
+    // Write outputs into this tar archive, named after the output pattern's
+    // file name, instead of as loose files.
+    tar_path: Option<&'a Path>,
+    // Write outputs as records of this packed file, see `fuzz::read_packed`,
+    // instead of as loose files.
+    pack_path: Option<&'a Path>,
+    // Number of threads generating outputs.
+    jobs: usize,
+    // Outputs larger than this are discarded and re-rolled.
+    max_output_size: Option<usize>,
+    // Skip outputs identical to one already written, numbering the rest
+    // without gaps.
+    unique: bool,
+    // Record every output written, for `write_manifest`.
+    manifest: Option<&'a Mutex<Vec<ManifestEntry>>>,
+    // Tally outputs here instead of writing anything to disk.
+    dry_run: Option<&'a Mutex<DryRun>>,
+}
+
+impl<'a> Default for FuzzOptions<'a> {
+    fn default() -> Self {
+        FuzzOptions {
+            sidecar_seed_paths: None,
+            dump_grammar: None,
+            paranoid_inputs: None,
+            excluded_bytes: Vec::new(),
+            abort_on_panic: false,
+            warmup: 0,
+            seed: 0,
+            tar_path: None,
+            pack_path: None,
+            jobs: 1,
+            max_output_size: None,
+            unique: false,
+            manifest: None,
+            dry_run: None,
+        }
+    }
+}
+
+// Where do_fuzz put an output.
+enum OutputLocation {
+    File(OsString),
+    // The `record`th record of the packed file, starting `offset` bytes in.
+    Packed { record: usize, offset: u64 },
+}
+
+// An output written by do_fuzz and how it was generated.
+struct ManifestEntry {
+    index: usize,
+    location: OutputLocation,
+    iteration: usize,
+    // Index of the input the output was mutated from.
+    seed: usize,
+    hash: u64,
+    mutations: Vec<fuzz::Mutation>,
+}
+
+// What a `--dry-run` would have written.
+struct DryRun {
+    sizes: Vec<usize>,
+    mutations: HashMap<fuzz::Mutation, usize>,
+}
+
+impl DryRun {
+    fn new() -> Self {
+        DryRun {
+            sizes: Vec::new(),
+            mutations: HashMap::new(),
+        }
+    }
+
+    fn add(self: &mut Self, size: usize, mutations: &[fuzz::Mutation]) {
+        self.sizes.push(size);
+        for mutation in mutations {
+            *self.mutations.entry(mutation.clone()).or_insert(0) += 1;
+        }
+    }
+
+    fn report(self: &mut Self, num_iterations: usize) {
+        println!("would write {} of {} outputs", self.sizes.len(), num_iterations);
+        if self.sizes.is_empty() {
+            return
+        }
+        self.sizes.sort();
+        let percentile = |p: usize| self.sizes[(self.sizes.len() - 1) * p / 100];
+        let total: usize = self.sizes.iter().sum();
+        println!("sizes: min {}, median {}, 90th percentile {}, max {}, total {} bytes",
+            percentile(0), percentile(50), percentile(90), percentile(100), total);
+        let mut mutations: Vec<_> = self.mutations.iter().collect();
+        mutations.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.to_string().cmp(&b.0.to_string())));
+        println!("mutations:");
+        for (mutation, count) in mutations {
+            println!("  {}: {}", mutation, count);
+        }
+    }
+}
+
+const MAX_REROLLS: usize = 100;
+
+// Only the parts of `fuzzed_file` its mutations touched are re-checked.
+fn check_invariants(parsed_file: &ParsedFile, input: &[u8], fuzzed_file: &fuzz::FuzzFile, iteration: usize) {
+    let mut serialized = Vec::new();
+    fuzz::FuzzFile::new(parsed_file).serialize(&mut serialized);
+    if serialized != input {
+        panic!("iteration {}: unmutated input no longer round-trips ({} bytes in, {} bytes out)\n{}", iteration, input.len(), serialized.len(), parsed_file.dump());
+    }
+    if let Err(e) = fuzzed_file.validate_touched() {
+        panic!("iteration {}: mutated file is invalid: {}\n{:?}", iteration, e, fuzzed_file);
+    }
+}
+
+fn write_output(tar: Option<&mut tar::Builder<File>>, out_filename: &OsString, serialized: &[u8]) -> Result<()> {
+    match tar {
+        Some(tar) => {
+            let name = Path::new(out_filename).file_name().expect("output pattern has a file name");
+            let mut header = tar::Header::new_gnu();
+            header.set_size(serialized.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, serialized).chain_err(|| format!("Could not add {:?} to tar archive", name))
+        },
+        None => {
+            let mut file = File::create(out_filename).chain_err(|| format!("Could not create output file {:?}", out_filename))?;
+            file.write_all(serialized).chain_err(|| format!("Could not write output file {:?}", out_filename))
+        },
+    }
+}
+
+fn describe_trace(trace: &[fuzz::Mutation]) -> String {
+    trace.iter().map(|mutation| mutation.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn panic_message(cause: &Box<Any + Send>) -> &str {
+    match (cause.downcast_ref::<&str>(), cause.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "unknown panic",
+    }
+}
+
+// The number of jobs do_fuzz actually runs. A tar archive has a single
+// writer, and deduplication has to see every output to number them. Packed
+// outputs are buffered per job instead, see `do_fuzz`.
+fn effective_jobs(options: &FuzzOptions, num_iterations: usize) -> usize {
+    if options.tar_path.is_some() || options.unique {
+        1
+    } else {
+        cmp::max(1, cmp::min(options.jobs, num_iterations))
+    }
+}
+
+// A packed file being written by do_fuzz, see `fuzz::write_packed`. With
+// several jobs, each writes to its own buffer and do_fuzz appends them to
+// the file in order.
+struct PackWriter<'a, W: Write = BufWriter<File>> {
+    path: &'a Path,
+    writer: W,
+    records: usize,
+    offset: u64,
+}
+
+impl<'a> PackWriter<'a> {
+    fn create(path: &'a Path) -> Result<PackWriter<'a>> {
+        let file = File::create(path).chain_err(|| format!("Could not create packed file {}", path.display()))?;
+        Ok(PackWriter {
+            path: path,
+            writer: BufWriter::new(file),
+            records: 0,
+            offset: 0,
+        })
+    }
+
+    // Returns the record and offset the first of `buffer`'s records now
+    // starts at.
+    fn append(self: &mut Self, buffer: PackWriter<Vec<u8>>) -> Result<(usize, u64)> {
+        let start = (self.records, self.offset);
+        self.writer.write_all(&buffer.writer).chain_err(|| format!("Could not write to packed file {}", self.path.display()))?;
+        self.records += buffer.records;
+        self.offset += buffer.offset;
+        Ok(start)
+    }
+}
+
+impl<'a> PackWriter<'a, Vec<u8>> {
+    fn buffered(path: &'a Path) -> PackWriter<'a, Vec<u8>> {
+        PackWriter {
+            path: path,
+            writer: Vec::new(),
+            records: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl<'a, W: Write> PackWriter<'a, W> {
+    fn write(self: &mut Self, record: &[u8]) -> Result<OutputLocation> {
+        fuzz::write_packed(&mut self.writer, record).chain_err(|| format!("Could not write to packed file {}", self.path.display()))?;
+        let location = OutputLocation::Packed { record: self.records, offset: self.offset };
+        self.records += 1;
+        self.offset += 4 + record.len() as u64;
+        Ok(location)
+    }
+
+    fn finish(mut self: Self) -> Result<()> {
+        self.writer.flush().chain_err(|| format!("Could not write packed file {}", self.path.display()))
+    }
+}
+
+// Splits the iterations into one contiguous block per job. Iteration `i`
+// gets its own RNG seeded with `[seed, i]`, so a given seed gives the same
+// outputs whatever the job count, and an iteration can be regenerated on
+// its own. Iteration `i` mutates seed `i % parsed_files.len()`. Outputs are named
+// after `pattern`, which is only optional with `options.pack_path`. Packed
+// outputs are appended block by block, so they stay in iteration order.
+// Returns how many outputs `options.unique` suppressed.
+fn do_fuzz<'buf>(parsed_files: &[ParsedFile<'buf>], pattern: Option<&OutputPattern>, num_iterations: usize, config: &fuzz::FuzzConfig, options: &FuzzOptions, profile: &mut Option<Profile>) -> Result<usize> {
+    if pattern.is_none() && options.pack_path.is_none() && options.dry_run.is_none() {
+        bail!("Fuzzing needs an output pattern or a packed file");
+    }
+    let mut pack = match options.pack_path {
+        Some(_) if options.dry_run.is_some() => None,
+        Some(pack_path) => Some(PackWriter::create(pack_path)?),
+        None => None,
+    };
+    let jobs = effective_jobs(options, num_iterations);
+    if jobs == 1 {
+        let duplicates = fuzz_iterations(parsed_files, pattern, pack.as_mut(), 0..num_iterations, config, options, profile)?;
+        if let Some(pack) = pack {
+            pack.finish()?;
+        }
+        return Ok(duplicates)
+    }
+
+    let buffered = pack.is_some();
+    let results: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs).map(|job| {
+            let iterations = job * num_iterations / jobs..(job + 1) * num_iterations / jobs;
+            let mut job_pack = options.pack_path.filter(|_| buffered).map(PackWriter::buffered);
+            let mut job_profile = profile.as_ref().map(|_| Profile::new());
+            scope.spawn(move || {
+                let result = fuzz_iterations(parsed_files, pattern, job_pack.as_mut(), iterations.clone(), config, options, &mut job_profile);
+                (iterations, result, job_pack, job_profile)
+            })
+        }).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap_or_else(|cause| panic::resume_unwind(cause))).collect()
+    });
+
+    let mut duplicates = 0;
+    // Where each job's packed records start, to relocate its manifest entries.
+    let mut pack_starts = Vec::new();
+    for (iterations, result, job_pack, job_profile) in results {
+        if let (Some(profile), Some(job_profile)) = (profile.as_mut(), job_profile) {
+            profile.add(&job_profile);
+        }
+        duplicates += result?;
+        if let (Some(pack), Some(job_pack)) = (pack.as_mut(), job_pack) {
+            pack_starts.push((iterations, pack.append(job_pack)?));
+        }
+    }
+    if let Some(pack) = pack {
+        pack.finish()?;
+    }
+    if let Some(manifest) = options.manifest {
+        for entry in manifest.lock().unwrap().iter_mut() {
+            let iteration = entry.iteration;
+            if let OutputLocation::Packed { ref mut record, ref mut offset } = entry.location {
+                let &(_, (start_record, start_offset)) = pack_starts.iter().find(|start| start.0.contains(&iteration)).expect("packed by a job");
+                *record += start_record;
+                *offset += start_offset;
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+// A panic in one iteration is reported and skipped, unless
+// `options.abort_on_panic` is set. With `options.paranoid_inputs` every
+// panic aborts, as a broken invariant is a bug rather than a bad input.
+fn fuzz_iterations<'buf, W: Write>(parsed_files: &[ParsedFile<'buf>], pattern: Option<&OutputPattern>, mut pack: Option<&mut PackWriter<W>>, iterations: Range<usize>, config: &fuzz::FuzzConfig, options: &FuzzOptions, profile: &mut Option<Profile>) -> Result<usize> {
+    let mut tar = match options.tar_path {
+        Some(_) if options.dry_run.is_some() => None,
+        Some(tar_path) => {
+            let file = File::create(tar_path).chain_err(|| format!("Could not create tar archive {}", tar_path.display()))?;
+            Some(tar::Builder::new(file))
+        },
+        None => None,
+    };
+    // Hashes of the outputs written so far, for `options.unique`.
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    for i in iterations {
+        let mut rng = isaac::Isaac64Rng::from_seed(&[options.seed, i as u64]);
+        for _ in 0..options.warmup {
+            rng.next_u64();
+        }
+        let seed = i % parsed_files.len();
+        let parsed_file = &parsed_files[seed];
+        // The mutations of the latest attempt, reported if it panics.
+        let mut trace = Vec::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<()> {
+            for _ in 0..MAX_REROLLS {
+                let result = timed(profile.as_mut().map(|p| &mut p.fuzz), || fuzz::fuzz_one_traced(parsed_file, &mut rng, config));
+
+                let fuzzed_file = match result {
+                    Some((fuzzed_file, applied)) => {
+                        trace = applied;
+                        fuzzed_file
+                    },
+                    None => break,
+                };
+
+                if let Some(inputs) = options.paranoid_inputs {
+                    check_invariants(parsed_file, &inputs[seed], &fuzzed_file, i);
+                }
+
+                let serialized = timed(profile.as_mut().map(|p| &mut p.serialize), || {
+                    let mut serialized = Vec::new();
+                    match options.max_output_size {
+                        Some(max) => if fuzzed_file.serialize_capped(&mut serialized, max) {
+                            return None
+                        },
+                        None => fuzzed_file.serialize(&mut serialized),
+                    }
+                    if serialized.iter().any(|b| options.excluded_bytes.contains(b)) {
+                        return None
+                    }
+                    Some(serialized)
+                });
+
+                if let Some(serialized) = serialized {
+                    let hash = content_hash(&serialized);
+                    let index = if options.unique {
+                        if !seen.insert(hash) {
+                            duplicates += 1;
+                            break
+                        }
+                        seen.len()
+                    } else {
+                        i + 1
+                    };
+                    if let Some(dry_run) = options.dry_run {
+                        dry_run.lock().unwrap().add(serialized.len(), &trace);
+                        break
+                    }
+                    let location = match (pack.as_mut(), pattern) {
+                        (Some(pack), _) => timed(profile.as_mut().map(|p| &mut p.serialize), || pack.write(&serialized))?,
+                        (None, Some(pattern)) => {
+                            let out_filename = match tar {
+                                Some(_) => pattern.with(index),
+                                None => pattern.create_path(index)?,
+                            };
+                            timed(profile.as_mut().map(|p| &mut p.serialize), || write_output(tar.as_mut(), &out_filename, &serialized))?;
+                            OutputLocation::File(out_filename)
+                        },
+                        (None, None) => unreachable!("checked by do_fuzz"),
+                    };
+                    if let OutputLocation::File(ref out_filename) = location {
+                        if let Some(seed_paths) = options.sidecar_seed_paths {
+                            write_sidecar(out_filename, seed_paths[seed], options.seed, i, &trace)?;
+                        }
+                        if let Some((grammar, parse_config)) = options.dump_grammar {
+                            write_tree(out_filename, grammar, parse_config, &serialized)?;
+                        }
+                    }
+                    if let Some(manifest) = options.manifest {
+                        manifest.lock().unwrap().push(ManifestEntry {
+                            index: index,
+                            location: location,
+                            iteration: i,
+                            seed: seed,
+                            hash: hash,
+                            mutations: trace.clone(),
+                        });
+                    }
+                    break
+                }
+            }
+            Ok(())
+        }));
+        match result {
+            Ok(result) => result?,
+            Err(cause) => {
+                if options.abort_on_panic || options.paranoid_inputs.is_some() {
+                    panic::resume_unwind(cause)
+                }
+                eprintln!("warning: iteration {} panicked, skipping: {}", i, panic_message(&cause));
+                eprintln!("warning: mutations applied: {}", describe_trace(&trace));
+            },
+        }
+    }
+    if let Some(mut tar) = tar {
+        tar.finish().chain_err(|| "Could not write tar archive")?;
+    }
+    Ok(duplicates)
+}
+
+fn do_import<P: AsRef<Path>>(grammar: &Grammar, parse_config: &ParseConfig, dir: P, pattern: &OutputPattern, max_input_size: u64) -> Result<usize> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&dir).chain_err(|| "Could not read directory")? {
+        let path = entry.chain_err(|| "Could not read directory entry")?.path();
+        if path.is_file() {
+            paths.push(path)
+        }
+    }
+    paths.sort();
+
+    let mut seen = HashSet::new();
+    for path in paths {
+        let buf = read_file(&path, max_input_size)?;
+        let parsed_file = slurp_with(grammar, &buf, parse_config);
+        if seen.insert(parsed_file.structural_hash()) {
+            let out_filename = pattern.create_path(seen.len())?;
+            let mut file = File::create(&out_filename).chain_err(|| format!("Could not create output file {:?}", out_filename))?;
+            file.write_all(&buf[..]).chain_err(|| format!("Could not write output file {:?}", out_filename))?;
+        }
+    }
+    Ok(seen.len())
+}
+
+// Runs `command`, with `@@` in its arguments replaced by `path`, after
+// writing `buf` to `path`. A failing exit status means `buf` is still
+// interesting.
+fn still_fails(command: &[&str], path: &Path, buf: &[u8]) -> Result<bool> {
+    let mut file = File::create(path).chain_err(|| format!("Could not create {}", path.display()))?;
+    file.write_all(buf).chain_err(|| format!("Could not write {}", path.display()))?;
+    drop(file);
+
+    let args: Vec<&OsStr> = command[1..].iter().map(|arg| if *arg == "@@" { path.as_os_str() } else { OsStr::new(arg) }).collect();
+    let status = Command::new(command[0]).args(&args).status().chain_err(|| format!("Could not run {}", command[0]))?;
+    Ok(!status.success())
+}
+
+// Minimizes `input` against `command`, leaving the result in `output`.
+// Returns the minimized length.
+fn do_minimize(grammar: &Grammar, parse_config: &ParseConfig, input: &[u8], output: &Path, command: &[&str]) -> Result<usize> {
+    if !still_fails(command, output, input)? {
+        bail!("Command succeeds on the unminimized input");
+    }
+
+    let parsed_file = slurp_with(grammar, input, parse_config);
+    let mut error = None;
+    let minimized = fuzz::minimize(&parsed_file, |buf| {
+        if error.is_some() {
+            return false
+        }
+        match still_fails(command, output, buf) {
+            Ok(fails) => fails,
+            Err(e) => {
+                error = Some(e);
+                false
+            },
+        }
+    });
+    if let Some(e) = error {
+        return Err(e)
+    }
+
+    let mut serialized = Vec::new();
+    minimized.serialize(&mut serialized);
+    let mut file = File::create(output).chain_err(|| format!("Could not create {}", output.display()))?;
+    file.write_all(&serialized).chain_err(|| format!("Could not write {}", output.display()))?;
+    Ok(serialized.len())
+}
+
+fn describe_def(def: &GrammarDef) -> String {
+    match *def {
+        GrammarDef::Breaker(ref pattern) => format!("break {:?}", String::from_utf8_lossy(pattern)),
+        GrammarDef::Comment(ref start_pattern, ref end_pattern) => format!("comment ({:?}, {:?})", String::from_utf8_lossy(start_pattern), String::from_utf8_lossy(end_pattern)),
+        GrammarDef::Delim(ref start_pattern, ref end_pattern, _) => format!("delim ({:?}, {:?})", String::from_utf8_lossy(start_pattern), String::from_utf8_lossy(end_pattern)),
+        GrammarDef::Tokenizer(ref pattern, _) => format!("tokenizer {:?}", String::from_utf8_lossy(pattern)),
+        GrammarDef::LengthPrefixed { width, endian } => format!("length-prefixed ({} bytes, {:?})", width, endian),
+    }
+}
+
+fn output_paths(pattern: &OutputPattern, start: usize, num: usize) -> Vec<OsString> {
+    (start..start + num).map(|i| pattern.with(i)).collect()
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    let byte = if s.starts_with("0x") || s.starts_with("0X") {
+        u8::from_str_radix(&s[2..], 16)
+    } else {
+        u8::from_str(s)
+    };
+    byte.chain_err(|| format!("Invalid byte: {}", s))
+}
+
+fn lookup<'a>(matches: &'a ArgMatches, key: &str) -> &'a str {
+    matches.value_of(key).expect("impossible")
+}
+
+// Only fuzz cycles through several inputs, the other subcommands read one.
+// Standard input can only be read once.
+fn check_inputs(matches: &ArgMatches) -> Result<()> {
+    let input_filenames: Vec<&str> = match matches.values_of("INPUT") {
+        Some(values) => values.collect(),
+        None => return Ok(()),
+    };
+    if input_filenames.len() > 1 && matches.subcommand_name() != Some("fuzz") {
+        bail!("Only fuzz accepts more than one --input");
+    }
+    if input_filenames.iter().filter(|input_filename| **input_filename == "-").count() > 1 {
+        bail!("Standard input can only be given once as --input");
+    }
+    Ok(())
+}
+
+fn required<'a>(matches: &'a ArgMatches, key: &str) -> Result<&'a str> {
+    matches.value_of(key).ok_or_else(|| format!("Missing required argument --{}", key.to_lowercase()).into())
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("super-happy-fuzz-time")
+        .arg(Arg::with_name("INPUT")
+            .help("File to fuzz, or - for stdin. Fuzzing accepts several, and cycles through them")
+            .long("input")
+            .short("i")
+            .number_of_values(1)
+            .multiple(true)
+            .global(true))
+        .arg(Arg::with_name("CONFIG")
+            .help("Config file")
+            .long("config")
+            .short("c")
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("GRAMMAR_CACHE")
+            .help("Compiled config cache, rebuilt when the config changes")
+            .long("grammar-cache")
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("MAX_INPUT_SIZE")
+            .help("Maximum input file size in bytes (default 256MB)")
+            .long("max-input-size")
+            .number_of_values(1)
+            .global(true))
+        .arg(Arg::with_name("MAX_DEPTH")
+            .help("Maximum delim nesting depth, deeper start patterns are kept as tokens (default 1024)")
+            .long("max-depth")
+            .number_of_values(1)
+            .global(true))
+        .subcommand(
+            SubCommand::with_name("dump")
+                .help("Parse and dump input file")
+                .arg(Arg::with_name("FORMAT")
+                    .help("Output format")
+                    .long("format")
+                    .possible_values(&["text", "json"])
+                    .default_value("text")))
+        .subcommand(
+            SubCommand::with_name("stats")
+                .help("Parse input file and summarize the tree"))
+        .subcommand(
+            SubCommand::with_name("check")
+                .help("Warn about config defs that can never match"))
+        .subcommand(
+            SubCommand::with_name("fuzz")
+                .help("Fuzz input file")
+                .arg(Arg::with_name("OUTPUT")
+                    .help("Output pattern")
+                    .long("output")
+                    .short("o")
+                    .number_of_values(1)
+                    .required_unless_one(&["PACK", "DRY_RUN"]))
+                .arg(Arg::with_name("ITERATIONS")
+                    .help("Number of files to generate")
+                    .long("num")
+                    .short("n")
+                    .number_of_values(1)
+                    .required(true))
+                .arg(Arg::with_name("ONLY")
+                    .help("Only apply this mutation")
+                    .long("only")
+                    .number_of_values(1))
+                .arg(Arg::with_name("MUTATIONS")
+                    .help("Comma-separated mutations to apply, default all")
+                    .long("mutations")
+                    .use_delimiter(true)
+                    .conflicts_with("ONLY"))
+                .arg(Arg::with_name("DICT")
+                    .help("AFL-style dictionary of token values for dict-token")
+                    .long("dict")
+                    .number_of_values(1))
+                .arg(Arg::with_name("DICT_LEVEL")
+                    .help("How often dict-token is picked, from never (0) to most mutations (3)")
+                    .long("dict-level")
+                    .number_of_values(1)
+                    .possible_values(&["0", "1", "2", "3"]))
+                .arg(Arg::with_name("PRESET")
+                    .help("Mutation settings for a format family")
+                    .long("preset")
+                    .number_of_values(1)
+                    .possible_values(&["text", "binary", "structured"]))
+                .arg(Arg::with_name("CONTENT_ONLY")
+                    .help("Only mutate token contents, keeping the structure intact")
+                    .long("content-only"))
+                .arg(Arg::with_name("SIDECARS")
+                    .help("Write a JSON .meta file describing each output")
+                    .long("sidecars"))
+                .arg(Arg::with_name("WITH_DUMP")
+                    .help("Write a .tree file with the re-parsed dump of each output")
+                    .long("with-dump"))
+                .arg(Arg::with_name("EXCLUDE_BYTE")
+                    .help("Never write outputs containing this byte, e.g. 0x00")
+                    .long("exclude-byte")
+                    .number_of_values(1)
+                    .multiple(true))
+                .arg(Arg::with_name("PARANOID")
+                    .help("Check tree invariants before writing each output, stopping at the first violation")
+                    .long("paranoid"))
+                .arg(Arg::with_name("TAR")
+                    .help("Write outputs into this tar archive instead of loose files")
+                    .long("tar")
+                    .number_of_values(1)
+                    .conflicts_with_all(&["SIDECARS", "WITH_DUMP"]))
+                .arg(Arg::with_name("PACK")
+                    .help("Write outputs into this file as records with a 4-byte little-endian length prefix, instead of loose files")
+                    .long("pack")
+                    .number_of_values(1)
+                    .conflicts_with_all(&["OUTPUT", "TAR", "SIDECARS", "WITH_DUMP"]))
+                .arg(Arg::with_name("SEED")
+                    .help("Seed the RNG with this value instead of a random one")
+                    .long("seed")
+                    .number_of_values(1))
+                .arg(Arg::with_name("WARMUP")
+                    .help("Discard this many RNG outputs at the start of each iteration")
+                    .long("warmup")
+                    .number_of_values(1))
+                .arg(Arg::with_name("ABORT_ON_PANIC")
+                    .help("Stop at the first iteration that panics, instead of skipping it")
+                    .long("abort-on-panic"))
+                .arg(Arg::with_name("JOBS")
+                    .help("Number of threads to fuzz with (default: number of CPUs). --tar and --unique always use one")
+                    .long("jobs")
+                    .short("j")
+                    .number_of_values(1))
+                .arg(Arg::with_name("MAX_OUTPUT_SIZE")
+                    .help("Discard outputs larger than this many bytes")
+                    .long("max-output-size")
+                    .number_of_values(1))
+                .arg(Arg::with_name("MANIFEST")
+                    .help("Write a JSON manifest of the run parameters and every output to this file")
+                    .long("manifest")
+                    .number_of_values(1))
+                .arg(Arg::with_name("UNIQUE")
+                    .help("Skip outputs identical to one already written (implies --jobs 1)")
+                    .long("unique"))
+                .arg(Arg::with_name("DRY_RUN")
+                    .help("Report how many outputs would be written, their sizes and the mutations applied, without writing anything")
+                    .long("dry-run")
+                    .conflicts_with_all(&["SIDECARS", "WITH_DUMP", "TAR", "MANIFEST"]))
+                .arg(Arg::with_name("PROFILE")
+                    .help("Report time spent parsing, fuzzing and serializing")
+                    .long("profile")))
+        .subcommand(
+            SubCommand::with_name("import")
+                .help("Import a directory of files, skipping structural duplicates")
+                .arg(Arg::with_name("DIR")
+                    .help("Directory to import, e.g. an AFL queue")
+                    .long("dir")
+                    .short("d")
+                    .number_of_values(1)
+                    .required(true))
+                .arg(Arg::with_name("OUTPUT")
+                    .help("Output pattern")
+                    .long("output")
+                    .short("o")
+                    .number_of_values(1)
+                    .required(true)))
+        .subcommand(
+            SubCommand::with_name("minimize")
+                .help("Shrink the input while a command keeps failing on it")
+                .arg(Arg::with_name("OUTPUT")
+                    .help("Minimized file, also passed to the command as @@")
+                    .long("output")
+                    .short("o")
+                    .number_of_values(1)
+                    .required(true))
+                .arg(Arg::with_name("COMMAND")
+                    .help("Command to run, e.g. -- ./target @@")
+                    .multiple(true)
+                    .required(true)
+                    .last(true)))
+        .subcommand(
+            SubCommand::with_name("paths")
+                .help("Print the filenames a fuzz run would write")
+                .arg(Arg::with_name("OUTPUT")
+                    .help("Output pattern")
+                    .long("output")
+                    .short("o")
+                    .number_of_values(1)
+                    .required(true))
+                .arg(Arg::with_name("ITERATIONS")
+                    .help("Number of filenames to print")
+                    .long("num")
+                    .short("n")
+                    .number_of_values(1)
+                    .required(true))
+                .arg(Arg::with_name("START")
+                    .help("First file number (default 1)")
+                    .long("start")
+                    .number_of_values(1)))
+}
+
+fn fuzz_config<'g>(grammar: &'g Grammar, matches: &ArgMatches) -> Result<fuzz::FuzzConfig<'g>> {
+    let mut config = match matches.value_of("PRESET") {
+        Some(preset) => {
+            let preset = fuzz::Preset::from_str(preset).chain_err(|| format!("Invalid preset: {}", preset))?;
+            fuzz::FuzzConfig::preset(preset, grammar.delims())
+        },
+        None => fuzz::FuzzConfig::new(grammar.delims()),
+    };
+
+    if let Some(only) = matches.value_of("ONLY") {
+        config.valid_actions = vec![fuzz::Mutation::from_str(only).chain_err(|| format!("Invalid mutation: {}", only))?];
+    }
+    if let Some(names) = matches.values_of("MUTATIONS") {
+        config.valid_actions = names.map(|name| fuzz::Mutation::from_str(name).chain_err(|| format!("Invalid mutation: {}", name))).collect::<Result<_>>()?;
+    }
+    config.content_only = matches.is_present("CONTENT_ONLY");
+    if let Some(dict_path) = matches.value_of("DICT") {
+        config.dictionary = dict::read_dictionary(dict_path)?;
+    }
+    if let Some(level) = matches.value_of("DICT_LEVEL") {
+        // Each level scales the dict-token weight relative to the other mutations.
+        let multiplier = match level {
+            "0" => 0,
+            "1" => 1,
+            "2" => 4,
+            _ => 16,
+        };
+        let index = match config.valid_actions.iter().position(|mutation| *mutation == fuzz::Mutation::DictToken) {
+            Some(index) => index,
+            None => bail!("--dict-level needs dict-token among the enabled mutations"),
+        };
+        if config.weights.is_empty() {
+            config.weights = vec![1; config.valid_actions.len()];
+        }
+        if let Some(weight) = config.weights.get_mut(index) {
+            *weight *= multiplier;
+        }
+    }
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn go() -> Result<()> {
+    let matches = app().get_matches();
+
+    // Expanding a pattern needs neither a config nor an input file.
+    if let ("paths", Some(paths_matches)) = matches.subcommand() {
+        let output = lookup(paths_matches, "OUTPUT");
+        let iterations = lookup(paths_matches, "ITERATIONS");
+        let num_iterations = usize::from_str(iterations).chain_err(|| format!("Invalid iterations: {}", iterations))?;
+        let start = match paths_matches.value_of("START") {
+            Some(start) => usize::from_str(start).chain_err(|| format!("Invalid start: {}", start))?,
+            None => 1,
+        };
+        let pattern = OutputPattern::from_path(output).chain_err(|| format!("Invalid output pattern: {}", output))?;
+        for path in output_paths(&pattern, start, num_iterations) {
+            println!("{}", path.to_string_lossy());
+        }
+        return Ok(())
+    }
+
+    check_inputs(&matches)?;
+    let config_filename = required(&matches, "CONFIG")?;
+    let max_input_size = match matches.value_of("MAX_INPUT_SIZE") {
+        Some(size) => u64::from_str(size).chain_err(|| format!("Invalid maximum input size: {}", size))?,
+        None => DEFAULT_MAX_INPUT_SIZE,
+    };
+    let mut parse_config = ParseConfig::new();
+    parse_config.max_depth = match matches.value_of("MAX_DEPTH") {
+        Some(depth) => usize::from_str(depth).chain_err(|| format!("Invalid maximum depth: {}", depth))?,
+        None => DEFAULT_MAX_DEPTH,
+    };
+
+    let grammar = match matches.value_of("GRAMMAR_CACHE") {
+        Some(cache_filename) => Grammar::from_path_cached(config_filename, cache_filename),
+        None => Grammar::from_path(config_filename),
+    }.chain_err(|| format!("Could not load config {}", config_filename))?;
+
+    match matches.subcommand() {
+        ("dump", Some(dump_matches)) => {
+            let input_filename = required(&matches, "INPUT")?;
+            let buf = read_input(input_filename, max_input_size)?;
+            let parsed_file = try_slurp_with(&grammar, &buf, &parse_config)?;
+            match lookup(dump_matches, "FORMAT") {
+                "json" => println!("{}", parsed_file.to_json()),
+                _ => println!("{}", parsed_file.dump()),
+            }
+        },
+        ("stats", _) => {
+            let input_filename = required(&matches, "INPUT")?;
+            let buf = read_input(input_filename, max_input_size)?;
+            println!("{}", try_slurp_with(&grammar, &buf, &parse_config)?.stats());
+        },
+        ("check", _) => {
+            let duplicates = grammar.duplicate_delims();
+            for &(index, original) in &duplicates {
+                println!("warning: {} duplicates {}", describe_def(&grammar.defs[index]), describe_def(&grammar.defs[original]));
+            }
+            // A duplicate is also unreachable, so only warn about it once.
+            let unreachable = grammar.unreachable_defs();
+            for index in unreachable.iter().filter(|index| !duplicates.iter().any(|&(duplicate, _)| duplicate == **index)) {
+                println!("warning: {} can never match, an earlier pattern always wins", describe_def(&grammar.defs[*index]));
+            }
+            if unreachable.is_empty() && duplicates.is_empty() {
+                println!("{}: ok", config_filename);
+            }
+        },
+        ("fuzz", Some(fuzz_matches)) => {
+            let iterations = lookup(fuzz_matches, "ITERATIONS");
+            let num_iterations = usize::from_str(iterations).chain_err(|| format!("Invalid iterations: {}", iterations))?;
+            let pattern = match fuzz_matches.value_of("OUTPUT") {
+                Some(output) => Some(OutputPattern::from_path(output).chain_err(|| format!("Invalid output pattern: {}", output))?),
+                None => None,
+            };
+            let config = fuzz_config(&grammar, fuzz_matches)?;
+            let excluded_bytes = match fuzz_matches.values_of("EXCLUDE_BYTE") {
+                Some(values) => values.map(parse_byte).collect::<Result<Vec<_>>>()?,
+                None => Vec::new(),
+            };
+            let warmup = match fuzz_matches.value_of("WARMUP") {
+                Some(warmup) => usize::from_str(warmup).chain_err(|| format!("Invalid warmup: {}", warmup))?,
+                None => 0,
+            };
+            let seed = match fuzz_matches.value_of("SEED") {
+                Some(seed) => u64::from_str(seed).chain_err(|| format!("Invalid seed: {}", seed))?,
+                None => {
+                    let seed = rand::OsRng::new().chain_err(|| "Could not open OS RNG")?.next_u64();
+                    eprintln!("seed: {}", seed);
+                    seed
+                },
+            };
+            let jobs = match fuzz_matches.value_of("JOBS") {
+                Some(jobs) => usize::from_str(jobs).chain_err(|| format!("Invalid jobs: {}", jobs))?,
+                None => thread::available_parallelism().map(|jobs| jobs.get()).unwrap_or(1),
+            };
+            let max_output_size = match fuzz_matches.value_of("MAX_OUTPUT_SIZE") {
+                Some(max) => Some(usize::from_str(max).chain_err(|| format!("Invalid max output size: {}", max))?),
+                None => None,
+            };
+            let mut profile = if fuzz_matches.is_present("PROFILE") {
+                Some(Profile::new())
+            } else {
+                None
+            };
+            let manifest = fuzz_matches.value_of("MANIFEST").map(|_| Mutex::new(Vec::new()));
+            let dry_run = if fuzz_matches.is_present("DRY_RUN") {
+                Some(Mutex::new(DryRun::new()))
+            } else {
+                None
+            };
+            let input_filenames: Vec<&str> = match matches.values_of("INPUT") {
+                Some(values) => values.collect(),
+                None => bail!("Missing required argument --input"),
+            };
+            let bufs = input_filenames.iter().map(|input_filename| read_input(input_filename, max_input_size)).collect::<Result<Vec<_>>>()?;
+            let parsed_files = timed(profile.as_mut().map(|p| &mut p.parse), || {
+                bufs.iter().map(|buf| try_slurp_with(&grammar, buf, &parse_config)).collect::<Result<Vec<_>>>()
+            })?;
+            let options = FuzzOptions {
+                sidecar_seed_paths: if fuzz_matches.is_present("SIDECARS") { Some(&input_filenames[..]) } else { None },
+                dump_grammar: if fuzz_matches.is_present("WITH_DUMP") { Some((&grammar, &parse_config)) } else { None },
+                paranoid_inputs: if fuzz_matches.is_present("PARANOID") { Some(&bufs[..]) } else { None },
+                excluded_bytes: excluded_bytes,
+                abort_on_panic: fuzz_matches.is_present("ABORT_ON_PANIC"),
+                warmup: warmup,
+                seed: seed,
+                tar_path: fuzz_matches.value_of("TAR").map(Path::new),
+                pack_path: fuzz_matches.value_of("PACK").map(Path::new),
+                jobs: jobs,
+                max_output_size: max_output_size,
+                unique: fuzz_matches.is_present("UNIQUE"),
+                manifest: manifest.as_ref(),
+                dry_run: dry_run.as_ref(),
+            };
+            let duplicates = do_fuzz(&parsed_files, pattern.as_ref(), num_iterations, &config, &options, &mut profile).chain_err(|| "Error fuzzing input file")?;
+            if options.unique {
+                eprintln!("suppressed {} duplicate outputs", duplicates);
+            }
+            if let (Some(manifest_path), Some(manifest)) = (fuzz_matches.value_of("MANIFEST"), options.manifest) {
+                write_manifest(manifest_path, config_filename, &input_filenames, &config, &options, num_iterations, &mut manifest.lock().unwrap())?;
+            }
+            if let Some(dry_run) = dry_run {
+                dry_run.into_inner().unwrap().report(num_iterations);
+            }
+            if let Some(ref profile) = profile {
+                profile.report();
+            }
+        },
+        ("import", Some(import_matches)) => {
+            let dir = lookup(import_matches, "DIR");
+            let output = lookup(import_matches, "OUTPUT");
+            let pattern = OutputPattern::from_path(output).chain_err(|| format!("Invalid output pattern: {}", output))?;
+            let num_imported = do_import(&grammar, &parse_config, dir, &pattern, max_input_size).chain_err(|| format!("Error importing {}", dir))?;
+            println!("imported {} files", num_imported);
+        },
+        ("minimize", Some(minimize_matches)) => {
+            let output = Path::new(lookup(minimize_matches, "OUTPUT"));
+            let command: Vec<&str> = minimize_matches.values_of("COMMAND").expect("required argument").collect();
+            let input_filename = required(&matches, "INPUT")?;
+            let buf = read_input(input_filename, max_input_size)?;
+            let minimized_len = do_minimize(&grammar, &parse_config, &buf, output, &command).chain_err(|| format!("Error minimizing {}", input_filename))?;
+            println!("minimized {} bytes to {} bytes", buf.len(), minimized_len);
         },
         _ => {
-            bail!("Must provide 'dump' or 'fuzz'");
+            bail!("Must provide 'check', 'dump', 'fuzz', 'import', 'minimize' or 'paths'");
         },
     }
     Ok(())
@@ -1,27 +1,116 @@
 use std::ffi::OsString;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use libshft::error::*;
 
+// How `{}` renders the index: `{:06}` zero-pads to 6 digits, `{:x}` uses
+// hex, and `{:08x}` does both.
+#[derive(Clone, Copy, Default)]
+struct IndexFormat {
+    width: usize,
+    hex: bool,
+}
+
+impl IndexFormat {
+    fn format(self: &Self, index: usize) -> String {
+        if self.hex {
+            format!("{:0width$x}", index, width = self.width)
+        } else {
+            format!("{:0width$}", index, width = self.width)
+        }
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Index(IndexFormat),
+    // `{shard:N}` expands to `index / N`.
+    Shard(usize),
+}
+
 pub struct OutputPattern {
-    dirname: PathBuf,
-    filename_prefix: OsString,
-    filename_suffix: OsString,
+    segments: Vec<Segment>,
+    has_shard: bool,
+}
+
+fn parse_index_format(spec: &str) -> Result<IndexFormat> {
+    let (digits, hex) = if spec.ends_with('x') {
+        (&spec[..spec.len()-1], true)
+    } else {
+        (spec, false)
+    };
+    if digits.is_empty() {
+        return Ok(IndexFormat { width: 0, hex: hex })
+    }
+    if !digits.starts_with('0') {
+        bail!("Invalid index format '{{:{}}}', only zero-padded widths like '{{:06}}' are supported", spec);
+    }
+    match usize::from_str(digits) {
+        Ok(width) => Ok(IndexFormat { width: width, hex: hex }),
+        _ => Err(format!("Invalid index format '{{:{}}}', expected e.g. '{{:06}}', '{{:x}}' or '{{:08x}}'", spec).into()),
+    }
+}
+
+fn parse_marker(marker: &str) -> Result<Segment> {
+    if marker.is_empty() {
+        return Ok(Segment::Index(IndexFormat::default()))
+    }
+    if marker.starts_with(':') {
+        return parse_index_format(&marker[1..]).map(Segment::Index)
+    }
+    if marker.starts_with("shard:") {
+        let bucket_size = &marker["shard:".len()..];
+        return match usize::from_str(bucket_size) {
+            Ok(bucket_size) if bucket_size > 0 => Ok(Segment::Shard(bucket_size)),
+            _ => Err(format!("Invalid shard bucket size '{}'", bucket_size).into()),
+        }
+    }
+    Err(format!("Unknown marker '{{{}}}'", marker).into())
+}
+
+fn parse_segments(pattern: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut remainder = pattern;
+    while let Some(start) = remainder.find('{') {
+        let end = match remainder[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("Unterminated marker in '{}'", pattern),
+        };
+        if start > 0 {
+            segments.push(Segment::Literal(remainder[..start].to_string()))
+        }
+        segments.push(parse_marker(&remainder[start+1..end])?);
+        remainder = &remainder[end+1..];
+    }
+    if !remainder.is_empty() {
+        segments.push(Segment::Literal(remainder.to_string()))
+    }
+    Ok(segments)
 }
 
 impl OutputPattern {
     pub fn from_path<P: AsRef<Path>>(pattern: P) -> Result<OutputPattern> {
         let pattern = PathBuf::from(pattern.as_ref());
         match (pattern.parent(), pattern.file_name()) {
-            (Some(dirname), Some(filename)) => {
-                let filename = filename.to_str();
-                match filename.map(|s| (s, s.find("{}"))) {
-                    Some((filename_str, Some(pattern_offset))) => {
-                        let filename_prefix = OsString::from(&filename_str[..pattern_offset]);
-                        let filename_suffix = OsString::from(&filename_str[pattern_offset+2..]);
+            (Some(_), Some(filename)) => {
+                match (pattern.to_str(), filename.to_str()) {
+                    (Some(pattern_str), Some(filename_str)) => {
+                        let segments = parse_segments(pattern_str)?;
+                        let has_index = parse_segments(filename_str)?.iter().any(|segment| match *segment {
+                            Segment::Index(_) => true,
+                            _ => false,
+                        });
+                        if !has_index {
+                            bail!("Could not find '{}' marker");
+                        }
+                        let has_shard = segments.iter().any(|segment| match *segment {
+                            Segment::Shard(_) => true,
+                            _ => false,
+                        });
                         Ok(OutputPattern {
-                            dirname: PathBuf::from(dirname),
-                            filename_prefix: filename_prefix,
-                            filename_suffix: filename_suffix,
+                            segments: segments,
+                            has_shard: has_shard,
                         })
                     },
                     _ => Err("Could not find '{}' marker".into()),
@@ -31,16 +120,27 @@ impl OutputPattern {
         }
     }
 
-    pub fn with<T: ToString>(self: &Self, value: T) -> OsString {
-        let value = OsString::from(value.to_string());
-        let mut filename = OsString::from(self.filename_prefix.clone());
-        filename.push(value);
-        filename.push(self.filename_suffix.clone());
+    pub fn with(self: &Self, index: usize) -> OsString {
+        let mut path = String::new();
+        for segment in &self.segments {
+            match *segment {
+                Segment::Literal(ref literal) => path.push_str(literal),
+                Segment::Index(format) => path.push_str(&format.format(index)),
+                Segment::Shard(bucket_size) => path.push_str(&(index / bucket_size).to_string()),
+            }
+        }
+        OsString::from(path)
+    }
 
-        let mut path = PathBuf::new();
-        path.push(self.dirname.clone());
-        path.push(filename);
-        path.into_os_string()
+    // Like `with`, but also creates the shard directory the path lives in.
+    pub fn create_path(self: &Self, index: usize) -> Result<OsString> {
+        let path = self.with(index);
+        if self.has_shard {
+            if let Some(parent) = Path::new(&path).parent() {
+                fs::create_dir_all(parent).chain_err(|| format!("Could not create output directory {}", parent.display()))?;
+            }
+        }
+        Ok(path)
     }
 }
 
@@ -50,3 +150,45 @@ fn test_output_pattern() {
     let pattern = OutputPattern::from_path("out/{}.ext").unwrap();
     assert!(pattern.with(0) == OsString::from("out/0.ext"));
 }
+
+#[test]
+fn test_output_pattern_format() {
+    let pattern = OutputPattern::from_path("out/{:04}.bin").unwrap();
+    assert!(pattern.with(1) == OsString::from("out/0001.bin"));
+    assert!(pattern.with(12345) == OsString::from("out/12345.bin"));
+    let pattern = OutputPattern::from_path("out/{:x}.bin").unwrap();
+    assert!(pattern.with(255) == OsString::from("out/ff.bin"));
+    let pattern = OutputPattern::from_path("out/{:06x}.bin").unwrap();
+    assert!(pattern.with(255) == OsString::from("out/0000ff.bin"));
+    let pattern = OutputPattern::from_path("out/{:}.bin").unwrap();
+    assert!(pattern.with(7) == OsString::from("out/7.bin"));
+
+    for invalid in &["out/{:4}.bin", "out/{:0y}.bin", "out/{:-04}.bin", "out/{:04X}.bin"] {
+        let err = OutputPattern::from_path(invalid).err().unwrap();
+        assert!(err.to_string().starts_with("Invalid index format"));
+    }
+    assert!(OutputPattern::from_path("out/{:04}/name.bin").is_err());
+}
+
+#[test]
+fn test_output_pattern_shard() {
+    assert!(OutputPattern::from_path("out/{shard:0}/{}.bin").is_err());
+    assert!(OutputPattern::from_path("out/{shard:x}/{}.bin").is_err());
+    assert!(OutputPattern::from_path("out/{bogus}/{}.bin").is_err());
+    assert!(OutputPattern::from_path("out/{shard:10}/{.bin").is_err());
+
+    let dir = ::std::env::temp_dir().join(format!("shft-test-shard-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let pattern = OutputPattern::from_path(format!("{}/{{shard:10}}/{{}}.bin", dir.display())).unwrap();
+    assert!(pattern.with(9) == dir.join("0/9.bin").into_os_string());
+    assert!(pattern.with(10) == dir.join("1/10.bin").into_os_string());
+
+    for index in 0..25 {
+        let path = pattern.create_path(index).unwrap();
+        fs::write(&path, b"").unwrap();
+    }
+    let mut shards: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    shards.sort();
+    assert!(shards == vec![OsString::from("0"), OsString::from("1"), OsString::from("2")]);
+    assert!(fs::read_dir(dir.join("2")).unwrap().count() == 5);
+}